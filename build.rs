@@ -1,115 +1,237 @@
-use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Write;
-use std::iter::FromIterator;
-use std::path::PathBuf;
-
-const SYMBOL_MAP: [(char, (bool, &str)); 33] = [
-    ('`', (false, "Grave")),
-    ('-', (false, "Minus")),
-    ('=', (false, "Equal")),
-    ('[', (false, "LBracket")),
-    (']', (false, "RBracket")),
-    ('\\', (false, "Bslash")),
-    (';', (false, "SColon")),
-    ('\'', (false, "Quote")),
-    (',', (false, "Comma")),
-    ('.', (false, "Dot")),
-    ('/', (false, "Slash")),
-    (' ', (false, "Space")),
-    // Shift keys
-    ('~', (true, "Grave")),
-    ('!', (true, "Kb1")),
-    ('@', (true, "Kb2")),
-    ('#', (true, "Kb3")),
-    ('$', (true, "Kb4")),
-    ('%', (true, "Kb5")),
-    ('^', (true, "Kb6")),
-    ('&', (true, "Kb7")),
-    ('*', (true, "Kb8")),
-    ('(', (true, "Kb9")),
-    (')', (true, "Kb0")),
-    ('_', (true, "Minus")),
-    ('+', (true, "Equal")),
-    ('{', (true, "LBracket")),
-    ('}', (true, "RBracket")),
-    ('|', (true, "Bslash")),
-    (':', (true, "SColon")),
-    ('"', (true, "Quote")),
-    ('<', (true, "Comma")),
-    ('>', (true, "Dot")),
-    ('?', (true, "Slash")),
-];
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// Default RTTTL settings, used when a song doesn't specify them
+const DEFAULT_DURATION: u32 = 4;
+const DEFAULT_OCTAVE: u32 = 6;
+const DEFAULT_BPM: u32 = 63;
+/// Silence at the end of each note in ms, so that repeated notes can be heard
+const ARTICULATION: u32 = 10;
 
 fn main() {
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    compile_macros(&out_dir);
+    compile_songs(&out_dir);
+
+    // memory.x is found by cortex-m-rt's link.x in the linker search path
+    fs::copy("memory.x", out_dir.join("memory.x")).expect("unable to copy memory.x");
+    println!("cargo:rustc-link-search={}", out_dir.display());
+    println!("cargo:rerun-if-changed=memory.x");
+}
+
+fn compile_macros(out_dir: &Path) {
     let macros_src = include_str!("src/macros.txt");
-    let output_path = PathBuf::from(env::var("OUT_DIR").unwrap()).join("macros.rs");
+    let output_path = out_dir.join("macros.rs");
     let mut out_file = File::create(&output_path).expect("unable to create output macro file");
-    let shift_map: HashMap<char, (bool, &str)> = HashMap::from_iter(SYMBOL_MAP);
 
-    for line in macros_src.lines() {
-        if line.starts_with('#') {
-            continue;
+    match clueboard_macros::parse(macros_src) {
+        Ok(macros) => {
+            for macro_ in macros {
+                writeln!(out_file, "{}", macro_).unwrap();
+            }
         }
-
-        let (const_name, rest) = line.split_once(':').unwrap();
-        let keys = if rest.starts_with(' ') {
-            // Skip space in between : and definition
-            &rest[1..]
-        } else {
-            rest
-        };
-
-        write!(
-            out_file,
-            "const {}: Action = Action::Sequence {{ events: &[",
-            const_name
-        )
-        .unwrap();
-        for ch in keys.chars() {
-            if !ch.is_ascii() {
-                panic!("line contains non-ASCII keys: {}", line)
+        Err(errors) => {
+            for error in errors {
+                eprintln!("error: src/macros.txt:{}", error);
             }
+            process::exit(1);
+        }
+    }
+}
 
-            match ch {
-                '0'..='9' => {
-                    write!(out_file, "SequenceEvent::Press(Kb{key}), SequenceEvent::Release(Kb{key}), ", key=ch).unwrap();
-                }
-                'a'..='z' => {
-                    let key = ch.to_ascii_uppercase();
-                    out_file.write_all(press_release(key).as_bytes()).unwrap();
-                }
-                'A'..='Z' => {
-                    write!(out_file, "SequenceEvent::Press(LShift), ").unwrap();
-                    out_file.write_all(press_release(ch).as_bytes()).unwrap();
-                    write!(out_file, "SequenceEvent::Release(LShift), ").unwrap();
-                }
-                _ => {
-                    if let Some(&(shift, keys)) = shift_map.get(&ch) {
-                        if shift {
-                            write!(out_file, "SequenceEvent::Press(LShift), SequenceEvent::Press({key}), SequenceEvent::Release({key}), SequenceEvent::Release(LShift), ", key=keys).unwrap();
-                        } else {
-                            write!(
-                                out_file,
-                                "SequenceEvent::Press({key}), SequenceEvent::Release({key}), ",
-                                key = keys
-                            )
-                            .unwrap();
-                        }
+fn compile_songs(out_dir: &Path) {
+    let songs_src = include_str!("src/songs.txt");
+    let output_path = out_dir.join("songs.rs");
+    let mut out_file = File::create(&output_path).expect("unable to create output songs file");
+    let mut errors = Vec::new();
+
+    for (i, line) in songs_src.lines().enumerate() {
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+
+        match parse_song(line) {
+            Ok((const_name, notes)) => {
+                write!(out_file, "pub(crate) const {}: &[Note] = &[", const_name).unwrap();
+                for (frequency, duration) in notes {
+                    if frequency == 0 {
+                        write!(out_file, "Note::rest({}), ", duration).unwrap();
+                    } else if duration > ARTICULATION * 2 {
+                        write!(
+                            out_file,
+                            "Note::new({}, {}), Note::rest({}), ",
+                            frequency,
+                            duration - ARTICULATION,
+                            ARTICULATION
+                        )
+                        .unwrap();
                     } else {
-                        panic!("unhandled char: {}", ch);
+                        write!(out_file, "Note::new({}, {}), ", frequency, duration).unwrap();
                     }
                 }
+                writeln!(out_file, "];").unwrap();
             }
+            Err(err) => errors.push(format!("src/songs.txt:{}: {}", i + 1, err)),
+        }
+    }
+
+    if !errors.is_empty() {
+        for error in errors {
+            eprintln!("error: {}", error);
+        }
+        process::exit(1);
+    }
+}
+
+/// A song's constant name and the frequency (Hz) and duration (ms) of each note
+type Song<'a> = (&'a str, Vec<(u32, u32)>);
+
+/// Parse an RTTTL song, returning its name and the frequency (Hz) and duration (ms) of each
+/// note. Rests have a frequency of 0.
+fn parse_song(line: &str) -> Result<Song<'_>, String> {
+    let mut sections = line.splitn(3, ':');
+    let const_name = sections.next().unwrap().trim();
+    let (settings, notes) = match (sections.next(), sections.next()) {
+        (Some(settings), Some(notes)) => (settings, notes),
+        _ => return Err("expected NAME:settings:notes".to_string()),
+    };
+    if !is_identifier(const_name) {
+        return Err(format!("'{}' is not a valid constant name", const_name));
+    }
+
+    let mut default_duration = DEFAULT_DURATION;
+    let mut default_octave = DEFAULT_OCTAVE;
+    let mut bpm = DEFAULT_BPM;
+    for setting in settings.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (key, value) = setting
+            .split_once('=')
+            .ok_or_else(|| format!("expected key=value, found '{}'", setting))?;
+        let value = value
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid number in setting '{}'", setting))?;
+        match key.trim() {
+            "d" => default_duration = check_duration(value)?,
+            "o" => default_octave = check_octave(value)?,
+            "b" if (1..=900).contains(&value) => bpm = value,
+            "b" => return Err(format!("tempo {} is outside 1 to 900", value)),
+            key => return Err(format!("unknown setting '{}', expected d, o, or b", key)),
         }
-        writeln!(out_file, "] }};").unwrap();
+    }
+
+    // A whole note is four beats
+    let whole_note = 4 * 60_000 / bpm;
+    notes
+        .split(',')
+        .map(str::trim)
+        .map(|note| {
+            parse_note(note, default_duration, default_octave, whole_note)
+                .map_err(|err| format!("invalid note '{}': {}", note, err))
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(|notes| (const_name, notes))
+}
+
+fn parse_note(
+    note: &str,
+    default_duration: u32,
+    default_octave: u32,
+    whole_note: u32,
+) -> Result<(u32, u32), String> {
+    let mut chars = note.chars().peekable();
+
+    let duration = match take_number(&mut chars)? {
+        Some(duration) => check_duration(duration)?,
+        None => default_duration,
+    };
+    let semitone = match chars.next().map(|ch| ch.to_ascii_lowercase()) {
+        Some('c') => Some(0),
+        Some('d') => Some(2),
+        Some('e') => Some(4),
+        Some('f') => Some(5),
+        Some('g') => Some(7),
+        Some('a') => Some(9),
+        Some('b') => Some(11),
+        Some('p') => None,
+        Some(ch) => return Err(format!("'{}' is not a note, expected a to g or p", ch)),
+        None => return Err("missing note".to_string()),
+    };
+    let sharp = chars.next_if_eq(&'#').is_some();
+    let mut dotted = chars.next_if_eq(&'.').is_some();
+    let octave = match take_number(&mut chars)? {
+        Some(octave) => check_octave(octave)?,
+        None => default_octave,
+    };
+    dotted |= chars.next_if_eq(&'.').is_some();
+    if let Some(ch) = chars.next() {
+        return Err(format!("unexpected '{}'", ch));
+    }
+
+    let mut length = whole_note / duration;
+    if dotted {
+        length += length / 2;
+    }
+    let frequency = match semitone {
+        Some(semitone) => {
+            // MIDI note number, A4 is 69 at 440 Hz
+            let midi = (octave + 1) * 12 + semitone + u32::from(sharp);
+            (440.0 * 2f64.powf((f64::from(midi) - 69.0) / 12.0)).round() as u32
+        }
+        None if sharp => return Err("rests can't be sharp".to_string()),
+        None => 0,
+    };
+    // The player takes both as u16
+    if u16::try_from(length).is_err() {
+        return Err(format!(
+            "note is {} ms long, longer than the {} ms a note can be",
+            length,
+            u16::MAX
+        ));
+    }
+    if u16::try_from(frequency).is_err() {
+        return Err(format!("frequency {} Hz is too high", frequency));
+    }
+    Ok((frequency, length))
+}
+
+fn take_number(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<Option<u32>, String> {
+    let mut number = None;
+    while let Some(digit) = chars.peek().and_then(|ch| ch.to_digit(10)) {
+        chars.next();
+        let value = number
+            .unwrap_or(0_u32)
+            .checked_mul(10)
+            .and_then(|number| number.checked_add(digit))
+            .ok_or_else(|| "number too large".to_string())?;
+        number = Some(value);
+    }
+    Ok(number)
+}
+
+fn check_duration(duration: u32) -> Result<u32, String> {
+    match duration {
+        1 | 2 | 4 | 8 | 16 | 32 => Ok(duration),
+        _ => Err(format!(
+            "duration {} is not one of 1, 2, 4, 8, 16, or 32",
+            duration
+        )),
+    }
+}
+
+fn check_octave(octave: u32) -> Result<u32, String> {
+    if (3..=8).contains(&octave) {
+        Ok(octave)
+    } else {
+        Err(format!("octave {} is outside 3 to 8", octave))
     }
 }
 
-fn press_release(key: char) -> String {
-    format!(
-        "SequenceEvent::Press({key}), SequenceEvent::Release({key}), ",
-        key = key
-    )
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(ch) if ch.is_ascii_alphabetic() || ch == '_')
+        && chars.all(|ch| ch.is_ascii_alphanumeric() || ch == '_')
 }
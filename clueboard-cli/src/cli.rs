@@ -0,0 +1,173 @@
+//! Command line parsing and the output of each command
+
+use std::convert::TryFrom;
+use std::io::Write;
+use std::path::PathBuf;
+
+use clueboard_core::effects::Effect;
+use clueboard_core::via::Value;
+
+use crate::client::{Client, Error};
+use crate::transport::Transport;
+
+pub const USAGE: &str = "\
+Usage: clueboard [--device PATH] COMMAND
+
+Commands:
+    list                         List the connected keyboards
+    keymap                       Print the keycodes of every layer
+    set LAYER ROW COL KEYCODE    Change the key at ROW, COL on LAYER to a QMK keycode
+    settings                     Print the backlight, audio and layer settings
+    stats                        Print the firmware version, uptime and key presses
+    bootloader                   Reboot the keyboard into its bootloader
+
+Without --device the first keyboard found is used. Numbers can be decimal or hex with 0x.";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    List,
+    Keymap,
+    SetKeycode {
+        layer: u8,
+        row: u8,
+        col: u8,
+        code: u16,
+    },
+    Settings,
+    Stats,
+    Bootloader,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Args {
+    /// hidraw device node of the keyboard to use
+    pub device: Option<PathBuf>,
+    pub command: Command,
+}
+
+/// Parse the arguments after the program name
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Args, String> {
+    let mut args = args.into_iter();
+    let mut device = None;
+    let command = loop {
+        match args.next().as_deref() {
+            Some("--device") => {
+                let path = args.next().ok_or("--device needs a path")?;
+                device = Some(PathBuf::from(path));
+            }
+            Some(command) => break command.to_string(),
+            None => return Err("no command given".to_string()),
+        }
+    };
+    let command = match command.as_str() {
+        "list" => Command::List,
+        "keymap" => Command::Keymap,
+        "set" => {
+            let mut number = |name| {
+                let arg = args.next().ok_or(format!("set needs {}", name))?;
+                parse_number(&arg).ok_or(format!("{} isn't a valid {}", arg, name))
+            };
+            let layer = number("LAYER")?;
+            let row = number("ROW")?;
+            let col = number("COL")?;
+            let code = number("KEYCODE")?;
+            let byte = |n: u32, name| u8::try_from(n).map_err(|_| format!("{} is too big", name));
+            Command::SetKeycode {
+                layer: byte(layer, "LAYER")?,
+                row: byte(row, "ROW")?,
+                col: byte(col, "COL")?,
+                code: u16::try_from(code).map_err(|_| "KEYCODE is too big".to_string())?,
+            }
+        }
+        "settings" => Command::Settings,
+        "stats" => Command::Stats,
+        "bootloader" => Command::Bootloader,
+        command => return Err(format!("unknown command {}", command)),
+    };
+    match args.next() {
+        Some(arg) => Err(format!("unexpected argument {}", arg)),
+        None => Ok(Args { device, command }),
+    }
+}
+
+/// Run `command` against the keyboard, `List` needs the devices so is left to the caller
+pub fn run<T: Transport>(
+    command: Command,
+    client: &mut Client<T>,
+    out: &mut impl Write,
+) -> Result<(), Error> {
+    match command {
+        Command::List => {}
+        Command::Keymap => {
+            for (layer, rows) in client.keymap()?.iter().enumerate() {
+                writeln!(out, "Layer {}", layer)?;
+                for (row, codes) in rows.iter().enumerate() {
+                    write!(out, "  row {}:", row)?;
+                    for code in codes {
+                        write!(out, " {:#06x}", code)?;
+                    }
+                    writeln!(out)?;
+                }
+            }
+        }
+        Command::SetKeycode {
+            layer,
+            row,
+            col,
+            code,
+        } => client.set_keycode(layer, row, col, code)?,
+        Command::Settings => {
+            let effect = client.value(Value::Effect)?;
+            let effect = u8::try_from(effect).ok().and_then(Effect::from_index);
+            writeln!(
+                out,
+                "backlight level: {}",
+                client.value(Value::BacklightLevel)?
+            )?;
+            writeln!(
+                out,
+                "backlight: {}",
+                on_off(client.value(Value::BacklightEnabled)?)
+            )?;
+            match effect {
+                Some(effect) => writeln!(out, "effect: {:?}", effect)?,
+                None => writeln!(out, "effect: unknown")?,
+            }
+            writeln!(out, "clicky: {}", on_off(client.value(Value::Clicky)?))?;
+            writeln!(out, "volume: {}", client.value(Value::Volume)?)?;
+            writeln!(out, "default layer: {}", client.value(Value::DefaultLayer)?)?;
+        }
+        Command::Stats => {
+            let [_, major, minor, patch] = client.firmware_version()?.to_be_bytes();
+            writeln!(out, "firmware version: {}.{}.{}", major, minor, patch)?;
+            writeln!(out, "protocol version: {}", client.protocol_version()?)?;
+            let seconds = client.uptime()? / 1000;
+            writeln!(
+                out,
+                "uptime: {}:{:02}:{:02}",
+                seconds / 3600,
+                seconds / 60 % 60,
+                seconds % 60
+            )?;
+            writeln!(out, "key presses: {}", client.value(Value::KeyPresses)?)?;
+        }
+        Command::Bootloader => client.bootloader()?,
+    }
+    Ok(())
+}
+
+/// `arg` as a decimal number, or hex if it starts with 0x
+fn parse_number(arg: &str) -> Option<u32> {
+    match arg.strip_prefix("0x").or_else(|| arg.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => arg.parse().ok(),
+    }
+}
+
+fn on_off(value: u32) -> &'static str {
+    if value != 0 {
+        "on"
+    } else {
+        "off"
+    }
+}
@@ -0,0 +1,193 @@
+//! The requests the CLI makes, on top of any `Transport`
+
+use std::fmt;
+use std::io;
+
+use clueboard_core::via::{self, Value, MAX_CHUNK, REPORT_LEN, UNHANDLED};
+use clueboard_core::{COLS, ROWS};
+
+use crate::transport::Transport;
+
+/// `QK_KB_0`, setting a key to it puts back the compiled in action, so it never reads back as set
+const DEFAULT_KEYCODE: u16 = 0x7E00;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The keyboard doesn't support the command, the firmware may be too old
+    Unhandled(u8),
+    /// The keyboard answered something other than the command
+    Unexpected {
+        command: u8,
+        response: u8,
+    },
+    /// The keyboard has no key there, it ignores changes to it
+    NoSuchKey {
+        layer: u8,
+        row: u8,
+        col: u8,
+    },
+    /// The key reads back as something else after being set, the keyboard doesn't support the
+    /// keycode or won't allow it
+    NotSet {
+        code: u16,
+        read: u16,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => err.fmt(f),
+            Error::Unhandled(command) => {
+                write!(f, "the keyboard doesn't support command {:#04x}", command)
+            }
+            Error::Unexpected { command, response } => write!(
+                f,
+                "expected a response to command {:#04x}, got {:#04x}",
+                command, response
+            ),
+            Error::NoSuchKey { layer, row, col } => write!(
+                f,
+                "there's no key at row {}, column {} on layer {}",
+                row, col, layer
+            ),
+            Error::NotSet { code, read } => write!(
+                f,
+                "the keyboard didn't accept keycode {:#06x}, the key is {:#06x}",
+                code, read
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+pub struct Client<T> {
+    transport: T,
+}
+
+impl<T: Transport> Client<T> {
+    pub fn new(transport: T) -> Self {
+        Client { transport }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+
+    pub fn protocol_version(&mut self) -> Result<u16, Error> {
+        let response = self.request(&[via::GET_PROTOCOL_VERSION])?;
+        Ok(u16::from_be_bytes([response[1], response[2]]))
+    }
+
+    /// Firmware version, major in the third byte down to patch in the lowest
+    pub fn firmware_version(&mut self) -> Result<u32, Error> {
+        self.keyboard_value(via::FIRMWARE_VERSION)
+    }
+
+    /// Milliseconds since the keyboard started
+    pub fn uptime(&mut self) -> Result<u32, Error> {
+        self.keyboard_value(via::UPTIME)
+    }
+
+    pub fn value(&mut self, value: Value) -> Result<u32, Error> {
+        let response = self.request(&[via::CUSTOM_GET_VALUE, via::CUSTOM_CHANNEL, value as u8])?;
+        Ok(u32::from_be_bytes([
+            response[3],
+            response[4],
+            response[5],
+            response[6],
+        ]))
+    }
+
+    pub fn layer_count(&mut self) -> Result<u8, Error> {
+        Ok(self.request(&[via::DYNAMIC_KEYMAP_GET_LAYER_COUNT])?[1])
+    }
+
+    /// Every keycode, by layer then matrix row then column
+    pub fn keymap(&mut self) -> Result<Vec<[[u16; COLS]; ROWS]>, Error> {
+        let layer_count = self.layer_count()?;
+        let mut bytes = vec![0; usize::from(layer_count) * ROWS * COLS * 2];
+        for (i, chunk) in bytes.chunks_mut(MAX_CHUNK).enumerate() {
+            let [hi, lo] = ((i * MAX_CHUNK) as u16).to_be_bytes();
+            let size = chunk.len() as u8;
+            let response = self.request(&[via::DYNAMIC_KEYMAP_GET_BUFFER, hi, lo, size])?;
+            chunk.copy_from_slice(&response[4..4 + chunk.len()]);
+        }
+
+        let mut codes = bytes
+            .chunks_exact(2)
+            .map(|code| u16::from_be_bytes([code[0], code[1]]));
+        let mut layers = vec![[[0; COLS]; ROWS]; usize::from(layer_count)];
+        for code in layers.iter_mut().flatten().flatten() {
+            *code = codes.next().unwrap_or_default();
+        }
+        Ok(layers)
+    }
+
+    pub fn keycode(&mut self, layer: u8, row: u8, col: u8) -> Result<u16, Error> {
+        let response = self.request(&[via::DYNAMIC_KEYMAP_GET_KEYCODE, layer, row, col])?;
+        Ok(u16::from_be_bytes([response[4], response[5]]))
+    }
+
+    /// Change the key at `row`, `col` on `layer` to `code`
+    ///
+    /// The keyboard answers the same whether or not it made the change, so the key is checked
+    /// first and read back after.
+    pub fn set_keycode(&mut self, layer: u8, row: u8, col: u8, code: u16) -> Result<(), Error> {
+        if layer >= self.layer_count()? || usize::from(row) >= ROWS || usize::from(col) >= COLS {
+            return Err(Error::NoSuchKey { layer, row, col });
+        }
+        let [hi, lo] = code.to_be_bytes();
+        self.request(&[via::DYNAMIC_KEYMAP_SET_KEYCODE, layer, row, col, hi, lo])?;
+        let read = self.keycode(layer, row, col)?;
+        if read != code && code != DEFAULT_KEYCODE {
+            return Err(Error::NotSet { code, read });
+        }
+        Ok(())
+    }
+
+    /// Reboot the keyboard into its bootloader, it doesn't answer this
+    pub fn bootloader(&mut self) -> Result<(), Error> {
+        self.transport.write(&report(&[via::BOOTLOADER_JUMP]))?;
+        Ok(())
+    }
+
+    fn keyboard_value(&mut self, id: u8) -> Result<u32, Error> {
+        let response = self.request(&[via::GET_KEYBOARD_VALUE, id])?;
+        Ok(u32::from_be_bytes([
+            response[2],
+            response[3],
+            response[4],
+            response[5],
+        ]))
+    }
+
+    /// Send `request` and wait for the response to it
+    fn request(&mut self, request: &[u8]) -> Result<[u8; REPORT_LEN], Error> {
+        self.transport.write(&report(request))?;
+        let response = self.transport.read()?;
+        match response[0] {
+            command if command == request[0] => Ok(response),
+            UNHANDLED => Err(Error::Unhandled(request[0])),
+            response => Err(Error::Unexpected {
+                command: request[0],
+                response,
+            }),
+        }
+    }
+}
+
+/// `request` padded out to a whole report
+fn report(request: &[u8]) -> [u8; REPORT_LEN] {
+    let mut report = [0; REPORT_LEN];
+    report[..request.len()].copy_from_slice(request);
+    report
+}
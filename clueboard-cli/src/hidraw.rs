@@ -0,0 +1,82 @@
+//! Keyboards found through Linux's hidraw devices
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use clueboard_core::via::REPORT_LEN;
+
+use crate::transport::Transport;
+
+/// Start of the raw HID interface's report descriptor, usage page 0xFF60. The keyboard's other
+/// interfaces have the same IDs.
+const USAGE_PAGE: [u8; 3] = [0x06, 0x60, 0xFF];
+
+/// A raw HID interface of a connected keyboard
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Device {
+    /// The device node, like `/dev/hidraw3`
+    pub path: PathBuf,
+    /// Manufacturer and product name
+    pub name: String,
+}
+
+/// The raw HID interfaces of every connected USB keyboard with these IDs
+pub fn devices(vid: u16, pid: u16) -> io::Result<Vec<Device>> {
+    let id = format!("HID_ID=0003:{:08X}:{:08X}", vid, pid);
+    let entries = match fs::read_dir("/sys/class/hidraw") {
+        Ok(entries) => entries,
+        // No hidraw devices at all
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let mut devices = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let sys = entry.path().join("device");
+        let uevent = fs::read_to_string(sys.join("uevent"))?;
+        if !uevent.lines().any(|line| line == id) {
+            continue;
+        }
+        if !fs::read(sys.join("report_descriptor"))?.starts_with(&USAGE_PAGE) {
+            continue;
+        }
+        let name = uevent
+            .lines()
+            .find_map(|line| line.strip_prefix("HID_NAME="))
+            .unwrap_or_default();
+        devices.push(Device {
+            path: Path::new("/dev").join(entry.file_name()),
+            name: name.to_string(),
+        });
+    }
+    devices.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(devices)
+}
+
+/// An open hidraw device node
+pub struct HidRaw {
+    file: File,
+}
+
+impl HidRaw {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(HidRaw { file })
+    }
+}
+
+impl Transport for HidRaw {
+    fn write(&mut self, report: &[u8; REPORT_LEN]) -> io::Result<()> {
+        // hidraw wants the report ID first, the interface doesn't use them so it's 0
+        let mut buf = [0; REPORT_LEN + 1];
+        buf[1..].copy_from_slice(report);
+        self.file.write_all(&buf)
+    }
+
+    fn read(&mut self) -> io::Result<[u8; REPORT_LEN]> {
+        let mut report = [0; REPORT_LEN];
+        self.file.read_exact(&mut report)?;
+        Ok(report)
+    }
+}
@@ -0,0 +1,13 @@
+//! Talks to the keyboard from the host, over the same raw HID interface VIA uses
+//!
+//! The requests are made over a `Transport` so they can be tested against a simulated keyboard.
+//! Being a host crate it needs the host target:
+//!
+//! ```text
+//! cargo run -p clueboard-cli --target x86_64-unknown-linux-gnu -- list
+//! ```
+
+pub mod cli;
+pub mod client;
+pub mod hidraw;
+pub mod transport;
@@ -0,0 +1,43 @@
+use std::io::{self, Write};
+use std::process;
+
+use clueboard_cli::cli::{self, Command};
+use clueboard_cli::client::{Client, Error};
+use clueboard_cli::hidraw::{self, HidRaw};
+use clueboard_core::{PID, VID};
+
+fn main() {
+    let args = match cli::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(message) => {
+            eprintln!("clueboard: {}\n\n{}", message, cli::USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(err) = run(args) {
+        eprintln!("clueboard: {}", err);
+        process::exit(1);
+    }
+}
+
+fn run(args: cli::Args) -> Result<(), Error> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    if args.command == Command::List {
+        for device in hidraw::devices(VID, PID)? {
+            writeln!(out, "{}: {}", device.path.display(), device.name)?;
+        }
+        return Ok(());
+    }
+
+    let path = match args.device {
+        Some(path) => path,
+        None => hidraw::devices(VID, PID)?
+            .into_iter()
+            .next()
+            .map(|device| device.path)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no keyboard found"))?,
+    };
+    let mut client = Client::new(HidRaw::open(&path)?);
+    cli::run(args.command, &mut client, &mut out)
+}
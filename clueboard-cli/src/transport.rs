@@ -0,0 +1,13 @@
+//! How reports get to and from the keyboard
+
+use std::io;
+
+use clueboard_core::via::REPORT_LEN;
+
+/// Sends requests to the keyboard and reads back its responses
+pub trait Transport {
+    fn write(&mut self, report: &[u8; REPORT_LEN]) -> io::Result<()>;
+
+    /// Wait for the next report from the keyboard
+    fn read(&mut self) -> io::Result<[u8; REPORT_LEN]>;
+}
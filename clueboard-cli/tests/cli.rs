@@ -0,0 +1,243 @@
+//! The CLI against a simulated keyboard that handles requests the way the firmware does
+
+use std::collections::VecDeque;
+use std::io;
+
+use clueboard_cli::cli::{self, Command};
+use clueboard_cli::client::{Client, Error};
+use clueboard_cli::transport::Transport;
+use clueboard_core::dynamic_macros::MacroBuffer;
+use clueboard_core::via::{self, Value, REPORT_LEN};
+use clueboard_core::{COLS, ROWS};
+
+const LAYERS: usize = 3;
+const QK_BOOT: u16 = 0x7C00;
+
+struct Simulator {
+    keymap: [[[u16; COLS]; ROWS]; LAYERS],
+    macros: MacroBuffer,
+    matrix: [[bool; COLS]; ROWS],
+    bootloader: bool,
+    /// Like Vial before it's unlocked, `QK_BOOT` can't be set
+    locked: bool,
+    responses: VecDeque<[u8; REPORT_LEN]>,
+}
+
+impl Simulator {
+    fn new() -> Self {
+        let mut keymap = [[[0; COLS]; ROWS]; LAYERS];
+        keymap[0][0][0] = 0x0029;
+        keymap[1][0][1] = 0x003A;
+        Simulator {
+            keymap,
+            macros: MacroBuffer::new(),
+            matrix: [[false; COLS]; ROWS],
+            bootloader: false,
+            locked: false,
+            responses: VecDeque::new(),
+        }
+    }
+}
+
+impl via::Keyboard for Simulator {
+    fn layer_count(&self) -> u8 {
+        LAYERS as u8
+    }
+
+    fn keycode(&self, layer: u8, row: u8, col: u8) -> u16 {
+        self.keymap[usize::from(layer)][usize::from(row)][usize::from(col)]
+    }
+
+    fn set_keycode(&mut self, layer: u8, row: u8, col: u8, code: u16) {
+        if self.locked && code == QK_BOOT {
+            return;
+        }
+        self.keymap[usize::from(layer)][usize::from(row)][usize::from(col)] = code;
+    }
+
+    fn reset_keymap(&mut self) {
+        self.keymap = Simulator::new().keymap;
+    }
+
+    fn macros(&mut self) -> &mut MacroBuffer {
+        &mut self.macros
+    }
+
+    fn uptime(&self) -> u32 {
+        3_723_000
+    }
+
+    fn matrix(&self) -> &[[bool; COLS]; ROWS] {
+        &self.matrix
+    }
+
+    fn firmware_version(&self) -> u32 {
+        0x0001_0203
+    }
+
+    fn value(&self, value: Value) -> u32 {
+        match value {
+            Value::BacklightLevel => 4,
+            Value::BacklightEnabled => 1,
+            Value::Effect => 1,
+            Value::Clicky => 0,
+            Value::Volume => 2047,
+            Value::DefaultLayer => 0,
+            Value::KeyPresses => 1234,
+        }
+    }
+
+    fn bootloader(&mut self) {
+        self.bootloader = true;
+    }
+}
+
+impl Transport for Simulator {
+    fn write(&mut self, report: &[u8; REPORT_LEN]) -> io::Result<()> {
+        let mut report = *report;
+        via::handle(self, &mut report);
+        if !self.bootloader {
+            self.responses.push_back(report);
+        }
+        Ok(())
+    }
+
+    fn read(&mut self) -> io::Result<[u8; REPORT_LEN]> {
+        self.responses
+            .pop_front()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "no response"))
+    }
+}
+
+/// Run the command line `args` against `simulator`, returning what was printed
+fn run(simulator: Simulator, args: &[&str]) -> (Simulator, Result<String, Error>) {
+    let args = cli::parse(args.iter().map(|arg| arg.to_string())).unwrap();
+    let mut client = Client::new(simulator);
+    let mut out = Vec::new();
+    let result = cli::run(args.command, &mut client, &mut out);
+    let out = String::from_utf8(out).unwrap();
+    (client.into_inner(), result.map(|()| out))
+}
+
+#[test]
+fn keymap_dump() {
+    let (_, out) = run(Simulator::new(), &["keymap"]);
+    let out = out.unwrap();
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines.len(), LAYERS * (ROWS + 1));
+    assert_eq!(lines[0], "Layer 0");
+    assert_eq!(
+        lines[1],
+        "  row 0: 0x0029 0x0000 0x0000 0x0000 0x0000 0x0000 0x0000 0x0000"
+    );
+    assert_eq!(lines[ROWS + 1], "Layer 1");
+    assert!(lines[ROWS + 2].starts_with("  row 0: 0x0000 0x003a "));
+}
+
+#[test]
+fn set_keycode() {
+    let (simulator, out) = run(Simulator::new(), &["set", "2", "9", "7", "0x5221"]);
+    assert_eq!(out.unwrap(), "");
+    assert_eq!(simulator.keymap[2][9][7], 0x5221);
+
+    let (_, out) = run(simulator, &["keymap"]);
+    assert!(out.unwrap().lines().last().unwrap().ends_with(" 0x5221"));
+}
+
+#[test]
+fn set_keycode_checks_the_key_exists() {
+    for args in [["3", "0", "0"], ["0", "10", "0"], ["0", "0", "8"]].iter() {
+        let (simulator, out) = run(Simulator::new(), &["set", args[0], args[1], args[2], "4"]);
+        assert!(matches!(out, Err(Error::NoSuchKey { .. })), "{:?}", args);
+        assert_eq!(simulator.keymap, Simulator::new().keymap);
+    }
+}
+
+#[test]
+fn set_keycode_checks_the_key_changed() {
+    let mut simulator = Simulator::new();
+    simulator.locked = true;
+    let (_, out) = run(simulator, &["set", "0", "0", "0", "0x7C00"]);
+    assert!(matches!(
+        out,
+        Err(Error::NotSet {
+            code: QK_BOOT,
+            read: 0x0029,
+        })
+    ));
+}
+
+#[test]
+fn settings() {
+    let (_, out) = run(Simulator::new(), &["settings"]);
+    assert_eq!(
+        out.unwrap(),
+        "backlight level: 4\n\
+         backlight: on\n\
+         effect: Breathing\n\
+         clicky: off\n\
+         volume: 2047\n\
+         default layer: 0\n"
+    );
+}
+
+#[test]
+fn stats() {
+    let (_, out) = run(Simulator::new(), &["stats"]);
+    assert_eq!(
+        out.unwrap(),
+        "firmware version: 1.2.3\n\
+         protocol version: 12\n\
+         uptime: 1:02:03\n\
+         key presses: 1234\n"
+    );
+}
+
+#[test]
+fn bootloader() {
+    let (simulator, out) = run(Simulator::new(), &["--device", "/dev/null", "bootloader"]);
+    assert_eq!(out.unwrap(), "");
+    assert!(simulator.bootloader);
+}
+
+#[test]
+fn bad_responses_are_errors() {
+    let mut simulator = Simulator::new();
+    // As if the firmware were too old to know the command
+    simulator.responses.push_back([via::UNHANDLED; REPORT_LEN]);
+    let (_, out) = run(simulator, &["settings"]);
+    assert!(matches!(out, Err(Error::Unhandled(via::CUSTOM_GET_VALUE))));
+
+    let mut simulator = Simulator::new();
+    simulator.responses.push_back([0x42; REPORT_LEN]);
+    let (_, out) = run(simulator, &["stats"]);
+    assert!(matches!(
+        out,
+        Err(Error::Unexpected {
+            command: via::GET_KEYBOARD_VALUE,
+            response: 0x42,
+        })
+    ));
+}
+
+#[test]
+fn parse_errors() {
+    let parse = |args: &[&str]| cli::parse(args.iter().map(|arg| arg.to_string()));
+    assert_eq!(parse(&["list"]).unwrap().command, Command::List);
+    assert_eq!(
+        parse(&["--device", "/dev/hidraw3", "set", "1", "0", "0x2", "41"])
+            .unwrap()
+            .command,
+        Command::SetKeycode {
+            layer: 1,
+            row: 0,
+            col: 2,
+            code: 41
+        }
+    );
+    assert!(parse(&[]).is_err());
+    assert!(parse(&["set", "0", "0"]).is_err());
+    assert!(parse(&["set", "0", "0", "256", "4"]).is_err());
+    assert!(parse(&["keymap", "extra"]).is_err());
+    assert!(parse(&["frobnicate"]).is_err());
+}
@@ -0,0 +1,352 @@
+//! Note sequencing for the speakers
+//!
+//! `Player` holds a queue of notes and is advanced once per matrix scan. It only works out which
+//! frequency should be playing, driving the speakers is left to the firmware. Songs are played
+//! straight from their static note tables so they can be longer than the queue.
+
+/// Maximum number of notes waiting to be played
+pub const QUEUE_LEN: usize = 32;
+
+/// Pitch of the key click on the base layer
+const CLICK_FREQUENCY: u16 = 2000;
+/// How much higher the click is on each layer above the base layer
+const CLICK_LAYER_STEP: u16 = 400;
+/// Length of the key click in ticks
+const CLICK_DURATION: u16 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Note {
+    /// Frequency in Hz, 0 for a rest
+    pub frequency: u16,
+    /// Duration in ticks (milliseconds)
+    pub duration: u16,
+}
+
+impl Note {
+    pub const fn new(frequency: u16, duration: u16) -> Self {
+        Note {
+            frequency,
+            duration,
+        }
+    }
+
+    /// Silence for `duration`
+    pub const fn rest(duration: u16) -> Self {
+        Note::new(0, duration)
+    }
+}
+
+pub struct Player {
+    notes: [Note; QUEUE_LEN],
+    head: usize,
+    len: usize,
+    /// Notes of the song being played, after the queue
+    song: &'static [Note],
+    /// Ticks left of the current note
+    remaining: u16,
+    /// Frequency currently being output
+    frequency: u16,
+    /// Whether anything is played at all
+    enabled: bool,
+    /// Whether what's playing is a key click, which another click can cut off
+    clicking: bool,
+}
+
+impl Player {
+    pub const fn new() -> Self {
+        Player {
+            notes: [Note::rest(0); QUEUE_LEN],
+            head: 0,
+            len: 0,
+            song: &[],
+            remaining: 0,
+            frequency: 0,
+            enabled: true,
+            clicking: false,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Turn all sound on or off, nothing is played while it's off
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn toggle(&mut self) {
+        self.set_enabled(!self.enabled);
+    }
+
+    /// Play `frequency` for `duration` ticks straight away, dropping anything queued
+    pub fn play(&mut self, frequency: u16, duration: u16) {
+        self.stop();
+        self.queue(Note::new(frequency, duration)).ok();
+    }
+
+    /// Play `song` straight away, dropping anything queued
+    pub fn play_song(&mut self, song: &'static [Note]) {
+        self.stop();
+        self.song = song;
+    }
+
+    /// Play a key click straight away, unless something other than a click is playing
+    pub fn click(&mut self, click: Note) {
+        if self.is_playing() && !self.clicking {
+            return;
+        }
+        self.play(click.frequency, click.duration);
+        self.clicking = true;
+    }
+
+    /// Add `note` to the end of the queue
+    ///
+    /// If the queue is full the note is handed back.
+    pub fn queue(&mut self, note: Note) -> Result<(), Note> {
+        if self.len == QUEUE_LEN {
+            return Err(note);
+        }
+        self.notes[(self.head + self.len) % QUEUE_LEN] = note;
+        self.len += 1;
+        self.clicking = false;
+        Ok(())
+    }
+
+    /// Add each of `notes` to the queue, returns the number that fit
+    pub fn queue_all(&mut self, notes: &[Note]) -> usize {
+        notes
+            .iter()
+            .take_while(|&&note| self.queue(note).is_ok())
+            .count()
+    }
+
+    /// Silence the speakers and drop everything queued
+    pub fn stop(&mut self) {
+        self.len = 0;
+        self.song = &[];
+        self.remaining = 0;
+        self.clicking = false;
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.remaining != 0 || self.len != 0 || !self.song.is_empty()
+    }
+
+    /// Advance time by one tick
+    ///
+    /// Returns the frequency the speakers should switch to, 0 meaning silent, or `None` if they
+    /// should carry on as they are.
+    pub fn tick(&mut self) -> Option<u16> {
+        if !self.enabled {
+            // Anything started while sound is off is dropped rather than played later
+            self.stop();
+            return self.output(0);
+        }
+        if self.remaining != 0 {
+            self.remaining -= 1;
+            return None;
+        }
+
+        while let Some(note) = self.pop() {
+            if note.duration != 0 {
+                self.remaining = note.duration - 1;
+                return self.output(note.frequency);
+            }
+        }
+        self.output(0)
+    }
+
+    fn pop(&mut self) -> Option<Note> {
+        if self.len == 0 {
+            let (&note, rest) = self.song.split_first()?;
+            self.song = rest;
+            return Some(note);
+        }
+        let note = self.notes[self.head];
+        self.head = (self.head + 1) % QUEUE_LEN;
+        self.len -= 1;
+        Some(note)
+    }
+
+    fn output(&mut self, frequency: u16) -> Option<u16> {
+        if frequency == self.frequency {
+            None
+        } else {
+            self.frequency = frequency;
+            Some(frequency)
+        }
+    }
+}
+
+/// Sound changes that can be bound in the layout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioAction {
+    /// Turn all sound on or off
+    Toggle,
+    /// Turn the key click on or off
+    ToggleClicky,
+    VolumeUp,
+    VolumeDown,
+}
+
+/// Plays a short click for each key press
+#[derive(Default)]
+pub struct Clicky {
+    enabled: bool,
+}
+
+impl Clicky {
+    pub const fn new() -> Self {
+        Clicky { enabled: false }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn toggle(&mut self) {
+        self.enabled = !self.enabled;
+    }
+
+    /// The click for a key pressed while `layer` is active, if enabled
+    pub fn click(&self, layer: usize) -> Option<Note> {
+        if !self.enabled {
+            return None;
+        }
+        let step = CLICK_LAYER_STEP.saturating_mul(layer as u16);
+        Some(Note::new(
+            CLICK_FREQUENCY.saturating_add(step),
+            CLICK_DURATION,
+        ))
+    }
+}
+
+impl Default for Player {
+    fn default() -> Self {
+        Player::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tick `player` `ticks` times, collecting each change of frequency with the tick it happened
+    fn run(player: &mut Player, ticks: u16) -> [Option<(u16, u16)>; 8] {
+        let mut changes = [None; 8];
+        let mut i = 0;
+        for tick in 0..ticks {
+            if let Some(frequency) = player.tick() {
+                changes[i] = Some((tick, frequency));
+                i += 1;
+            }
+        }
+        changes
+    }
+
+    #[test]
+    fn plays_queued_notes_in_order() {
+        let mut player = Player::new();
+        player.queue(Note::new(440, 3)).unwrap();
+        player.queue(Note::rest(2)).unwrap();
+        player.queue(Note::new(880, 1)).unwrap();
+
+        let changes = run(&mut player, 10);
+        assert_eq!(
+            &changes[..4],
+            &[Some((0, 440)), Some((3, 0)), Some((5, 880)), Some((6, 0))]
+        );
+        assert!(!player.is_playing());
+    }
+
+    #[test]
+    fn repeated_frequencies_are_not_restarted() {
+        let mut player = Player::new();
+        player.queue_all(&[Note::new(440, 2), Note::new(440, 2)]);
+        let changes = run(&mut player, 10);
+        assert_eq!(&changes[..2], &[Some((0, 440)), Some((4, 0))]);
+    }
+
+    #[test]
+    fn play_replaces_the_queue() {
+        let mut player = Player::new();
+        player.queue_all(&[Note::new(440, 100), Note::new(880, 100)]);
+        player.tick();
+        player.play(1000, 1);
+        let changes = run(&mut player, 300);
+        assert_eq!(&changes[..2], &[Some((0, 1000)), Some((1, 0))]);
+    }
+
+    #[test]
+    fn songs_play_after_the_queue_is_empty() {
+        static SONG: [Note; QUEUE_LEN + 2] = [Note::new(440, 1); QUEUE_LEN + 2];
+        let mut player = Player::new();
+        player.play_song(&SONG);
+        player.queue(Note::new(880, 1)).unwrap();
+        assert_eq!(player.tick(), Some(880));
+        assert_eq!(player.tick(), Some(440));
+        for _ in 0..SONG.len() - 1 {
+            assert_eq!(player.tick(), None);
+        }
+        assert_eq!(player.tick(), Some(0));
+        assert!(!player.is_playing());
+    }
+
+    #[test]
+    fn nothing_plays_while_disabled() {
+        static SONG: [Note; 1] = [Note::new(880, 1)];
+        let mut player = Player::new();
+        player.play(440, 10);
+        assert_eq!(player.tick(), Some(440));
+        player.toggle();
+        assert_eq!(player.tick(), Some(0));
+        player.play_song(&SONG);
+        assert_eq!(run(&mut player, 10), [None; 8]);
+        assert!(!player.is_playing());
+
+        player.toggle();
+        player.play(440, 1);
+        assert_eq!(player.tick(), Some(440));
+    }
+
+    #[test]
+    fn clicks_dont_cut_off_songs() {
+        static SONG: [Note; 2] = [Note::new(440, 5), Note::new(880, 5)];
+        let click = Note::new(2000, 2);
+        let mut player = Player::new();
+        player.play_song(&SONG);
+        assert_eq!(player.tick(), Some(440));
+        player.click(click);
+        assert_eq!(run(&mut player, 20)[..2], [Some((4, 880)), Some((9, 0))]);
+
+        // Clicks do replace each other
+        player.click(click);
+        player.tick();
+        player.click(Note::new(3000, 2));
+        assert_eq!(player.tick(), Some(3000));
+    }
+
+    #[test]
+    fn clicks_are_higher_on_upper_layers() {
+        let mut clicky = Clicky::new();
+        assert_eq!(clicky.click(0), None);
+        clicky.toggle();
+        let base = clicky.click(0).unwrap();
+        let upper = clicky.click(2).unwrap();
+        assert!(upper.frequency > base.frequency);
+        assert_eq!(upper.duration, base.duration);
+    }
+
+    #[test]
+    fn queue_is_bounded() {
+        let mut player = Player::new();
+        let notes = [Note::new(440, 1); QUEUE_LEN + 1];
+        assert_eq!(player.queue_all(&notes), QUEUE_LEN);
+        assert_eq!(player.queue(notes[0]), Err(notes[0]));
+    }
+}
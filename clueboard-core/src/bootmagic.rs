@@ -0,0 +1,79 @@
+//! Power-on key checks
+//!
+//! The matrix is read once while the keyboard starts up. Holding certain keys while plugging it in
+//! is a way to recover when a bad keymap or setting has made the keyboard unusable, like QMK's
+//! Bootmagic.
+
+use crate::{COLS, ROWS};
+
+/// Matrix position of Esc, which enters the bootloader
+const ESC: (usize, usize) = (0, 0);
+/// Matrix position of the left space bar, held with one other key for the options below
+const SPACE: (usize, usize) = (4, 5);
+/// Matrix position of Backspace, which with Space clears the stored settings
+const BACKSPACE: (usize, usize) = (5, 5);
+/// Matrix position of K, which with Space starts with the default keymap
+const K: (usize, usize) = (7, 0);
+
+/// What to do differently while starting up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bootmagic {
+    /// Go straight to the DFU bootloader
+    Bootloader,
+    /// Forget all stored settings
+    ClearSettings,
+    /// Use the compiled in keymap, ignoring any changes made to it
+    SafeKeymap,
+}
+
+/// Check which keys are held in `pressed`, indexed by matrix row then column
+pub fn check(pressed: &[[bool; COLS]; ROWS]) -> Option<Bootmagic> {
+    let held = |(row, col): (usize, usize)| pressed[row][col];
+    if held(ESC) {
+        Some(Bootmagic::Bootloader)
+    } else if held(SPACE) && held(BACKSPACE) {
+        Some(Bootmagic::ClearSettings)
+    } else if held(SPACE) && held(K) {
+        Some(Bootmagic::SafeKeymap)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn holding(keys: &[(usize, usize)]) -> [[bool; COLS]; ROWS] {
+        let mut pressed = [[false; COLS]; ROWS];
+        for &(row, col) in keys {
+            pressed[row][col] = true;
+        }
+        pressed
+    }
+
+    #[test]
+    fn nothing_held() {
+        assert_eq!(check(&holding(&[])), None);
+        assert_eq!(check(&holding(&[SPACE])), None);
+        assert_eq!(check(&holding(&[BACKSPACE, K])), None);
+    }
+
+    #[test]
+    fn escape_enters_the_bootloader() {
+        assert_eq!(check(&holding(&[ESC])), Some(Bootmagic::Bootloader));
+        assert_eq!(
+            check(&holding(&[ESC, SPACE, BACKSPACE])),
+            Some(Bootmagic::Bootloader)
+        );
+    }
+
+    #[test]
+    fn space_combinations() {
+        assert_eq!(
+            check(&holding(&[SPACE, BACKSPACE])),
+            Some(Bootmagic::ClearSettings)
+        );
+        assert_eq!(check(&holding(&[SPACE, K])), Some(Bootmagic::SafeKeymap));
+    }
+}
@@ -0,0 +1,280 @@
+//! Keys pressed together that type something else, as set with Vial
+//!
+//! Combos work on the keys the keymap reports rather than switch positions, the same as QMK. A key
+//! that's part of a combo is held back for up to `COMBO_TERM` ticks while the rest of the combo
+//! might be pressed. Only basic keycodes can be used, for both the keys pressed and the output.
+
+use core::convert::TryFrom;
+
+use crate::dynamic_entries::{self, Entries, Entry, ENTRY_LEN};
+
+/// Number of combos that can be set
+pub const COMBO_COUNT: usize = 8;
+/// How long the keys of a combo have to be pressed within, in ticks
+pub const COMBO_TERM: u16 = 50;
+/// Most keys in a combo
+const MAX_INPUTS: usize = 4;
+/// Most keys down at once that combos can keep track of
+const MAX_PRESSED: usize = 16;
+
+pub type Combos = Entries<Combo, COMBO_COUNT>;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Combo {
+    /// Keys to press together, unused ones are 0
+    pub inputs: [u16; MAX_INPUTS],
+    /// Key to type instead of them
+    pub output: u16,
+}
+
+impl Combo {
+    fn is_set(&self) -> bool {
+        self.output != 0 && self.inputs().next().is_some()
+    }
+
+    fn inputs(&self) -> impl Iterator<Item = u8> + '_ {
+        self.inputs
+            .iter()
+            .filter(|&&code| code != 0)
+            .map(|&code| basic(code).unwrap_or(0))
+    }
+}
+
+impl Entry for Combo {
+    const FIRST_STORE_KEY: u8 = 0x78;
+
+    fn from_bytes(bytes: &[u8; ENTRY_LEN]) -> Self {
+        let [a, b, c, d, output] = dynamic_entries::codes(bytes);
+        Combo {
+            inputs: [a, b, c, d],
+            output,
+        }
+    }
+
+    fn to_bytes(&self) -> [u8; ENTRY_LEN] {
+        let [a, b, c, d] = self.inputs;
+        dynamic_entries::to_bytes(&[a, b, c, d, self.output])
+    }
+}
+
+/// Turns the keys the keymap reports into the keys to send, with combos applied
+#[derive(Debug, Default)]
+pub struct Combiner {
+    /// Keys the keymap reported last tick, 0 is empty
+    pressed: [u8; MAX_PRESSED],
+    /// Keys held back in case they're part of a combo, in the order they were pressed
+    waiting: [u8; MAX_INPUTS],
+    /// Ticks since the first waiting key was pressed
+    waited: u16,
+    /// Keys released while they were waiting, sent for one tick
+    taps: [u8; MAX_INPUTS],
+    /// Keys used up by a combo, they're not sent until they've been released
+    used: [u8; MAX_PRESSED],
+    /// Which of the combos are pressed
+    active: [bool; COMBO_COUNT],
+}
+
+impl Combiner {
+    pub fn new() -> Self {
+        Combiner::default()
+    }
+
+    /// Take the HID usages of the keys the keymap has down this tick
+    pub fn update(&mut self, combos: &[Combo], pressed: impl Iterator<Item = u8>) {
+        let previous = self.pressed;
+        self.pressed = [0; MAX_PRESSED];
+        for (slot, usage) in self.pressed.iter_mut().zip(pressed) {
+            *slot = usage;
+        }
+        self.taps = [0; MAX_INPUTS];
+        let pressed = self.pressed;
+
+        // Releasing a waiting key means it's not a combo, the ones still down are sent as usual
+        let released = |usage| usage != 0 && !contains(&pressed, usage);
+        if self.waiting.iter().any(|&usage| released(usage)) {
+            for (tap, &usage) in self.taps.iter_mut().zip(self.waiting.iter()) {
+                if released(usage) {
+                    *tap = usage;
+                }
+            }
+            self.waiting = [0; MAX_INPUTS];
+        }
+        for slot in self.used.iter_mut() {
+            if !contains(&pressed, *slot) {
+                *slot = 0;
+            }
+        }
+        for (active, combo) in self.active.iter_mut().zip(combos) {
+            // Letting go of any of the keys ends the combo
+            *active &= combo.inputs().all(|usage| contains(&pressed, usage));
+        }
+
+        // Presses
+        for &usage in pressed.iter().filter(|&&usage| usage != 0) {
+            if contains(&previous, usage) {
+                continue;
+            }
+            let is_input = combos
+                .iter()
+                .any(|combo| combo.is_set() && combo.inputs().any(|input| input == usage));
+            if is_input && insert(&mut self.waiting, usage) {
+                if self.waiting.iter().filter(|&&usage| usage != 0).count() == 1 {
+                    self.waited = 0;
+                }
+            } else {
+                // Anything else pressed means the waiting keys aren't a combo
+                self.waiting = [0; MAX_INPUTS];
+            }
+        }
+
+        // Finished combos
+        for (active, combo) in self.active.iter_mut().zip(combos) {
+            let waiting = self.waiting;
+            if !*active && combo.is_set() && combo.inputs().all(|usage| contains(&waiting, usage)) {
+                *active = true;
+                for usage in combo.inputs() {
+                    remove(&mut self.waiting, usage);
+                    insert(&mut self.used, usage);
+                }
+            }
+        }
+
+        if self.waiting.iter().any(|&usage| usage != 0) {
+            self.waited += 1;
+            if self.waited >= COMBO_TERM {
+                self.waiting = [0; MAX_INPUTS];
+            }
+        }
+    }
+
+    /// HID usages of the keys to send, after the last `update`
+    pub fn keys<'a>(&'a self, combos: &'a [Combo]) -> impl Iterator<Item = u8> + 'a {
+        let pressed = self.pressed.iter().copied().filter(move |&usage| {
+            usage != 0 && !contains(&self.waiting, usage) && !contains(&self.used, usage)
+        });
+        let taps = self.taps.iter().copied().filter(|&usage| usage != 0);
+        let outputs = self
+            .active
+            .iter()
+            .zip(combos)
+            .filter(|(&active, _)| active)
+            .filter_map(|(_, combo)| basic(combo.output));
+        pressed.chain(taps).chain(outputs)
+    }
+}
+
+/// The HID usage of `code` if it's a basic keycode
+fn basic(code: u16) -> Option<u8> {
+    u8::try_from(code).ok().filter(|&usage| usage >= 0x04)
+}
+
+fn contains(usages: &[u8], usage: u8) -> bool {
+    usage != 0 && usages.contains(&usage)
+}
+
+/// Add `usage` to the first empty slot, returns false if there isn't one
+fn insert(usages: &mut [u8], usage: u8) -> bool {
+    if contains(usages, usage) {
+        return true;
+    }
+    match usages.iter_mut().find(|slot| **slot == 0) {
+        Some(slot) => {
+            *slot = usage;
+            true
+        }
+        None => false,
+    }
+}
+
+fn remove(usages: &mut [u8], usage: u8) {
+    for slot in usages.iter_mut().filter(|slot| **slot == usage) {
+        *slot = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMBOS: [Combo; 2] = [
+        // J + K types Escape
+        Combo {
+            inputs: [0x0D, 0x0E, 0, 0],
+            output: 0x29,
+        },
+        // Not set
+        Combo {
+            inputs: [0x04, 0, 0, 0],
+            output: 0,
+        },
+    ];
+
+    /// Update with `pressed` and collect the keys to send
+    fn update(combiner: &mut Combiner, pressed: &[u8]) -> [u8; 4] {
+        combiner.update(&COMBOS, pressed.iter().copied());
+        let mut keys = [0; 4];
+        for (key, usage) in keys.iter_mut().zip(combiner.keys(&COMBOS)) {
+            *key = usage;
+        }
+        keys
+    }
+
+    #[test]
+    fn other_keys_pass_straight_through() {
+        let mut combiner = Combiner::new();
+        assert_eq!(update(&mut combiner, &[0x04, 0x05]), [0x04, 0x05, 0, 0]);
+    }
+
+    #[test]
+    fn combo_replaces_its_keys() {
+        let mut combiner = Combiner::new();
+        assert_eq!(update(&mut combiner, &[0x0D]), [0; 4]);
+        assert_eq!(update(&mut combiner, &[0x0D, 0x0E]), [0x29, 0, 0, 0]);
+        assert_eq!(update(&mut combiner, &[0x0D, 0x0E]), [0x29, 0, 0, 0]);
+        // Letting go of one key ends the combo, without typing the other
+        assert_eq!(update(&mut combiner, &[0x0D]), [0; 4]);
+        assert_eq!(update(&mut combiner, &[]), [0; 4]);
+    }
+
+    #[test]
+    fn waiting_key_is_sent_after_the_term() {
+        let mut combiner = Combiner::new();
+        for _ in 1..COMBO_TERM {
+            assert_eq!(update(&mut combiner, &[0x0D]), [0; 4]);
+        }
+        assert_eq!(update(&mut combiner, &[0x0D]), [0x0D, 0, 0, 0]);
+        // Too late for the combo
+        assert_eq!(update(&mut combiner, &[0x0D, 0x0E]), [0x0D, 0, 0, 0]);
+    }
+
+    #[test]
+    fn waiting_key_is_tapped_when_released() {
+        let mut combiner = Combiner::new();
+        update(&mut combiner, &[0x0D]);
+        assert_eq!(update(&mut combiner, &[]), [0x0D, 0, 0, 0]);
+        assert_eq!(update(&mut combiner, &[]), [0; 4]);
+    }
+
+    #[test]
+    fn rolling_between_combo_keys_types_them() {
+        let mut combiner = Combiner::new();
+        update(&mut combiner, &[0x0D]);
+        // J is let go before K is pressed, so it's not the combo
+        assert_eq!(update(&mut combiner, &[0x0E]), [0x0D, 0, 0, 0]);
+        assert_eq!(update(&mut combiner, &[]), [0x0E, 0, 0, 0]);
+    }
+
+    #[test]
+    fn other_key_sends_waiting_keys() {
+        let mut combiner = Combiner::new();
+        update(&mut combiner, &[0x0D]);
+        assert_eq!(update(&mut combiner, &[0x0D, 0x04]), [0x0D, 0x04, 0, 0]);
+    }
+
+    #[test]
+    fn entries_round_trip() {
+        let bytes = COMBOS[0].to_bytes();
+        assert_eq!(bytes, [0x0D, 0, 0x0E, 0, 0, 0, 0, 0, 0x29, 0]);
+        assert_eq!(Combo::from_bytes(&bytes), COMBOS[0]);
+    }
+}
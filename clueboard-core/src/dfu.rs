@@ -0,0 +1,98 @@
+//! When a DFU detach request from the host reboots the keyboard
+//!
+//! The USB side of the DFU runtime interface is in the firmware. This decides whether a detach
+//! is accepted, and counts down to the reboot so the host sees its request complete first.
+
+/// Ticks to wait after accepting a detach before rebooting
+const DETACH_DELAY: u8 = 10;
+
+pub struct Detach {
+    allowed: bool,
+    /// Ticks until the keyboard should detach, once requested
+    detach_in: Option<u8>,
+}
+
+impl Detach {
+    pub const fn new() -> Self {
+        Detach {
+            allowed: true,
+            detach_in: None,
+        }
+    }
+
+    /// Whether a detach request would be accepted
+    pub fn is_allowed(&self) -> bool {
+        self.allowed
+    }
+
+    /// Allow or refuse detach requests, one already accepted is dropped when they're refused
+    pub fn set_allowed(&mut self, allowed: bool) {
+        self.allowed = allowed;
+        if !allowed {
+            self.detach_in = None;
+        }
+    }
+
+    /// Start counting down to the reboot, if detaching is allowed
+    pub fn start(&mut self) {
+        if self.allowed {
+            self.detach_in = Some(DETACH_DELAY);
+        }
+    }
+
+    /// Advance time by one tick, returns true when it's time to reboot into the bootloader
+    pub fn tick(&mut self) -> bool {
+        match self.detach_in {
+            Some(0) => true,
+            Some(ref mut ticks) => {
+                *ticks -= 1;
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+impl Default for Detach {
+    fn default() -> Self {
+        Detach::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tick until the reboot, returning how many ticks that took
+    fn ticks_to_reboot(detach: &mut Detach) -> Option<u8> {
+        (0..=DETACH_DELAY).find(|_| detach.tick())
+    }
+
+    #[test]
+    fn reboots_after_the_delay() {
+        let mut detach = Detach::new();
+        assert_eq!(ticks_to_reboot(&mut detach), None);
+        detach.start();
+        assert_eq!(ticks_to_reboot(&mut detach), Some(DETACH_DELAY));
+    }
+
+    #[test]
+    fn detach_while_locked_is_not_kept_for_later() {
+        let mut detach = Detach::new();
+        detach.set_allowed(false);
+        detach.start();
+        assert_eq!(ticks_to_reboot(&mut detach), None);
+        detach.set_allowed(true);
+        assert_eq!(ticks_to_reboot(&mut detach), None);
+    }
+
+    #[test]
+    fn locking_drops_an_accepted_detach() {
+        let mut detach = Detach::new();
+        detach.start();
+        detach.tick();
+        detach.set_allowed(false);
+        detach.set_allowed(true);
+        assert_eq!(ticks_to_reboot(&mut detach), None);
+    }
+}
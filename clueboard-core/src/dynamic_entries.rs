@@ -0,0 +1,110 @@
+//! Tap dances and combos edited at runtime, kept in the settings store
+//!
+//! Both are lists of fixed size entries that Vial reads and writes one at a time, so each entry is
+//! stored under its own key.
+
+use crate::store::{self, Flash, Store};
+
+/// Size of an entry, as Vial sends them
+pub const ENTRY_LEN: usize = 10;
+
+/// Something kept in a list of `Entries`
+pub trait Entry: Copy + Default + PartialEq {
+    /// Store key of the first entry, the rest follow on from it
+    const FIRST_STORE_KEY: u8;
+
+    fn from_bytes(bytes: &[u8; ENTRY_LEN]) -> Self;
+
+    fn to_bytes(&self) -> [u8; ENTRY_LEN];
+}
+
+/// `COUNT` entries, all empty to start with
+#[derive(Debug, Clone)]
+pub struct Entries<T, const COUNT: usize> {
+    entries: [T; COUNT],
+    changed: bool,
+}
+
+impl<T: Entry, const COUNT: usize> Entries<T, COUNT> {
+    pub fn new() -> Self {
+        Entries {
+            entries: [T::default(); COUNT],
+            changed: false,
+        }
+    }
+
+    /// Entry number `index`, if there are that many
+    pub fn get(&self, index: u8) -> Option<T> {
+        self.entries.get(usize::from(index)).copied()
+    }
+
+    /// Replace entry number `index`, returns false if there aren't that many
+    pub fn set(&mut self, index: u8, entry: T) -> bool {
+        match self.entries.get_mut(usize::from(index)) {
+            Some(slot) => {
+                if *slot != entry {
+                    *slot = entry;
+                    self.changed = true;
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn as_slice(&self) -> &[T] {
+        &self.entries
+    }
+
+    /// Whether any entry has changed since this was last called
+    pub fn take_changed(&mut self) -> bool {
+        core::mem::replace(&mut self.changed, false)
+    }
+
+    /// Read the entries from `store`
+    pub fn load<F: Flash>(store: &Store<F>) -> Self {
+        let mut entries = Entries::new();
+        for (key, entry) in (T::FIRST_STORE_KEY..).zip(entries.entries.iter_mut()) {
+            let mut buf = [0; ENTRY_LEN];
+            if store.get(key, &mut buf) == Some(ENTRY_LEN) {
+                *entry = T::from_bytes(&buf);
+            }
+        }
+        entries
+    }
+
+    /// Write the entries to `store`, ones that have never been set aren't stored
+    pub fn save<F: Flash>(&self, store: &mut Store<F>) -> Result<(), store::Error<F::Error>> {
+        for (key, entry) in (T::FIRST_STORE_KEY..).zip(self.entries.iter()) {
+            if *entry == T::default() && store.get(key, &mut []).is_none() {
+                continue;
+            }
+            store.set(key, &entry.to_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Entry, const COUNT: usize> Default for Entries<T, COUNT> {
+    fn default() -> Self {
+        Entries::new()
+    }
+}
+
+/// Read little endian keycodes out of `bytes`
+pub(crate) fn codes<const N: usize>(bytes: &[u8]) -> [u16; N] {
+    let mut codes = [0; N];
+    for (code, bytes) in codes.iter_mut().zip(bytes.chunks_exact(2)) {
+        *code = u16::from_le_bytes([bytes[0], bytes[1]]);
+    }
+    codes
+}
+
+/// Write `codes` into an entry as little endian
+pub(crate) fn to_bytes(codes: &[u16]) -> [u8; ENTRY_LEN] {
+    let mut bytes = [0; ENTRY_LEN];
+    for (bytes, code) in bytes.chunks_exact_mut(2).zip(codes) {
+        bytes.copy_from_slice(&code.to_le_bytes());
+    }
+    bytes
+}
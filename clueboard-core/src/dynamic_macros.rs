@@ -0,0 +1,369 @@
+//! Macros recorded in flash at runtime, as edited by VIA
+//!
+//! The macros are kept one after another in a fixed size buffer, each ended by a 0 byte. A macro
+//! is mostly text to type, with a few escape sequences for pressing other keys:
+//!
+//! ```text
+//! 0x01 0x01 usage    tap the key with HID usage `usage`
+//! 0x01 0x02 usage    press the key down
+//! 0x01 0x03 usage    release the key
+//! 0x01 0x04 ms '|'   wait `ms` milliseconds, written out in decimal digits
+//! ```
+
+use crate::store::{self, Flash, Store, MAX_VALUE_LEN};
+
+/// Number of macros that can be bound to keys
+pub const MACRO_COUNT: u8 = 16;
+/// Size of the buffer holding all of the macros
+pub const BUFFER_LEN: usize = 256;
+/// Store key of the first part of the buffer, the rest follow on from it
+pub const FIRST_STORE_KEY: u8 = 0x60;
+
+/// Starts an escape sequence
+const PREFIX: u8 = 0x01;
+const TAP: u8 = 0x01;
+const DOWN: u8 = 0x02;
+const UP: u8 = 0x03;
+const DELAY: u8 = 0x04;
+/// Ends the digits of a delay
+const DELAY_END: u8 = b'|';
+
+/// Most keys that can be held down at once by a macro
+const MAX_HELD: usize = 6;
+/// HID usage of left shift
+const LEFT_SHIFT: u8 = 0xE1;
+
+#[derive(Clone)]
+pub struct MacroBuffer {
+    data: [u8; BUFFER_LEN],
+    changed: bool,
+}
+
+impl MacroBuffer {
+    /// No macros
+    pub const fn new() -> Self {
+        MacroBuffer {
+            data: [0; BUFFER_LEN],
+            changed: false,
+        }
+    }
+
+    /// Copy the buffer from `offset` into `buf`, anything past the end of the buffer is left alone
+    pub fn read(&self, offset: usize, buf: &mut [u8]) {
+        let data = self.data.get(offset..).unwrap_or(&[]);
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+    }
+
+    /// Copy `data` into the buffer at `offset`, anything past the end of the buffer is dropped
+    pub fn write(&mut self, offset: usize, data: &[u8]) {
+        if let Some(buf) = self.data.get_mut(offset..) {
+            let len = data.len().min(buf.len());
+            buf[..len].copy_from_slice(&data[..len]);
+            self.changed = true;
+        }
+    }
+
+    /// Remove every macro
+    pub fn reset(&mut self) {
+        self.data = [0; BUFFER_LEN];
+        self.changed = true;
+    }
+
+    /// Whether the buffer has changed since this was last called
+    pub fn take_changed(&mut self) -> bool {
+        core::mem::replace(&mut self.changed, false)
+    }
+
+    /// The contents of macro number `index`, empty if there aren't that many
+    pub fn get(&self, index: u8) -> &[u8] {
+        self.data
+            .split(|&byte| byte == 0)
+            .nth(usize::from(index))
+            .unwrap_or(&[])
+    }
+
+    /// Read the buffer from `store`
+    pub fn load<F: Flash>(store: &Store<F>) -> Self {
+        let mut buffer = MacroBuffer::new();
+        for (key, chunk) in (FIRST_STORE_KEY..).zip(buffer.data.chunks_mut(MAX_VALUE_LEN)) {
+            store.get(key, chunk);
+        }
+        buffer
+    }
+
+    /// Write any parts of the buffer that have changed to `store`
+    pub fn save<F: Flash>(&self, store: &mut Store<F>) -> Result<(), store::Error<F::Error>> {
+        for (key, chunk) in (FIRST_STORE_KEY..).zip(self.data.chunks(MAX_VALUE_LEN)) {
+            // Empty parts that have never been written don't need storing
+            if chunk.iter().all(|&byte| byte == 0) && store.get(key, &mut []).is_none() {
+                continue;
+            }
+            store.set(key, chunk)?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for MacroBuffer {
+    fn default() -> Self {
+        MacroBuffer::new()
+    }
+}
+
+/// Types out a macro, one step per tick
+pub struct MacroPlayer {
+    /// Copy of the macro being played, so the buffer can change while it plays
+    data: [u8; BUFFER_LEN],
+    len: usize,
+    /// Offset of the next step in `data`
+    next: usize,
+    /// Key being tapped and whether it needs shift
+    tap: Option<(u8, bool)>,
+    /// Keys pressed down by the macro
+    held: [u8; MAX_HELD],
+    /// Ticks left to wait
+    delay: u32,
+}
+
+impl MacroPlayer {
+    pub const fn new() -> Self {
+        MacroPlayer {
+            data: [0; BUFFER_LEN],
+            len: 0,
+            next: 0,
+            tap: None,
+            held: [0; MAX_HELD],
+            delay: 0,
+        }
+    }
+
+    /// Start typing `data`, replacing anything being typed
+    pub fn play(&mut self, data: &[u8]) {
+        *self = MacroPlayer::new();
+        self.len = data.len().min(BUFFER_LEN);
+        self.data[..self.len].copy_from_slice(&data[..self.len]);
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.next < self.len
+            || self.tap.is_some()
+            || self.delay != 0
+            || self.held_keys().count() != 0
+    }
+
+    /// Advance by one tick
+    pub fn tick(&mut self) {
+        if self.delay != 0 {
+            self.delay -= 1;
+            return;
+        }
+        // Taps are released for a tick so repeated keys are seen as separate presses
+        if self.tap.take().is_some() {
+            return;
+        }
+
+        if self.next >= self.len {
+            // Don't leave anything held down once the macro is done
+            self.held = [0; MAX_HELD];
+            return;
+        }
+        let byte = self.take();
+        if byte != PREFIX {
+            self.tap = ascii_usage(byte);
+            return;
+        }
+        match self.take() {
+            TAP => self.tap = Some((self.take(), false)),
+            DOWN => {
+                let usage = self.take();
+                if let Some(slot) = self.held.iter_mut().find(|slot| **slot == 0) {
+                    *slot = usage;
+                }
+            }
+            UP => {
+                let usage = self.take();
+                for slot in self.held.iter_mut().filter(|slot| **slot == usage) {
+                    *slot = 0;
+                }
+            }
+            DELAY => {
+                let mut delay = 0_u32;
+                while let digit @ b'0'..=b'9' = self.take() {
+                    delay = delay
+                        .saturating_mul(10)
+                        .saturating_add(u32::from(digit - b'0'));
+                }
+                self.delay = delay;
+            }
+            _ => {}
+        }
+    }
+
+    /// HID usages of the keys the macro has down
+    pub fn keys(&self) -> impl Iterator<Item = u8> + '_ {
+        let (tap, shift) = match self.tap {
+            Some((usage, shift)) => (Some(usage), shift.then_some(LEFT_SHIFT)),
+            None => (None, None),
+        };
+        self.held_keys().chain(tap).chain(shift)
+    }
+
+    fn held_keys(&self) -> impl Iterator<Item = u8> + '_ {
+        self.held.iter().copied().filter(|&usage| usage != 0)
+    }
+
+    /// The next byte of the macro, or `DELAY_END` once it's run out so escapes are ended
+    fn take(&mut self) -> u8 {
+        match self.data[..self.len].get(self.next) {
+            Some(&byte) => {
+                self.next += 1;
+                byte
+            }
+            None => DELAY_END,
+        }
+    }
+}
+
+impl Default for MacroPlayer {
+    fn default() -> Self {
+        MacroPlayer::new()
+    }
+}
+
+/// HID usage of the key that types `c` on a US layout, and whether it needs shift
+pub fn ascii_usage(c: u8) -> Option<(u8, bool)> {
+    let usage = match c {
+        b'a'..=b'z' => (0x04 + c - b'a', false),
+        b'A'..=b'Z' => (0x04 + c - b'A', true),
+        b'1'..=b'9' => (0x1E + c - b'1', false),
+        b'0' => (0x27, false),
+        b'!' => (0x1E, true),
+        b'@' => (0x1F, true),
+        b'#' => (0x20, true),
+        b'$' => (0x21, true),
+        b'%' => (0x22, true),
+        b'^' => (0x23, true),
+        b'&' => (0x24, true),
+        b'*' => (0x25, true),
+        b'(' => (0x26, true),
+        b')' => (0x27, true),
+        b'\n' => (0x28, false),
+        0x1B => (0x29, false),
+        0x08 => (0x2A, false),
+        b'\t' => (0x2B, false),
+        b' ' => (0x2C, false),
+        b'-' => (0x2D, false),
+        b'_' => (0x2D, true),
+        b'=' => (0x2E, false),
+        b'+' => (0x2E, true),
+        b'[' => (0x2F, false),
+        b'{' => (0x2F, true),
+        b']' => (0x30, false),
+        b'}' => (0x30, true),
+        b'\\' => (0x31, false),
+        b'|' => (0x31, true),
+        b';' => (0x33, false),
+        b':' => (0x33, true),
+        b'\'' => (0x34, false),
+        b'"' => (0x34, true),
+        b'`' => (0x35, false),
+        b'~' => (0x35, true),
+        b',' => (0x36, false),
+        b'<' => (0x36, true),
+        b'.' => (0x37, false),
+        b'>' => (0x37, true),
+        b'/' => (0x38, false),
+        b'?' => (0x38, true),
+        _ => return None,
+    };
+    Some(usage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::RamFlash;
+
+    /// Play `data` to the end, collecting the keys down at each tick
+    fn play(data: &[u8]) -> [[u8; 4]; 16] {
+        let mut player = MacroPlayer::new();
+        player.play(data);
+        let mut ticks = [[0; 4]; 16];
+        for keys in ticks.iter_mut() {
+            player.tick();
+            for (key, usage) in keys.iter_mut().zip(player.keys()) {
+                *key = usage;
+            }
+        }
+        assert!(!player.is_playing());
+        ticks
+    }
+
+    #[test]
+    fn macros_are_separated_by_zeros() {
+        let mut buffer = MacroBuffer::new();
+        buffer.write(0, b"one\0two\0");
+        assert_eq!(buffer.get(0), b"one");
+        assert_eq!(buffer.get(1), b"two");
+        assert_eq!(buffer.get(2), b"");
+        assert_eq!(buffer.get(MACRO_COUNT), b"");
+    }
+
+    #[test]
+    fn writes_past_the_end_are_dropped() {
+        let mut buffer = MacroBuffer::new();
+        buffer.write(BUFFER_LEN - 2, b"abcd");
+        buffer.write(BUFFER_LEN + 10, b"abcd");
+        let mut buf = [0xFF; 4];
+        buffer.read(BUFFER_LEN - 2, &mut buf);
+        assert_eq!(buf, [b'a', b'b', 0xFF, 0xFF]);
+        assert!(buffer.take_changed());
+        assert!(!buffer.take_changed());
+    }
+
+    #[test]
+    fn buffer_survives_reopening() {
+        let mut store = Store::open(RamFlash::new());
+        let mut buffer = MacroBuffer::new();
+        buffer.write(0, b"hello\0");
+        buffer.save(&mut store).unwrap();
+
+        let store = Store::open(store.into_inner());
+        assert_eq!(MacroBuffer::load(&store).get(0), b"hello");
+    }
+
+    #[test]
+    fn text_is_typed_with_shift() {
+        let ticks = play(b"aA");
+        assert_eq!(ticks[0], [0x04, 0, 0, 0]);
+        assert_eq!(ticks[1], [0; 4]);
+        assert_eq!(ticks[2], [0x04, LEFT_SHIFT, 0, 0]);
+        assert_eq!(ticks[3], [0; 4]);
+    }
+
+    #[test]
+    fn keys_can_be_held() {
+        // Ctrl down, tap c, Ctrl up
+        let ticks = play(&[PREFIX, DOWN, 0xE0, PREFIX, TAP, 0x06, PREFIX, UP, 0xE0]);
+        assert_eq!(ticks[0], [0xE0, 0, 0, 0]);
+        assert_eq!(ticks[1], [0xE0, 0x06, 0, 0]);
+        assert_eq!(ticks[2], [0xE0, 0, 0, 0]);
+        assert_eq!(ticks[3], [0; 4]);
+    }
+
+    #[test]
+    fn delays_wait() {
+        let ticks = play(&[b'a', PREFIX, DELAY, b'1', b'0', DELAY_END, b'b']);
+        assert_eq!(ticks[0], [0x04, 0, 0, 0]);
+        assert!(ticks[1..13].iter().all(|keys| keys == &[0; 4]));
+        assert_eq!(ticks[13], [0x05, 0, 0, 0]);
+    }
+
+    #[test]
+    fn held_keys_are_released_at_the_end() {
+        let ticks = play(&[PREFIX, DOWN, 0xE1]);
+        assert_eq!(ticks[0], [0xE1, 0, 0, 0]);
+        assert_eq!(ticks[1], [0; 4]);
+    }
+}
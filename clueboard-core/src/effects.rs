@@ -0,0 +1,385 @@
+//! Backlight animations
+//!
+//! `Animator` is advanced once per matrix scan and produces a new `KeyFrame` every
+//! `FRAME_INTERVAL` ticks. All effects share that one frame rate so the cost of animating is
+//! bounded no matter which effect is running.
+
+use crate::{position, KeyFrame, COLS, ROWS};
+
+/// Ticks (milliseconds) between rendered frames
+pub const FRAME_INTERVAL: u32 = 20;
+
+const BREATHING_PERIOD: u32 = 4000;
+const WAVE_PERIOD: u32 = 2000;
+/// Delay between neighbouring columns in the wave
+const WAVE_SPACING: u32 = 100;
+/// Brightness lost per row going down the gradient
+const GRADIENT_STEP: u8 = 48;
+
+/// Number of ripples that can be spreading at once, the oldest is replaced when exceeded
+const MAX_RIPPLES: usize = 4;
+/// Fixed point scale of ripple distances, one key is `KEY` units wide
+const KEY: u32 = 256;
+/// Ticks for a ripple to spread one key further
+const RIPPLE_SPEED: u32 = 60;
+/// Width of the ripple ring
+const RIPPLE_WIDTH: u32 = KEY * 3 / 2;
+/// Ticks until a ripple has faded out completely
+const RIPPLE_LIFETIME: u32 = 900;
+
+/// Ticks for a key to fade out after being pressed in the reactive effect
+const REACTIVE_FADE: u32 = 400;
+/// Heat added to a key each time it's pressed
+const HEAT_PER_PRESS: u8 = 24;
+/// Ticks between each step of a key cooling down in the heatmap
+const HEAT_COOLING: u32 = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Effect {
+    /// Every key at full brightness
+    Solid,
+    /// The whole board slowly fades in and out
+    Breathing,
+    /// A band of light sweeps across the columns
+    Wave,
+    /// Rings spread out from each key as it's pressed
+    Ripple,
+    /// Bright top row fading towards the bottom
+    Gradient,
+    /// Keys light up when pressed and quickly fade out
+    Reactive,
+    /// Keys glow brighter the more they've been used recently
+    Heatmap,
+}
+
+impl Effect {
+    /// Every effect, `effect as u8` is its index in here
+    pub const ALL: [Effect; 7] = [
+        Effect::Solid,
+        Effect::Breathing,
+        Effect::Wave,
+        Effect::Ripple,
+        Effect::Gradient,
+        Effect::Reactive,
+        Effect::Heatmap,
+    ];
+
+    /// The effect at `index` in `ALL`
+    pub fn from_index(index: u8) -> Option<Self> {
+        Effect::ALL.get(usize::from(index)).copied()
+    }
+
+    /// The effect after this one, wrapping back to the first
+    pub fn next(self) -> Self {
+        match self {
+            Effect::Solid => Effect::Breathing,
+            Effect::Breathing => Effect::Wave,
+            Effect::Wave => Effect::Ripple,
+            Effect::Ripple => Effect::Gradient,
+            Effect::Gradient => Effect::Reactive,
+            Effect::Reactive => Effect::Heatmap,
+            Effect::Heatmap => Effect::Solid,
+        }
+    }
+}
+
+/// Effect changes that can be bound in the layout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EffectAction {
+    Select(Effect),
+    Next,
+}
+
+#[derive(Clone, Copy)]
+struct Ripple {
+    x: u8,
+    y: u8,
+    started: u32,
+}
+
+pub struct Animator {
+    effect: Effect,
+    now: u32,
+    ripples: [Option<Ripple>; MAX_RIPPLES],
+    next_ripple: usize,
+    /// Per-key brightness of the reactive and heatmap effects
+    levels: KeyFrame,
+}
+
+impl Animator {
+    pub const fn new(effect: Effect) -> Self {
+        Animator {
+            effect,
+            now: 0,
+            ripples: [None; MAX_RIPPLES],
+            next_ripple: 0,
+            levels: [[0; COLS]; ROWS],
+        }
+    }
+
+    pub fn effect(&self) -> Effect {
+        self.effect
+    }
+
+    pub fn apply(&mut self, action: EffectAction) {
+        self.effect = match action {
+            EffectAction::Select(effect) => effect,
+            EffectAction::Next => self.effect.next(),
+        };
+        self.ripples = [None; MAX_RIPPLES];
+        self.levels = [[0; COLS]; ROWS];
+    }
+
+    /// Let the animation know a switch was pressed
+    ///
+    /// This should be fed from the debounced matrix events so it reflects what was actually
+    /// typed.
+    pub fn key_pressed(&mut self, row: usize, col: usize) {
+        let level = match self.levels.get_mut(row).and_then(|keys| keys.get_mut(col)) {
+            Some(level) => level,
+            None => return,
+        };
+
+        match self.effect {
+            Effect::Ripple => {
+                let (x, y) = position(row, col);
+                self.ripples[self.next_ripple] = Some(Ripple {
+                    x,
+                    y,
+                    started: self.now,
+                });
+                self.next_ripple = (self.next_ripple + 1) % MAX_RIPPLES;
+            }
+            Effect::Reactive => *level = 255,
+            Effect::Heatmap => *level = level.saturating_add(HEAT_PER_PRESS),
+            _ => {}
+        }
+    }
+
+    /// Advance time by one tick, returns true when a new frame should be rendered
+    pub fn tick(&mut self) -> bool {
+        self.now = self.now.wrapping_add(1);
+        let now = self.now;
+        for ripple in self.ripples.iter_mut() {
+            if matches!(ripple, Some(r) if now.wrapping_sub(r.started) >= RIPPLE_LIFETIME) {
+                *ripple = None;
+            }
+        }
+
+        let frame_due = self.now.is_multiple_of(FRAME_INTERVAL);
+        let cooling = match self.effect {
+            Effect::Reactive if frame_due => (255 * FRAME_INTERVAL).div_ceil(REACTIVE_FADE),
+            Effect::Heatmap if self.now.is_multiple_of(HEAT_COOLING) => 1,
+            _ => 0,
+        };
+        if cooling != 0 {
+            for level in self.levels.iter_mut().flatten() {
+                *level = level.saturating_sub(cooling as u8);
+            }
+        }
+
+        frame_due
+    }
+
+    /// Draw the current state of the effect
+    pub fn render(&self, frame: &mut KeyFrame) {
+        for (row, keys) in frame.iter_mut().enumerate() {
+            for (col, key) in keys.iter_mut().enumerate() {
+                *key = self.key(row, col);
+            }
+        }
+    }
+
+    fn key(&self, row: usize, col: usize) -> u8 {
+        let (x, y) = position(row, col);
+        match self.effect {
+            Effect::Solid => 255,
+            Effect::Breathing => gamma(triangle(self.now, BREATHING_PERIOD)),
+            Effect::Wave => {
+                let phase = self.now.wrapping_sub(u32::from(x) * WAVE_SPACING);
+                gamma(triangle(phase, WAVE_PERIOD))
+            }
+            Effect::Ripple => self
+                .ripples
+                .iter()
+                .flatten()
+                .map(|ripple| self.ripple_brightness(ripple, x, y))
+                .max()
+                .unwrap_or(0),
+            Effect::Gradient => 255 - y.min(5) * GRADIENT_STEP,
+            Effect::Reactive | Effect::Heatmap => self.levels[row][col],
+        }
+    }
+
+    fn ripple_brightness(&self, ripple: &Ripple, x: u8, y: u8) -> u8 {
+        let age = self.now.wrapping_sub(ripple.started);
+        if age >= RIPPLE_LIFETIME {
+            return 0;
+        }
+
+        let radius = age * KEY / RIPPLE_SPEED;
+        let distance = distance(ripple.x, ripple.y, x, y);
+        let offset = distance.abs_diff(radius);
+        if offset >= RIPPLE_WIDTH {
+            return 0;
+        }
+
+        let ring = 255 * (RIPPLE_WIDTH - offset) / RIPPLE_WIDTH;
+        let fade = RIPPLE_LIFETIME - age;
+        (ring * fade / RIPPLE_LIFETIME) as u8
+    }
+}
+
+/// Rises from 0 to 255 and back over `period`
+fn triangle(time: u32, period: u32) -> u8 {
+    let phase = time % period;
+    let half = period / 2;
+    let rising = if phase < half { phase } else { period - phase };
+    (rising * 255 / half) as u8
+}
+
+/// Square the brightness so the fades look even to the eye
+fn gamma(brightness: u8) -> u8 {
+    let brightness = u16::from(brightness);
+    (brightness * brightness / 255) as u8
+}
+
+/// Approximate distance between two keys in `KEY` units
+fn distance(x1: u8, y1: u8, x2: u8, y2: u8) -> u32 {
+    let dx = u32::from(x1.abs_diff(x2)) * KEY;
+    let dy = u32::from(y1.abs_diff(y2)) * KEY;
+    // Octagonal approximation, within 12% of the euclidean distance
+    dx.max(dy) + dx.min(dy) / 2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame_at(animator: &mut Animator, ticks: u32) -> KeyFrame {
+        for _ in 0..ticks {
+            animator.tick();
+        }
+        let mut frame = [[0; COLS]; ROWS];
+        animator.render(&mut frame);
+        frame
+    }
+
+    #[test]
+    fn frames_are_rendered_at_the_frame_interval() {
+        let mut animator = Animator::new(Effect::Solid);
+        let frames = (0..FRAME_INTERVAL * 3).filter(|_| animator.tick()).count();
+        assert_eq!(frames, 3);
+    }
+
+    #[test]
+    fn solid_lights_every_key() {
+        let frame = frame_at(&mut Animator::new(Effect::Solid), 1);
+        assert!(frame.iter().flatten().all(|&key| key == 255));
+    }
+
+    #[test]
+    fn breathing_fades_in_and_out() {
+        let mut animator = Animator::new(Effect::Breathing);
+        assert_eq!(frame_at(&mut animator, 0)[0][0], 0);
+        assert_eq!(frame_at(&mut animator, BREATHING_PERIOD / 2)[0][0], 255);
+        assert_eq!(frame_at(&mut animator, BREATHING_PERIOD / 2)[0][0], 0);
+    }
+
+    #[test]
+    fn wave_moves_across_columns() {
+        let mut animator = Animator::new(Effect::Wave);
+        let frame = frame_at(&mut animator, WAVE_PERIOD / 2);
+        assert_eq!(frame[0][0], 255);
+        assert!(frame[0][1] < frame[0][0]);
+        let frame = frame_at(&mut animator, WAVE_SPACING);
+        assert_eq!(frame[0][1], 255);
+        assert!(frame[0][0] < frame[0][1]);
+    }
+
+    #[test]
+    fn ripple_spreads_from_pressed_key() {
+        let mut animator = Animator::new(Effect::Ripple);
+        assert!(frame_at(&mut animator, 1)
+            .iter()
+            .flatten()
+            .all(|&key| key == 0));
+
+        animator.key_pressed(2, 3);
+        let frame = frame_at(&mut animator, 1);
+        assert!(frame[2][3] > 200);
+        assert_eq!(frame[2][7], 0);
+
+        // Four keys away after four keys worth of spreading
+        let frame = frame_at(&mut animator, RIPPLE_SPEED * 4);
+        assert!(frame[2][7] > frame[2][3]);
+
+        let frame = frame_at(&mut animator, RIPPLE_LIFETIME);
+        assert!(frame.iter().flatten().all(|&key| key == 0));
+    }
+
+    #[test]
+    fn presses_are_ignored_by_other_effects() {
+        let mut animator = Animator::new(Effect::Solid);
+        animator.key_pressed(0, 0);
+        animator.apply(EffectAction::Select(Effect::Ripple));
+        assert!(frame_at(&mut animator, 1)
+            .iter()
+            .flatten()
+            .all(|&key| key == 0));
+    }
+
+    #[test]
+    fn gradient_dims_towards_bottom_row() {
+        let frame = frame_at(&mut Animator::new(Effect::Gradient), 1);
+        for row in 0..4 {
+            assert!(frame[row][0] > frame[row + 1][0]);
+        }
+        // Right half of the board matches the left
+        assert_eq!(frame[0][0], frame[5][0]);
+    }
+
+    #[test]
+    fn reactive_keys_fade_after_press() {
+        let mut animator = Animator::new(Effect::Reactive);
+        animator.key_pressed(1, 2);
+        let frame = frame_at(&mut animator, 1);
+        assert!(frame[1][2] > 200);
+        assert_eq!(frame[1][3], 0);
+        let frame = frame_at(&mut animator, REACTIVE_FADE);
+        assert_eq!(frame[1][2], 0);
+    }
+
+    #[test]
+    fn heatmap_brightens_with_use_and_cools() {
+        let mut animator = Animator::new(Effect::Heatmap);
+        animator.key_pressed(0, 0);
+        animator.key_pressed(0, 1);
+        animator.key_pressed(0, 1);
+        let frame = frame_at(&mut animator, 1);
+        assert_eq!(frame[0][0], HEAT_PER_PRESS);
+        assert_eq!(frame[0][1], HEAT_PER_PRESS * 2);
+
+        let frame = frame_at(&mut animator, HEAT_COOLING * u32::from(HEAT_PER_PRESS));
+        assert_eq!(frame[0][0], 0);
+        assert_eq!(frame[0][1], HEAT_PER_PRESS);
+    }
+
+    #[test]
+    fn next_cycles_through_every_effect() {
+        let mut animator = Animator::new(Effect::Solid);
+        for _ in 0..7 {
+            animator.apply(EffectAction::Next);
+        }
+        assert_eq!(animator.effect(), Effect::Solid);
+    }
+
+    #[test]
+    fn effects_are_numbered_in_order() {
+        for (i, &effect) in Effect::ALL.iter().enumerate() {
+            assert_eq!(Effect::from_index(effect as u8), Some(effect));
+            assert_eq!(effect.next(), Effect::ALL[(i + 1) % Effect::ALL.len()]);
+        }
+        assert_eq!(Effect::from_index(Effect::ALL.len() as u8), None);
+    }
+}
@@ -0,0 +1,162 @@
+//! Changes made to the compiled in keymap without reflashing
+//!
+//! Any key on any layer can be given a keycode that replaces its compiled in action. The firmware
+//! decides what the keycodes mean, here they're just numbers to remember. Overrides are kept in
+//! the settings store with one record per matrix row of each layer, so changing a key only
+//! rewrites its row.
+
+use crate::store::{self, Flash, Store};
+use crate::{COLS, ROWS};
+
+/// Store key of the first row of the first layer, the rest of the rows follow on from it
+pub const FIRST_STORE_KEY: u8 = 0x40;
+/// Stored for keys that aren't overridden
+const NOT_OVERRIDDEN: u16 = 0xFFFF;
+/// Size of a stored row
+const ROW_LEN: usize = COLS * 2;
+
+/// Keycodes replacing the compiled in actions on `LAYERS` layers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Overrides<const LAYERS: usize> {
+    codes: [[[Option<u16>; COLS]; ROWS]; LAYERS],
+}
+
+impl<const LAYERS: usize> Overrides<LAYERS> {
+    /// No keys overridden
+    pub const fn new() -> Self {
+        Overrides {
+            codes: [[[None; COLS]; ROWS]; LAYERS],
+        }
+    }
+
+    /// The keycode replacing the key at `row`, `col` on `layer`, if any
+    pub fn get(&self, layer: usize, row: usize, col: usize) -> Option<u16> {
+        self.codes[layer][row][col]
+    }
+
+    /// Replace the key at `row`, `col` on `layer` with `code`, `None` goes back to the compiled in
+    /// action
+    pub fn set(&mut self, layer: usize, row: usize, col: usize, code: Option<u16>) {
+        self.codes[layer][row][col] = code;
+    }
+
+    /// Go back to the compiled in keymap
+    pub fn clear(&mut self) {
+        *self = Overrides::new();
+    }
+
+    /// Layer, row, column and keycode of each overridden key
+    pub fn iter(&self) -> impl Iterator<Item = (usize, usize, usize, u16)> + '_ {
+        self.codes.iter().enumerate().flat_map(|(layer, rows)| {
+            rows.iter().enumerate().flat_map(move |(row, codes)| {
+                codes
+                    .iter()
+                    .enumerate()
+                    .filter_map(move |(col, code)| Some((layer, row, col, (*code)?)))
+            })
+        })
+    }
+
+    /// Read the overrides from `store`
+    pub fn load<F: Flash>(store: &Store<F>) -> Self {
+        let mut overrides = Overrides::new();
+        for (layer, rows) in overrides.codes.iter_mut().enumerate() {
+            for (row, codes) in rows.iter_mut().enumerate() {
+                let mut buf = [0; ROW_LEN];
+                if store.get(store_key(layer, row), &mut buf) != Some(ROW_LEN) {
+                    continue;
+                }
+                for (code, bytes) in codes.iter_mut().zip(buf.chunks_exact(2)) {
+                    *code = match u16::from_le_bytes([bytes[0], bytes[1]]) {
+                        NOT_OVERRIDDEN => None,
+                        value => Some(value),
+                    };
+                }
+            }
+        }
+        overrides
+    }
+
+    /// Write any rows that have changed to `store`
+    pub fn save<F: Flash>(&self, store: &mut Store<F>) -> Result<(), store::Error<F::Error>> {
+        for (layer, rows) in self.codes.iter().enumerate() {
+            for (row, codes) in rows.iter().enumerate() {
+                let key = store_key(layer, row);
+                if codes.iter().all(Option::is_none) {
+                    // Rows that have never been overridden aren't stored at all
+                    if store.get(key, &mut []).is_some() {
+                        store.set(key, &[])?;
+                    }
+                    continue;
+                }
+
+                let mut buf = [0; ROW_LEN];
+                for (bytes, code) in buf.chunks_exact_mut(2).zip(codes) {
+                    bytes.copy_from_slice(&code.unwrap_or(NOT_OVERRIDDEN).to_le_bytes());
+                }
+                store.set(key, &buf)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<const LAYERS: usize> Default for Overrides<LAYERS> {
+    fn default() -> Self {
+        Overrides::new()
+    }
+}
+
+fn store_key(layer: usize, row: usize) -> u8 {
+    FIRST_STORE_KEY + (layer * ROWS + row) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::RamFlash;
+
+    #[test]
+    fn nothing_overridden_in_empty_store() {
+        let store = Store::open(RamFlash::new());
+        assert_eq!(Overrides::<3>::load(&store), Overrides::new());
+    }
+
+    #[test]
+    fn overrides_survive_reopening() {
+        let mut store = Store::open(RamFlash::new());
+        let mut overrides = Overrides::<3>::new();
+        overrides.set(0, 2, 0, Some(0x39));
+        overrides.set(2, 9, 7, Some(0));
+        overrides.save(&mut store).unwrap();
+
+        let store = Store::open(store.into_inner());
+        let loaded = Overrides::<3>::load(&store);
+        assert_eq!(loaded, overrides);
+        assert_eq!(loaded.get(0, 2, 0), Some(0x39));
+        assert_eq!(loaded.get(0, 2, 1), None);
+        assert!(loaded.iter().eq([(0, 2, 0, 0x39), (2, 9, 7, 0)]));
+    }
+
+    #[test]
+    fn cleared_rows_are_forgotten() {
+        let mut store = Store::open(RamFlash::new());
+        let mut overrides = Overrides::<3>::new();
+        overrides.set(1, 4, 5, Some(0x2C));
+        overrides.save(&mut store).unwrap();
+        overrides.clear();
+        overrides.save(&mut store).unwrap();
+        assert_eq!(Overrides::<3>::load(&store), Overrides::new());
+    }
+
+    #[test]
+    fn only_overridden_rows_are_stored() {
+        let mut store = Store::open(RamFlash::new());
+        let mut overrides = Overrides::<3>::new();
+        overrides.set(0, 0, 0, Some(0x29));
+        overrides.save(&mut store).unwrap();
+        assert_eq!(store.get(store_key(0, 0), &mut []), Some(ROW_LEN));
+        assert_eq!(store.get(store_key(0, 1), &mut []), None);
+        assert_eq!(store.get(store_key(2, 9), &mut []), None);
+    }
+}
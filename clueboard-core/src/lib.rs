@@ -0,0 +1,45 @@
+//! Hardware independent parts of the Clueboard firmware
+//!
+//! Everything in here builds for the host as well as the keyboard so it can be unit tested with:
+//!
+//! ```text
+//! cargo test -p clueboard-core --target x86_64-unknown-linux-gnu
+//! ```
+
+#![no_std]
+
+pub mod audio;
+pub mod bootmagic;
+pub mod combos;
+pub mod dfu;
+pub mod dynamic_entries;
+pub mod dynamic_macros;
+pub mod effects;
+pub mod keymap;
+pub mod music;
+pub mod store;
+pub mod tap_dance;
+pub mod via;
+pub mod vial;
+
+/// USB vendor ID, the same one the Clueboard QMK firmware uses
+pub const VID: u16 = 0xC1ED;
+/// USB product ID, the same one the Clueboard QMK firmware uses
+pub const PID: u16 = 0x2391;
+
+/// Number of rows in the key matrix
+pub const ROWS: usize = 10;
+/// Number of columns in the key matrix
+pub const COLS: usize = 8;
+
+/// Brightness of each key, indexed by matrix row then column
+pub type KeyFrame = [[u8; COLS]; ROWS];
+
+/// Physical position of the switch at `row`, `col` in the matrix, in keys from the top left
+///
+/// The right half of each keyboard row is wired to matrix rows 5 to 9, so those are shifted
+/// across and up to line up with the left half.
+pub fn position(row: usize, col: usize) -> (u8, u8) {
+    let half = row / 5;
+    ((col + half * COLS) as u8, (row % 5) as u8)
+}
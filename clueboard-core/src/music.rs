@@ -0,0 +1,171 @@
+//! Music mode
+//!
+//! While music mode is active the keyboard is played like an instrument instead of typing. Each
+//! keyboard row is an octave, lowest at the bottom, with the notes rising chromatically from left
+//! to right. A few keys are kept back to transpose and to leave music mode.
+
+use crate::position;
+
+/// Matrix position of Esc, which leaves music mode
+const EXIT: (usize, usize) = (0, 0);
+/// Matrix positions of the arrow keys, which transpose by a semitone or an octave
+const LEFT: (usize, usize) = (9, 5);
+const RIGHT: (usize, usize) = (9, 7);
+const UP: (usize, usize) = (8, 6);
+const DOWN: (usize, usize) = (9, 6);
+
+/// Note number of the leftmost key on the bottom row, C3. Note 0 is C0.
+const BOTTOM_NOTE: i16 = 36;
+/// Furthest the keyboard can be transposed in either direction
+const MAX_TRANSPOSE: i16 = 24;
+
+/// Frequency of each note in octave 0 in hundredths of a Hz
+const OCTAVE_0: [u32; 12] = [
+    1635, 1732, 1835, 1945, 2060, 2183, 2312, 2450, 2596, 2750, 2914, 3087,
+];
+
+/// What the speakers should do after a key is pressed or released
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MusicEvent {
+    /// Start playing this frequency
+    Play(u16),
+    /// Stop the note that's playing
+    Stop,
+    /// Carry on as before
+    Nothing,
+}
+
+pub struct MusicMode {
+    active: bool,
+    /// Semitones added to every note
+    transpose: i16,
+    /// Matrix position of the key whose note is playing
+    sounding: Option<(usize, usize)>,
+}
+
+impl MusicMode {
+    pub const fn new() -> Self {
+        MusicMode {
+            active: false,
+            transpose: 0,
+            sounding: None,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    pub fn enter(&mut self) {
+        self.active = true;
+        self.sounding = None;
+    }
+
+    pub fn press(&mut self, row: usize, col: usize) -> MusicEvent {
+        let key = (row, col);
+        let transpose = match key {
+            EXIT => {
+                self.active = false;
+                self.sounding = None;
+                return MusicEvent::Stop;
+            }
+            LEFT => -1,
+            RIGHT => 1,
+            DOWN => -12,
+            UP => 12,
+            _ => {
+                self.sounding = Some(key);
+                return MusicEvent::Play(frequency(self.note(row, col)));
+            }
+        };
+        self.transpose = (self.transpose + transpose).clamp(-MAX_TRANSPOSE, MAX_TRANSPOSE);
+        MusicEvent::Nothing
+    }
+
+    pub fn release(&mut self, row: usize, col: usize) -> MusicEvent {
+        if self.sounding == Some((row, col)) {
+            self.sounding = None;
+            MusicEvent::Stop
+        } else {
+            MusicEvent::Nothing
+        }
+    }
+
+    /// Note number of the key at `row`, `col` in the matrix, 0 is C0
+    pub fn note(&self, row: usize, col: usize) -> u8 {
+        let (x, y) = position(row, col);
+        let octave = 4 - i16::from(y.min(4));
+        let note = BOTTOM_NOTE + octave * 12 + i16::from(x) + self.transpose;
+        note.clamp(0, 127) as u8
+    }
+}
+
+impl Default for MusicMode {
+    fn default() -> Self {
+        MusicMode::new()
+    }
+}
+
+/// Frequency of note number `note` in Hz, 0 is C0
+pub fn frequency(note: u8) -> u16 {
+    let hundredths = OCTAVE_0[usize::from(note % 12)] << (note / 12).min(10);
+    ((hundredths + 50) / 100).min(u32::from(u16::MAX)) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a4_is_440_hz() {
+        assert_eq!(frequency(57), 440);
+        assert_eq!(frequency(69), 880);
+        assert_eq!(frequency(48), 262);
+    }
+
+    #[test]
+    fn rows_are_octaves() {
+        let music = MusicMode::new();
+        // Left Ctrl, Left Shift, Caps, Tab, and Grave down the left edge
+        assert_eq!(music.note(4, 0), 36);
+        assert_eq!(music.note(3, 0), 48);
+        assert_eq!(music.note(2, 0), 60);
+        assert_eq!(music.note(1, 0), 72);
+        assert_eq!(music.note(0, 0), 84);
+        // Notes rise along the row, continuing into the right half of the matrix
+        assert_eq!(music.note(1, 1), 73);
+        assert_eq!(music.note(6, 0), 80);
+    }
+
+    #[test]
+    fn plays_while_held() {
+        let mut music = MusicMode::new();
+        music.enter();
+        assert_eq!(music.press(2, 0), MusicEvent::Play(frequency(60)));
+        assert_eq!(music.release(2, 1), MusicEvent::Nothing);
+        assert_eq!(music.release(2, 0), MusicEvent::Stop);
+    }
+
+    #[test]
+    fn arrows_transpose() {
+        let mut music = MusicMode::new();
+        music.enter();
+        assert_eq!(music.press(RIGHT.0, RIGHT.1), MusicEvent::Nothing);
+        assert_eq!(music.note(2, 0), 61);
+        music.press(DOWN.0, DOWN.1);
+        assert_eq!(music.note(2, 0), 49);
+        for _ in 0..10 {
+            music.press(UP.0, UP.1);
+        }
+        assert_eq!(music.note(2, 0), 60 + MAX_TRANSPOSE as u8);
+    }
+
+    #[test]
+    fn escape_leaves_music_mode() {
+        let mut music = MusicMode::new();
+        music.enter();
+        music.press(2, 0);
+        assert_eq!(music.press(EXIT.0, EXIT.1), MusicEvent::Stop);
+        assert!(!music.is_active());
+    }
+}
@@ -0,0 +1,533 @@
+//! Key/value store for settings kept in flash
+//!
+//! Flash can only be erased a page at a time and wears out after enough erases, so values are
+//! never overwritten in place. Each change is appended to the active page as a new record and
+//! the last record for a key wins. When the active page fills up, the latest value of every key is
+//! copied to the other page, which then becomes active. A page is only erased when it's about to be
+//! reused, so each change costs a few bytes rather than an erase.
+//!
+//! Each page starts with a header:
+//!
+//! ```text
+//! magic: u32, version: u16, sequence: u16
+//! ```
+//!
+//! The page with a valid header and the newest sequence number is the active one. The header is
+//! written last when switching pages so the old page stays active if power is lost part way.
+//!
+//! Records follow the header, padded to a multiple of two bytes:
+//!
+//! ```text
+//! key: u8, len: u8, value: [u8; len], crc: u16
+//! ```
+//!
+//! The CRC covers the key, length and value. Reading stops at the first erased or corrupt record,
+//! and a corrupt record forces the next write to start a fresh page.
+
+/// Marks the start of a page belonging to the store, "CLUE"
+const MAGIC: u32 = 0x4555_4C43;
+/// Layout of the pages and records, pages from other versions are ignored
+pub const VERSION: u16 = 1;
+/// Size of the page header
+const HEADER_LEN: usize = 8;
+/// Value of erased flash
+const ERASED: u8 = 0xFF;
+/// Longest value that can be stored
+pub const MAX_VALUE_LEN: usize = 64;
+/// Longest record, a value of `MAX_VALUE_LEN` with its key, length and CRC
+const MAX_RECORD_LEN: usize = record_len(MAX_VALUE_LEN);
+
+/// Two pages of flash set aside for the store
+///
+/// Erased flash reads as `0xFF` and writes can only be made to erased flash. Offsets and lengths
+/// passed to `write` are always multiples of two.
+pub trait Flash {
+    type Error;
+
+    /// Size of each page in bytes
+    const PAGE_SIZE: usize;
+
+    /// Fill `buf` from `page` (0 or 1) starting at `offset`
+    fn read(&self, page: usize, offset: usize, buf: &mut [u8]);
+
+    /// Set all of `page` back to `0xFF`
+    fn erase(&mut self, page: usize) -> Result<(), Self::Error>;
+
+    /// Write `data` to erased flash in `page` starting at `offset`
+    fn write(&mut self, page: usize, offset: usize, data: &[u8]) -> Result<(), Self::Error>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error<E> {
+    /// The flash couldn't be erased or written
+    Flash(E),
+    /// The value is longer than `MAX_VALUE_LEN` or the key is `0xFF`
+    Invalid,
+    /// The latest values of every key don't fit in one page
+    Full,
+}
+
+pub struct Store<F> {
+    flash: F,
+    /// The active page
+    page: usize,
+    /// Sequence number of the active page
+    sequence: u16,
+    /// Whether the active page has a valid header
+    formatted: bool,
+    /// Offset just past the last valid record
+    end: usize,
+    /// Whether there's something other than erased flash after `end`
+    dirty: bool,
+}
+
+impl<F: Flash> Store<F> {
+    /// Find the active page and the end of its records
+    ///
+    /// Nothing is written until the first `set`.
+    pub fn open(flash: F) -> Self {
+        let mut store = Store {
+            flash,
+            page: 0,
+            sequence: 0,
+            formatted: false,
+            end: HEADER_LEN,
+            dirty: false,
+        };
+
+        let headers = [store.header(0), store.header(1)];
+        let newest = match headers {
+            [Some(a), Some(b)] if is_newer(b, a) => Some((1, b)),
+            [Some(a), _] => Some((0, a)),
+            [None, Some(b)] => Some((1, b)),
+            [None, None] => None,
+        };
+        if let Some((page, sequence)) = newest {
+            store.page = page;
+            store.sequence = sequence;
+            store.formatted = true;
+            store.scan();
+        }
+        store
+    }
+
+    /// Copy the value of `key` into the start of `buf` and return its length
+    ///
+    /// Values longer than `buf` are truncated.
+    pub fn get(&self, key: u8, buf: &mut [u8]) -> Option<usize> {
+        let (offset, len) = self.find(key)?;
+        let copied = len.min(buf.len());
+        self.flash.read(self.page, offset + 2, &mut buf[..copied]);
+        Some(len)
+    }
+
+    /// Store `value` for `key`, replacing any previous value
+    ///
+    /// Nothing is written if the value is unchanged.
+    pub fn set(&mut self, key: u8, value: &[u8]) -> Result<(), Error<F::Error>> {
+        if key == ERASED || value.len() > MAX_VALUE_LEN {
+            return Err(Error::Invalid);
+        }
+
+        let mut current = [0; MAX_VALUE_LEN];
+        if let Some(len) = self.get(key, &mut current) {
+            if &current[..len] == value {
+                return Ok(());
+            }
+        }
+
+        if !self.formatted || self.dirty || self.end + record_len(value.len()) > F::PAGE_SIZE {
+            return self.compact(key, value);
+        }
+
+        let mut record = [ERASED; MAX_RECORD_LEN];
+        let len = encode(key, value, &mut record);
+        if let Err(err) = self.flash.write(self.page, self.end, &record[..len]) {
+            // Some of the record may have been written
+            self.dirty = true;
+            return Err(Error::Flash(err));
+        }
+        self.end += len;
+        Ok(())
+    }
+
+    /// Erase everything
+    pub fn clear(&mut self) -> Result<(), Error<F::Error>> {
+        self.formatted = false;
+        self.end = HEADER_LEN;
+        self.dirty = false;
+        for page in 0..2 {
+            self.flash.erase(page).map_err(Error::Flash)?;
+        }
+        Ok(())
+    }
+
+    /// Give back the flash
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+
+    /// Write the latest value of every key, with `value` for `key`, to the other page and make it
+    /// the active page
+    fn compact(&mut self, key: u8, value: &[u8]) -> Result<(), Error<F::Error>> {
+        let page = 1 - self.page;
+        let sequence = if self.formatted {
+            self.sequence.wrapping_add(1)
+        } else {
+            0
+        };
+        self.flash.erase(page).map_err(Error::Flash)?;
+
+        let mut end = HEADER_LEN;
+        let mut record = [ERASED; MAX_RECORD_LEN];
+        if self.formatted {
+            let mut offset = HEADER_LEN;
+            while offset < self.end {
+                let (record_key, len) = self.record_at(offset);
+                let size = record_len(len);
+                if record_key != key && self.find(record_key) == Some((offset, len)) {
+                    if end + size > F::PAGE_SIZE {
+                        return Err(Error::Full);
+                    }
+                    self.flash.read(self.page, offset, &mut record[..size]);
+                    self.flash
+                        .write(page, end, &record[..size])
+                        .map_err(Error::Flash)?;
+                    end += size;
+                }
+                offset += size;
+            }
+        }
+
+        let size = encode(key, value, &mut record);
+        if end + size > F::PAGE_SIZE {
+            return Err(Error::Full);
+        }
+        self.flash
+            .write(page, end, &record[..size])
+            .map_err(Error::Flash)?;
+        end += size;
+
+        let mut header = [0; HEADER_LEN];
+        header[..4].copy_from_slice(&MAGIC.to_le_bytes());
+        header[4..6].copy_from_slice(&VERSION.to_le_bytes());
+        header[6..].copy_from_slice(&sequence.to_le_bytes());
+        self.flash.write(page, 0, &header).map_err(Error::Flash)?;
+
+        self.page = page;
+        self.sequence = sequence;
+        self.formatted = true;
+        self.end = end;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// The sequence number of `page` if it has a valid header
+    fn header(&self, page: usize) -> Option<u16> {
+        let mut header = [0; HEADER_LEN];
+        self.flash.read(page, 0, &mut header);
+        let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let version = u16::from_le_bytes([header[4], header[5]]);
+        if magic == MAGIC && version == VERSION {
+            Some(u16::from_le_bytes([header[6], header[7]]))
+        } else {
+            None
+        }
+    }
+
+    /// Find the end of the records in the active page, checking each one
+    fn scan(&mut self) {
+        let mut offset = HEADER_LEN;
+        let mut record = [0; MAX_RECORD_LEN];
+        while offset + 2 <= F::PAGE_SIZE {
+            let (key, len) = self.record_at(offset);
+            if key == ERASED && len == usize::from(ERASED) {
+                break;
+            }
+            let size = record_len(len);
+            let valid = len <= MAX_VALUE_LEN && offset + size <= F::PAGE_SIZE && {
+                self.flash.read(self.page, offset, &mut record[..size]);
+                let crc = u16::from_le_bytes([record[size - 2], record[size - 1]]);
+                crc == crc16(&record[..2 + len])
+            };
+            if !valid {
+                self.dirty = true;
+                break;
+            }
+            offset += size;
+        }
+        self.end = offset;
+    }
+
+    /// Offset and length of the latest value of `key`
+    fn find(&self, key: u8) -> Option<(usize, usize)> {
+        if !self.formatted {
+            return None;
+        }
+        let mut found = None;
+        let mut offset = HEADER_LEN;
+        while offset < self.end {
+            let (record_key, len) = self.record_at(offset);
+            if record_key == key {
+                found = Some((offset, len));
+            }
+            offset += record_len(len);
+        }
+        found
+    }
+
+    /// Key and value length of the record at `offset`
+    fn record_at(&self, offset: usize) -> (u8, usize) {
+        let mut header = [0; 2];
+        self.flash.read(self.page, offset, &mut header);
+        (header[0], usize::from(header[1]))
+    }
+}
+
+/// Bytes taken up by a record holding a value of `len`
+const fn record_len(len: usize) -> usize {
+    2 + len.div_ceil(2) * 2 + 2
+}
+
+/// Write the record for `key` and `value` to the start of `buf`, returning its length
+fn encode(key: u8, value: &[u8], buf: &mut [u8; MAX_RECORD_LEN]) -> usize {
+    let size = record_len(value.len());
+    buf[0] = key;
+    buf[1] = value.len() as u8;
+    buf[2..2 + value.len()].copy_from_slice(value);
+    buf[2 + value.len()..size - 2].fill(ERASED);
+    let crc = crc16(&buf[..2 + value.len()]);
+    buf[size - 2..size].copy_from_slice(&crc.to_le_bytes());
+    size
+}
+
+/// Whether sequence number `a` comes after `b`, allowing for wrapping
+fn is_newer(a: u16, b: u16) -> bool {
+    (a.wrapping_sub(b) as i16) > 0
+}
+
+/// CRC-16/CCITT-FALSE
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFF_u16;
+    for &byte in data {
+        crc ^= u16::from(byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    const PAGE_SIZE: usize = 128;
+
+    /// Flash in RAM that enforces the same rules as the real thing
+    pub(crate) struct RamFlash {
+        pages: [[u8; PAGE_SIZE]; 2],
+        erases: [usize; 2],
+        /// Number of half-words that can be written before writes start failing
+        writes_left: Option<usize>,
+    }
+
+    impl RamFlash {
+        pub(crate) fn new() -> Self {
+            RamFlash {
+                pages: [[ERASED; PAGE_SIZE]; 2],
+                erases: [0; 2],
+                writes_left: None,
+            }
+        }
+    }
+
+    impl Flash for RamFlash {
+        type Error = ();
+
+        const PAGE_SIZE: usize = PAGE_SIZE;
+
+        fn read(&self, page: usize, offset: usize, buf: &mut [u8]) {
+            buf.copy_from_slice(&self.pages[page][offset..offset + buf.len()]);
+        }
+
+        fn erase(&mut self, page: usize) -> Result<(), ()> {
+            self.pages[page] = [ERASED; PAGE_SIZE];
+            self.erases[page] += 1;
+            Ok(())
+        }
+
+        fn write(&mut self, page: usize, offset: usize, data: &[u8]) -> Result<(), ()> {
+            assert!(offset.is_multiple_of(2) && data.len().is_multiple_of(2));
+            for (i, half_word) in data.chunks(2).enumerate() {
+                match &mut self.writes_left {
+                    Some(0) => return Err(()),
+                    Some(left) => *left -= 1,
+                    None => {}
+                }
+                let at = offset + i * 2;
+                assert_eq!(
+                    self.pages[page][at..at + 2],
+                    [ERASED; 2],
+                    "write to flash that isn't erased"
+                );
+                self.pages[page][at..at + 2].copy_from_slice(half_word);
+            }
+            Ok(())
+        }
+    }
+
+    fn get(store: &Store<RamFlash>, key: u8) -> Option<([u8; MAX_VALUE_LEN], usize)> {
+        let mut buf = [0; MAX_VALUE_LEN];
+        store.get(key, &mut buf).map(|len| (buf, len))
+    }
+
+    fn value(store: &Store<RamFlash>, key: u8) -> Option<u8> {
+        get(store, key).map(|(buf, _)| buf[0])
+    }
+
+    #[test]
+    fn empty_flash_has_no_values() {
+        let store = Store::open(RamFlash::new());
+        assert_eq!(value(&store, 0), None);
+        assert_eq!(store.into_inner().erases, [0, 0]);
+    }
+
+    #[test]
+    fn values_survive_reopening() {
+        let mut store = Store::open(RamFlash::new());
+        store.set(1, &[10]).unwrap();
+        store.set(2, &[20, 21, 22]).unwrap();
+        store.set(1, &[11]).unwrap();
+
+        let store = Store::open(store.into_inner());
+        assert_eq!(value(&store, 1), Some(11));
+        let (buf, len) = get(&store, 2).unwrap();
+        assert_eq!(&buf[..len], &[20, 21, 22]);
+        assert_eq!(value(&store, 3), None);
+    }
+
+    #[test]
+    fn unchanged_values_are_not_written() {
+        let mut store = Store::open(RamFlash::new());
+        store.set(1, &[10]).unwrap();
+        let end = store.end;
+        store.set(1, &[10]).unwrap();
+        assert_eq!(store.end, end);
+    }
+
+    #[test]
+    fn full_pages_are_compacted() {
+        let mut store = Store::open(RamFlash::new());
+        store.set(1, &[1]).unwrap();
+        for i in 0..100 {
+            store.set(2, &[i]).unwrap();
+        }
+        assert_eq!(value(&store, 1), Some(1));
+        assert_eq!(value(&store, 2), Some(99));
+
+        // Wear is spread over both pages
+        let store = Store::open(store.into_inner());
+        assert_eq!(value(&store, 2), Some(99));
+        let erases = store.into_inner().erases;
+        assert!(erases[0] > 1 && erases[1] > 1, "{:?}", erases);
+        assert!(erases[0].abs_diff(erases[1]) <= 1);
+    }
+
+    #[test]
+    fn too_much_data_is_rejected() {
+        let mut store = Store::open(RamFlash::new());
+        assert_eq!(store.set(0xFF, &[0]), Err(Error::Invalid));
+        assert_eq!(store.set(0, &[0; MAX_VALUE_LEN + 1]), Err(Error::Invalid));
+
+        store.set(0, &[0; MAX_VALUE_LEN]).unwrap();
+        assert_eq!(store.set(1, &[0; MAX_VALUE_LEN]), Err(Error::Full));
+        // The previous page is still active
+        let store = Store::open(store.into_inner());
+        assert_eq!(value(&store, 0), Some(0));
+        assert_eq!(value(&store, 1), None);
+    }
+
+    #[test]
+    fn torn_writes_are_ignored() {
+        let mut store = Store::open(RamFlash::new());
+        store.set(1, &[10]).unwrap();
+        // Only the first half of the record makes it to flash
+        store.flash.writes_left = Some(1);
+        assert_eq!(store.set(1, &[11, 12, 13]), Err(Error::Flash(())));
+
+        let mut flash = store.into_inner();
+        flash.writes_left = None;
+        let mut store = Store::open(flash);
+        assert_eq!(value(&store, 1), Some(10));
+
+        // The next write moves to a clean page
+        store.set(2, &[20]).unwrap();
+        let store = Store::open(store.into_inner());
+        assert_eq!(value(&store, 1), Some(10));
+        assert_eq!(value(&store, 2), Some(20));
+    }
+
+    #[test]
+    fn interrupted_compaction_keeps_the_old_page() {
+        let mut store = Store::open(RamFlash::new());
+        store.set(1, &[0; MAX_VALUE_LEN]).unwrap();
+        // There's no room for this in the active page, and the copy to the other one fails
+        store.flash.writes_left = Some(4);
+        assert!(store.set(1, &[1; MAX_VALUE_LEN]).is_err());
+
+        let mut flash = store.into_inner();
+        flash.writes_left = None;
+        let store = Store::open(flash);
+        assert_eq!(value(&store, 1), Some(0));
+    }
+
+    #[test]
+    fn corrupt_records_end_the_page() {
+        let mut store = Store::open(RamFlash::new());
+        store.set(1, &[10]).unwrap();
+        store.set(1, &[11]).unwrap();
+        let page = store.page;
+        let mut flash = store.into_inner();
+        // Flip a bit in the second record's value
+        flash.pages[page][HEADER_LEN + record_len(1) + 2] ^= 1;
+
+        let store = Store::open(flash);
+        assert_eq!(value(&store, 1), Some(10));
+    }
+
+    #[test]
+    fn other_versions_are_ignored() {
+        let mut store = Store::open(RamFlash::new());
+        store.set(1, &[10]).unwrap();
+        let page = store.page;
+        let mut flash = store.into_inner();
+        flash.pages[page][4] = VERSION as u8 + 1;
+        let store = Store::open(flash);
+        assert_eq!(value(&store, 1), None);
+    }
+
+    #[test]
+    fn clear_forgets_everything() {
+        let mut store = Store::open(RamFlash::new());
+        store.set(1, &[10]).unwrap();
+        store.clear().unwrap();
+        assert_eq!(value(&store, 1), None);
+        let mut store = Store::open(store.into_inner());
+        assert_eq!(value(&store, 1), None);
+        store.set(2, &[20]).unwrap();
+        assert_eq!(value(&Store::open(store.into_inner()), 2), Some(20));
+    }
+
+    #[test]
+    fn newest_page_wins_across_wrapping() {
+        assert!(is_newer(1, 0));
+        assert!(is_newer(0, u16::MAX));
+        assert!(!is_newer(u16::MAX, 0));
+        assert!(!is_newer(3, 3));
+    }
+}
@@ -0,0 +1,258 @@
+//! Keys that do different things when tapped, held, tapped twice, or tapped then held
+//!
+//! The actions are QMK keycodes, as set with Vial. Actions left empty fall back to `on_tap`. A
+//! dance is decided once the tapping term passes without the key changing, or straight away when
+//! another key is pressed.
+
+use crate::dynamic_entries::{self, Entries, Entry, ENTRY_LEN};
+
+/// Number of tap dances that can be bound to keys
+pub const TAP_DANCE_COUNT: usize = 8;
+/// Tapping term used when an entry doesn't have one, in ticks
+const DEFAULT_TAPPING_TERM: u16 = 200;
+
+pub type TapDances = Entries<TapDance, TAP_DANCE_COUNT>;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TapDance {
+    pub on_tap: u16,
+    pub on_hold: u16,
+    pub on_double_tap: u16,
+    pub on_tap_hold: u16,
+    /// How long to wait for the next tap, or until a press counts as a hold, in ticks
+    pub tapping_term: u16,
+}
+
+impl TapDance {
+    fn tapping_term(&self) -> u16 {
+        match self.tapping_term {
+            0 => DEFAULT_TAPPING_TERM,
+            term => term,
+        }
+    }
+
+    /// `code`, or `on_tap` if it's empty
+    fn or_tap(&self, code: u16) -> u16 {
+        match code {
+            0 => self.on_tap,
+            code => code,
+        }
+    }
+}
+
+impl Entry for TapDance {
+    const FIRST_STORE_KEY: u8 = 0x70;
+
+    fn from_bytes(bytes: &[u8; ENTRY_LEN]) -> Self {
+        let [on_tap, on_hold, on_double_tap, on_tap_hold, tapping_term] =
+            dynamic_entries::codes(bytes);
+        TapDance {
+            on_tap,
+            on_hold,
+            on_double_tap,
+            on_tap_hold,
+            tapping_term,
+        }
+    }
+
+    fn to_bytes(&self) -> [u8; ENTRY_LEN] {
+        dynamic_entries::to_bytes(&[
+            self.on_tap,
+            self.on_hold,
+            self.on_double_tap,
+            self.on_tap_hold,
+            self.tapping_term,
+        ])
+    }
+}
+
+/// Works out what tap dance keys do from their presses and releases
+#[derive(Debug, Default)]
+pub struct TapDancer {
+    /// Index and entry of the dance being decided
+    active: Option<(u8, TapDance)>,
+    /// Number of presses so far
+    taps: u8,
+    pressed: bool,
+    /// Ticks since the key last changed
+    elapsed: u16,
+    /// Index of the dance decided as a hold and its keycode, held down until it's released
+    held: Option<(u8, u16)>,
+    /// Keycode tapped for one tick
+    tap: Option<u16>,
+}
+
+impl TapDancer {
+    pub fn new() -> Self {
+        TapDancer::default()
+    }
+
+    /// The key for dance `index` was pressed
+    pub fn press(&mut self, index: u8, entry: TapDance) {
+        if !matches!(self.active, Some((active, _)) if active == index) {
+            // A different dance is an interruption like any other key
+            self.interrupt();
+            self.active = Some((index, entry));
+            self.taps = 0;
+        }
+        self.taps = self.taps.saturating_add(1);
+        self.pressed = true;
+        self.elapsed = 0;
+    }
+
+    /// The key for dance `index` was released
+    pub fn release(&mut self, index: u8) {
+        if matches!(self.held, Some((held, _)) if held == index) {
+            self.held = None;
+        }
+        if matches!(self.active, Some((active, _)) if active == index) {
+            self.pressed = false;
+            self.elapsed = 0;
+            if self.taps >= 2 {
+                // Dances only go up to two taps
+                self.decide();
+            }
+        }
+    }
+
+    /// Another key was pressed, decide the dance now so it's typed first
+    pub fn interrupt(&mut self) {
+        self.decide();
+    }
+
+    /// Advance by one tick, call this before passing on the tick's presses and releases
+    pub fn tick(&mut self) {
+        self.tap = None;
+        if let Some((_, entry)) = self.active {
+            self.elapsed = self.elapsed.saturating_add(1);
+            if self.elapsed >= entry.tapping_term() {
+                self.decide();
+            }
+        }
+    }
+
+    /// QMK keycodes of the keys the dances have down
+    pub fn keycodes(&self) -> impl Iterator<Item = u16> {
+        self.held.map(|(_, code)| code).into_iter().chain(self.tap)
+    }
+
+    /// Settle the dance being decided, if there is one
+    fn decide(&mut self) {
+        let (index, entry) = match self.active.take() {
+            Some(active) => active,
+            None => return,
+        };
+        match (self.taps, self.pressed) {
+            (1, true) => self.held = Some((index, entry.or_tap(entry.on_hold))),
+            (1, false) => self.tap = Some(entry.on_tap),
+            (_, true) => self.held = Some((index, entry.or_tap(entry.on_tap_hold))),
+            (_, false) => self.tap = Some(entry.or_tap(entry.on_double_tap)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::RamFlash;
+    use crate::store::Store;
+
+    const DANCE: TapDance = TapDance {
+        on_tap: 0x04,
+        on_hold: 0x05,
+        on_double_tap: 0x06,
+        on_tap_hold: 0x07,
+        tapping_term: 10,
+    };
+
+    /// Tick `ticks` times, returning the first keycode seen
+    fn wait(dancer: &mut TapDancer, ticks: u16) -> Option<u16> {
+        let mut seen = None;
+        for _ in 0..ticks {
+            dancer.tick();
+            seen = seen.or_else(|| dancer.keycodes().next());
+        }
+        seen
+    }
+
+    #[test]
+    fn tap() {
+        let mut dancer = TapDancer::new();
+        dancer.press(0, DANCE);
+        dancer.release(0);
+        assert_eq!(wait(&mut dancer, 9), None);
+        assert_eq!(wait(&mut dancer, 1), Some(0x04));
+        assert_eq!(wait(&mut dancer, 1), None);
+    }
+
+    #[test]
+    fn hold_lasts_until_release() {
+        let mut dancer = TapDancer::new();
+        dancer.press(0, DANCE);
+        assert_eq!(wait(&mut dancer, 10), Some(0x05));
+        assert_eq!(wait(&mut dancer, 100), Some(0x05));
+        dancer.release(0);
+        assert_eq!(wait(&mut dancer, 1), None);
+    }
+
+    #[test]
+    fn double_tap_is_decided_on_release() {
+        let mut dancer = TapDancer::new();
+        dancer.press(0, DANCE);
+        dancer.release(0);
+        wait(&mut dancer, 5);
+        dancer.press(0, DANCE);
+        dancer.release(0);
+        assert!(dancer.keycodes().eq([0x06]));
+    }
+
+    #[test]
+    fn tap_then_hold() {
+        let mut dancer = TapDancer::new();
+        dancer.press(0, DANCE);
+        dancer.release(0);
+        dancer.press(0, DANCE);
+        assert_eq!(wait(&mut dancer, 10), Some(0x07));
+    }
+
+    #[test]
+    fn empty_actions_fall_back_to_tap() {
+        let dance = TapDance {
+            on_tap: 0x04,
+            ..TapDance::default()
+        };
+        let mut dancer = TapDancer::new();
+        dancer.press(0, dance);
+        assert_eq!(wait(&mut dancer, DEFAULT_TAPPING_TERM), Some(0x04));
+    }
+
+    #[test]
+    fn other_keys_interrupt() {
+        let mut dancer = TapDancer::new();
+        dancer.press(0, DANCE);
+        dancer.release(0);
+        dancer.interrupt();
+        assert!(dancer.keycodes().eq([0x04]));
+
+        dancer.press(0, DANCE);
+        dancer.tick();
+        dancer.press(1, DANCE);
+        assert!(dancer.keycodes().eq([0x05]));
+    }
+
+    #[test]
+    fn entries_survive_reopening() {
+        let mut store = Store::open(RamFlash::new());
+        let mut dances = TapDances::new();
+        assert!(dances.set(3, DANCE));
+        assert!(!dances.set(TAP_DANCE_COUNT as u8, DANCE));
+        assert!(dances.take_changed());
+        dances.save(&mut store).unwrap();
+
+        let store = Store::open(store.into_inner());
+        let dances = TapDances::load(&store);
+        assert_eq!(dances.get(3), Some(DANCE));
+        assert_eq!(dances.get(0), Some(TapDance::default()));
+        assert_eq!(store.get(TapDance::FIRST_STORE_KEY, &mut []), None);
+    }
+}
@@ -0,0 +1,461 @@
+//! The VIA configuration protocol
+//!
+//! VIA sends 32 byte reports over raw HID. The first byte is the command and the response is the
+//! same report with the results filled in, or the command replaced with `UNHANDLED` if it isn't
+//! supported. Multi-byte values are big endian.
+//!
+//! https://github.com/the-via/app/blob/main/src/utils/keyboard-api.ts
+
+use crate::dynamic_macros::{MacroBuffer, BUFFER_LEN, MACRO_COUNT};
+use crate::{COLS, ROWS};
+
+/// Size of each report in both directions
+pub const REPORT_LEN: usize = 32;
+/// Version of the protocol, the version that uses QMK's current keycode numbering
+pub const PROTOCOL_VERSION: u16 = 0x000C;
+
+pub const GET_PROTOCOL_VERSION: u8 = 0x01;
+pub const GET_KEYBOARD_VALUE: u8 = 0x02;
+pub const SET_KEYBOARD_VALUE: u8 = 0x03;
+pub const DYNAMIC_KEYMAP_GET_KEYCODE: u8 = 0x04;
+pub const DYNAMIC_KEYMAP_SET_KEYCODE: u8 = 0x05;
+pub const DYNAMIC_KEYMAP_RESET: u8 = 0x06;
+pub const CUSTOM_GET_VALUE: u8 = 0x08;
+pub const EEPROM_RESET: u8 = 0x0A;
+pub const BOOTLOADER_JUMP: u8 = 0x0B;
+pub const DYNAMIC_KEYMAP_MACRO_GET_COUNT: u8 = 0x0C;
+pub const DYNAMIC_KEYMAP_MACRO_GET_BUFFER_SIZE: u8 = 0x0D;
+pub const DYNAMIC_KEYMAP_MACRO_GET_BUFFER: u8 = 0x0E;
+pub const DYNAMIC_KEYMAP_MACRO_SET_BUFFER: u8 = 0x0F;
+pub const DYNAMIC_KEYMAP_MACRO_RESET: u8 = 0x10;
+pub const DYNAMIC_KEYMAP_GET_LAYER_COUNT: u8 = 0x11;
+pub const DYNAMIC_KEYMAP_GET_BUFFER: u8 = 0x12;
+pub const DYNAMIC_KEYMAP_SET_BUFFER: u8 = 0x13;
+/// Response to commands that aren't supported
+pub const UNHANDLED: u8 = 0xFF;
+
+// Keyboard values
+pub const UPTIME: u8 = 0x01;
+pub const LAYOUT_OPTIONS: u8 = 0x02;
+pub const SWITCH_MATRIX_STATE: u8 = 0x03;
+pub const FIRMWARE_VERSION: u8 = 0x04;
+pub const DEVICE_INDICATION: u8 = 0x05;
+
+/// Channel of `CUSTOM_GET_VALUE` for the values only this keyboard has
+pub const CUSTOM_CHANNEL: u8 = 0x00;
+
+/// Values read with `CUSTOM_GET_VALUE`, as 4 bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Value {
+    BacklightLevel = 0x01,
+    /// 1 if the backlight is on
+    BacklightEnabled = 0x02,
+    /// Index of the effect in `Effect::ALL`
+    Effect = 0x03,
+    /// 1 if clicky mode is on
+    Clicky = 0x04,
+    Volume = 0x05,
+    DefaultLayer = 0x06,
+    /// Keys pressed since the keyboard started
+    KeyPresses = 0x80,
+}
+
+impl Value {
+    pub const ALL: [Value; 7] = [
+        Value::BacklightLevel,
+        Value::BacklightEnabled,
+        Value::Effect,
+        Value::Clicky,
+        Value::Volume,
+        Value::DefaultLayer,
+        Value::KeyPresses,
+    ];
+
+    pub fn from_id(id: u8) -> Option<Value> {
+        Value::ALL.iter().copied().find(|&value| value as u8 == id)
+    }
+}
+
+/// Most data that fits in a report after the command, offset and size
+pub const MAX_CHUNK: usize = 28;
+
+/// The parts of the keyboard VIA can see and change
+pub trait Keyboard {
+    fn layer_count(&self) -> u8;
+
+    /// Keycode of the key at `row`, `col` on `layer`, all are in range
+    fn keycode(&self, layer: u8, row: u8, col: u8) -> u16;
+
+    /// Change the key at `row`, `col` on `layer` to `code`, all are in range
+    fn set_keycode(&mut self, layer: u8, row: u8, col: u8, code: u16);
+
+    /// Go back to the compiled in keymap
+    fn reset_keymap(&mut self);
+
+    fn macros(&mut self) -> &mut MacroBuffer;
+
+    /// Milliseconds since the keyboard started
+    fn uptime(&self) -> u32;
+
+    /// Whether each switch is pressed, by matrix row then column
+    fn matrix(&self) -> &[[bool; COLS]; ROWS];
+
+    fn firmware_version(&self) -> u32;
+
+    /// The current setting or statistic for `value`
+    fn value(&self, value: Value) -> u32;
+
+    /// Reboot into the bootloader, this doesn't need to return
+    fn bootloader(&mut self);
+}
+
+/// Handle the command in `report`, replacing it with the response
+pub fn handle<K: Keyboard>(keyboard: &mut K, report: &mut [u8; REPORT_LEN]) {
+    let (command, data) = report.split_first_mut().unwrap();
+    match *command {
+        GET_PROTOCOL_VERSION => data[..2].copy_from_slice(&PROTOCOL_VERSION.to_be_bytes()),
+        GET_KEYBOARD_VALUE => match data[0] {
+            UPTIME => data[1..5].copy_from_slice(&keyboard.uptime().to_be_bytes()),
+            // There are no layout options
+            LAYOUT_OPTIONS => data[1..5].fill(0),
+            SWITCH_MATRIX_STATE => {
+                let offset = usize::from(data[1]);
+                let rows = keyboard.matrix().iter().skip(offset);
+                for (byte, row) in data[2..].iter_mut().zip(rows) {
+                    *byte = row
+                        .iter()
+                        .enumerate()
+                        .fold(0, |bits, (col, &pressed)| bits | (u8::from(pressed) << col));
+                }
+            }
+            FIRMWARE_VERSION => {
+                data[1..5].copy_from_slice(&keyboard.firmware_version().to_be_bytes())
+            }
+            _ => *command = UNHANDLED,
+        },
+        SET_KEYBOARD_VALUE => match data[0] {
+            // Nothing to set, and nothing to show when asked to identify itself
+            LAYOUT_OPTIONS | DEVICE_INDICATION => {}
+            _ => *command = UNHANDLED,
+        },
+        DYNAMIC_KEYMAP_GET_KEYCODE => {
+            let code = match key(keyboard, data[0], data[1], data[2]) {
+                Some((layer, row, col)) => keyboard.keycode(layer, row, col),
+                None => 0,
+            };
+            data[3..5].copy_from_slice(&code.to_be_bytes());
+        }
+        DYNAMIC_KEYMAP_SET_KEYCODE => {
+            if let Some((layer, row, col)) = key(keyboard, data[0], data[1], data[2]) {
+                keyboard.set_keycode(layer, row, col, u16::from_be_bytes([data[3], data[4]]));
+            }
+        }
+        DYNAMIC_KEYMAP_RESET => keyboard.reset_keymap(),
+        CUSTOM_GET_VALUE => match (data[0], Value::from_id(data[1])) {
+            (CUSTOM_CHANNEL, Some(value)) => {
+                data[2..6].copy_from_slice(&keyboard.value(value).to_be_bytes())
+            }
+            _ => *command = UNHANDLED,
+        },
+        // Only the keymap and macros can be reset, the other settings are changed from the keyboard
+        EEPROM_RESET => {
+            keyboard.reset_keymap();
+            keyboard.macros().reset();
+        }
+        BOOTLOADER_JUMP => keyboard.bootloader(),
+        DYNAMIC_KEYMAP_MACRO_GET_COUNT => data[0] = MACRO_COUNT,
+        DYNAMIC_KEYMAP_MACRO_GET_BUFFER_SIZE => {
+            data[..2].copy_from_slice(&(BUFFER_LEN as u16).to_be_bytes())
+        }
+        DYNAMIC_KEYMAP_MACRO_GET_BUFFER => {
+            if let Some((offset, chunk)) = chunk(data) {
+                keyboard.macros().read(offset, chunk);
+            }
+        }
+        DYNAMIC_KEYMAP_MACRO_SET_BUFFER => {
+            if let Some((offset, chunk)) = chunk(data) {
+                keyboard.macros().write(offset, chunk);
+            }
+        }
+        DYNAMIC_KEYMAP_MACRO_RESET => keyboard.macros().reset(),
+        DYNAMIC_KEYMAP_GET_LAYER_COUNT => data[0] = keyboard.layer_count(),
+        DYNAMIC_KEYMAP_GET_BUFFER => {
+            if let Some((offset, chunk)) = chunk(data) {
+                // The keymap is read as if it were one array of keycodes, layer by layer
+                for (i, byte) in chunk.iter_mut().enumerate() {
+                    let position = offset + i;
+                    if let Some((layer, row, col)) = buffer_key(keyboard, position / 2) {
+                        let code = keyboard.keycode(layer, row, col).to_be_bytes();
+                        *byte = code[position % 2];
+                    }
+                }
+            }
+        }
+        DYNAMIC_KEYMAP_SET_BUFFER => {
+            if let Some((offset, chunk)) = chunk(data) {
+                // Keycodes split across reports can't be set, VIA always sends whole ones
+                if offset % 2 == 0 {
+                    for (i, code) in chunk.chunks_exact(2).enumerate() {
+                        if let Some((layer, row, col)) = buffer_key(keyboard, offset / 2 + i) {
+                            let code = u16::from_be_bytes([code[0], code[1]]);
+                            keyboard.set_keycode(layer, row, col, code);
+                        }
+                    }
+                }
+            }
+        }
+        _ => *command = UNHANDLED,
+    }
+}
+
+/// `layer`, `row` and `col` if they're in range
+fn key<K: Keyboard>(keyboard: &K, layer: u8, row: u8, col: u8) -> Option<(u8, u8, u8)> {
+    if layer < keyboard.layer_count() && usize::from(row) < ROWS && usize::from(col) < COLS {
+        Some((layer, row, col))
+    } else {
+        None
+    }
+}
+
+/// Layer, row and column of keycode number `index` in the whole keymap
+fn buffer_key<K: Keyboard>(keyboard: &K, index: usize) -> Option<(u8, u8, u8)> {
+    let layer = index / (ROWS * COLS);
+    let row = index / COLS % ROWS;
+    let col = index % COLS;
+    if layer < usize::from(keyboard.layer_count()) {
+        Some((layer as u8, row as u8, col as u8))
+    } else {
+        None
+    }
+}
+
+/// Offset and data of a buffer read or write, the data is where the response goes too
+fn chunk(data: &mut [u8]) -> Option<(usize, &mut [u8])> {
+    let offset = usize::from(u16::from_be_bytes([data[0], data[1]]));
+    let size = usize::from(data[2]);
+    if size <= MAX_CHUNK {
+        Some((offset, &mut data[3..3 + size]))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::combos::Combos;
+    use crate::tap_dance::TapDances;
+
+    pub(crate) struct FakeKeyboard {
+        pub(crate) keymap: [[[u16; COLS]; ROWS]; 2],
+        pub(crate) macros: MacroBuffer,
+        pub(crate) matrix: [[bool; COLS]; ROWS],
+        pub(crate) bootloader: bool,
+        pub(crate) tap_dances: TapDances,
+        pub(crate) combos: Combos,
+    }
+
+    impl FakeKeyboard {
+        pub(crate) fn new() -> Self {
+            let mut keymap = [[[0; COLS]; ROWS]; 2];
+            for (layer, rows) in keymap.iter_mut().enumerate() {
+                for (row, codes) in rows.iter_mut().enumerate() {
+                    for (col, code) in codes.iter_mut().enumerate() {
+                        *code = (layer * 0x1000 + row * 0x10 + col) as u16;
+                    }
+                }
+            }
+            FakeKeyboard {
+                keymap,
+                macros: MacroBuffer::new(),
+                matrix: [[false; COLS]; ROWS],
+                bootloader: false,
+                tap_dances: TapDances::new(),
+                combos: Combos::new(),
+            }
+        }
+    }
+
+    impl Keyboard for FakeKeyboard {
+        fn layer_count(&self) -> u8 {
+            2
+        }
+
+        fn keycode(&self, layer: u8, row: u8, col: u8) -> u16 {
+            self.keymap[usize::from(layer)][usize::from(row)][usize::from(col)]
+        }
+
+        fn set_keycode(&mut self, layer: u8, row: u8, col: u8, code: u16) {
+            self.keymap[usize::from(layer)][usize::from(row)][usize::from(col)] = code;
+        }
+
+        fn reset_keymap(&mut self) {
+            self.keymap = FakeKeyboard::new().keymap;
+        }
+
+        fn macros(&mut self) -> &mut MacroBuffer {
+            &mut self.macros
+        }
+
+        fn uptime(&self) -> u32 {
+            0x0102_0304
+        }
+
+        fn matrix(&self) -> &[[bool; COLS]; ROWS] {
+            &self.matrix
+        }
+
+        fn firmware_version(&self) -> u32 {
+            0x0001_0000
+        }
+
+        fn value(&self, value: Value) -> u32 {
+            0x0100 + value as u32
+        }
+
+        fn bootloader(&mut self) {
+            self.bootloader = true;
+        }
+    }
+
+    fn send(keyboard: &mut FakeKeyboard, request: &[u8]) -> [u8; REPORT_LEN] {
+        let mut report = [0; REPORT_LEN];
+        report[..request.len()].copy_from_slice(request);
+        handle(keyboard, &mut report);
+        report
+    }
+
+    #[test]
+    fn protocol_version() {
+        let response = send(&mut FakeKeyboard::new(), &[GET_PROTOCOL_VERSION]);
+        assert_eq!(response[..3], [GET_PROTOCOL_VERSION, 0x00, 0x0C]);
+    }
+
+    #[test]
+    fn unknown_commands_are_unhandled() {
+        let mut keyboard = FakeKeyboard::new();
+        assert_eq!(send(&mut keyboard, &[0x42])[0], UNHANDLED);
+        assert_eq!(
+            send(&mut keyboard, &[GET_KEYBOARD_VALUE, 0x42])[0],
+            UNHANDLED
+        );
+    }
+
+    #[test]
+    fn keyboard_values() {
+        let mut keyboard = FakeKeyboard::new();
+        let response = send(&mut keyboard, &[GET_KEYBOARD_VALUE, UPTIME]);
+        assert_eq!(response[2..6], [1, 2, 3, 4]);
+
+        keyboard.matrix[1][0] = true;
+        keyboard.matrix[1][7] = true;
+        keyboard.matrix[9][2] = true;
+        let response = send(&mut keyboard, &[GET_KEYBOARD_VALUE, SWITCH_MATRIX_STATE, 1]);
+        assert_eq!(response[3..13], [0x81, 0, 0, 0, 0, 0, 0, 0, 0x04, 0]);
+    }
+
+    #[test]
+    fn custom_values() {
+        let mut keyboard = FakeKeyboard::new();
+        let response = send(&mut keyboard, &[CUSTOM_GET_VALUE, CUSTOM_CHANNEL, 0x80]);
+        assert_eq!(response[..7], [CUSTOM_GET_VALUE, 0, 0x80, 0, 0, 0x01, 0x80]);
+
+        let response = send(&mut keyboard, &[CUSTOM_GET_VALUE, CUSTOM_CHANNEL, 0x42]);
+        assert_eq!(response[0], UNHANDLED);
+        let response = send(&mut keyboard, &[CUSTOM_GET_VALUE, 0x01, 0x01]);
+        assert_eq!(response[0], UNHANDLED);
+    }
+
+    #[test]
+    fn get_and_set_keycodes() {
+        let mut keyboard = FakeKeyboard::new();
+        let response = send(&mut keyboard, &[DYNAMIC_KEYMAP_GET_KEYCODE, 1, 9, 7]);
+        assert_eq!(response[4..6], [0x10, 0x97]);
+
+        send(
+            &mut keyboard,
+            &[DYNAMIC_KEYMAP_SET_KEYCODE, 0, 2, 0, 0x00, 0x39],
+        );
+        assert_eq!(keyboard.keymap[0][2][0], 0x0039);
+
+        // Out of range keys are ignored
+        let response = send(&mut keyboard, &[DYNAMIC_KEYMAP_GET_KEYCODE, 2, 0, 0]);
+        assert_eq!(response[4..6], [0, 0]);
+        send(&mut keyboard, &[DYNAMIC_KEYMAP_SET_KEYCODE, 0, 10, 0, 0, 0]);
+    }
+
+    #[test]
+    fn keymap_buffer_spans_layers() {
+        let mut keyboard = FakeKeyboard::new();
+        // The last key of layer 0 and the first of layer 1
+        let offset = (ROWS * COLS - 1) as u16 * 2;
+        let [hi, lo] = offset.to_be_bytes();
+        let response = send(&mut keyboard, &[DYNAMIC_KEYMAP_GET_BUFFER, hi, lo, 4]);
+        assert_eq!(response[4..8], [0x00, 0x97, 0x10, 0x00]);
+
+        send(
+            &mut keyboard,
+            &[DYNAMIC_KEYMAP_SET_BUFFER, hi, lo, 4, 0xAA, 0xBB, 0xCC, 0xDD],
+        );
+        assert_eq!(keyboard.keymap[0][9][7], 0xAABB);
+        assert_eq!(keyboard.keymap[1][0][0], 0xCCDD);
+
+        // Reading past the end leaves the rest of the report alone
+        let offset = (2 * ROWS * COLS - 1) as u16 * 2;
+        let [hi, lo] = offset.to_be_bytes();
+        let response = send(&mut keyboard, &[DYNAMIC_KEYMAP_GET_BUFFER, hi, lo, 4]);
+        assert_eq!(response[4..8], [0x10, 0x97, 0, 0]);
+    }
+
+    #[test]
+    fn macro_buffer() {
+        let mut keyboard = FakeKeyboard::new();
+        assert_eq!(
+            send(&mut keyboard, &[DYNAMIC_KEYMAP_MACRO_GET_COUNT])[1],
+            MACRO_COUNT
+        );
+        let response = send(&mut keyboard, &[DYNAMIC_KEYMAP_MACRO_GET_BUFFER_SIZE]);
+        assert_eq!(response[1..3], (BUFFER_LEN as u16).to_be_bytes());
+
+        send(
+            &mut keyboard,
+            &[DYNAMIC_KEYMAP_MACRO_SET_BUFFER, 0, 4, 3, b'h', b'i', 0],
+        );
+        assert_eq!(keyboard.macros.get(4), b"hi");
+        let response = send(&mut keyboard, &[DYNAMIC_KEYMAP_MACRO_GET_BUFFER, 0, 3, 4]);
+        assert_eq!(response[4..8], [0, b'h', b'i', 0]);
+
+        send(&mut keyboard, &[DYNAMIC_KEYMAP_MACRO_RESET]);
+        assert_eq!(keyboard.macros.get(4), b"");
+    }
+
+    #[test]
+    fn oversized_chunks_are_ignored() {
+        let mut keyboard = FakeKeyboard::new();
+        send(
+            &mut keyboard,
+            &[
+                DYNAMIC_KEYMAP_SET_BUFFER,
+                0,
+                0,
+                MAX_CHUNK as u8 + 2,
+                0xAA,
+                0xBB,
+            ],
+        );
+        assert_eq!(keyboard.keymap[0][0][0], 0);
+    }
+
+    #[test]
+    fn resets() {
+        let mut keyboard = FakeKeyboard::new();
+        send(&mut keyboard, &[DYNAMIC_KEYMAP_SET_KEYCODE, 0, 0, 0, 0, 4]);
+        keyboard.macros.write(0, b"x");
+        send(&mut keyboard, &[EEPROM_RESET]);
+        assert_eq!(keyboard.keymap[0][0][0], 0);
+        assert_eq!(keyboard.macros.get(0), b"");
+
+        send(&mut keyboard, &[BOOTLOADER_JUMP]);
+        assert!(keyboard.bootloader);
+    }
+}
@@ -0,0 +1,474 @@
+//! The Vial configuration protocol
+//!
+//! Vial is VIA with extra commands behind a 0xFE prefix. It reads the keyboard definition from
+//! the keyboard itself, and edits tap dances and combos. Things that could be used to take over the
+//! computer the keyboard is plugged into need it to be unlocked first, by holding down keys on the
+//! keyboard. While locked macros can't be changed, nothing can be set to `QK_BOOT`, and the host
+//! can't reboot the keyboard into the bootloader.
+//!
+//! https://get.vial.today/docs/
+//! https://github.com/vial-kb/vial-qmk/blob/vial/quantum/vial.c
+
+use crate::combos::{Combo, Combos, COMBO_COUNT};
+use crate::dynamic_entries::{Entry, ENTRY_LEN};
+use crate::tap_dance::{TapDance, TapDances, TAP_DANCE_COUNT};
+use crate::via::{
+    self, BOOTLOADER_JUMP, DYNAMIC_KEYMAP_MACRO_SET_BUFFER, DYNAMIC_KEYMAP_SET_BUFFER,
+    DYNAMIC_KEYMAP_SET_KEYCODE, GET_PROTOCOL_VERSION, REPORT_LEN, UNHANDLED,
+};
+
+/// Version of the Vial protocol, the version that uses QMK's current keycode numbering
+pub const PROTOCOL_VERSION: u32 = 6;
+/// Version of the VIA protocol Vial expects
+pub const VIA_PROTOCOL_VERSION: u16 = 0x0009;
+/// Vial finds keyboards it can talk to by this in their USB serial number
+pub const SERIAL_NUMBER: &str = "vial:f64c2b3c";
+
+/// Starts every Vial command
+const PREFIX: u8 = 0xFE;
+
+const GET_KEYBOARD_ID: u8 = 0x00;
+const GET_SIZE: u8 = 0x01;
+const GET_DEFINITION: u8 = 0x02;
+const GET_UNLOCK_STATUS: u8 = 0x05;
+const UNLOCK_START: u8 = 0x06;
+const UNLOCK_POLL: u8 = 0x07;
+const LOCK: u8 = 0x08;
+const QMK_SETTINGS_QUERY: u8 = 0x09;
+const DYNAMIC_ENTRY_OP: u8 = 0x0D;
+
+// Dynamic entry operations
+const GET_NUMBER_OF_ENTRIES: u8 = 0x00;
+const TAP_DANCE_GET: u8 = 0x01;
+const TAP_DANCE_SET: u8 = 0x02;
+const COMBO_GET: u8 = 0x03;
+const COMBO_SET: u8 = 0x04;
+
+/// Result of a dynamic entry operation
+const OK: u8 = 0;
+const NO_SUCH_ENTRY: u8 = 1;
+
+const QK_BOOT: u16 = 0x7C00;
+
+/// Number of times the unlock keys have to be seen held down, `UNLOCK_STEP` apart
+const UNLOCK_STEPS: u8 = 50;
+/// Milliseconds between each step of unlocking
+const UNLOCK_STEP: u32 = 100;
+
+/// The parts of the keyboard Vial can see and change as well as the ones VIA can
+pub trait Keyboard: via::Keyboard {
+    fn tap_dances(&mut self) -> &mut TapDances;
+
+    fn combos(&mut self) -> &mut Combos;
+}
+
+pub struct Vial {
+    /// The keyboard definition JSON, compressed with xz
+    definition: &'static [u8],
+    /// Tells apart keyboards Vial has seen before
+    uid: [u8; 8],
+    /// Row and column of each key that has to be held down to unlock
+    unlock_keys: &'static [(u8, u8)],
+    unlocked: bool,
+    unlocking: bool,
+    /// Steps of unlocking left
+    unlock_steps: u8,
+    /// Uptime at the last step of unlocking
+    last_step: u32,
+}
+
+impl Vial {
+    pub const fn new(
+        definition: &'static [u8],
+        uid: [u8; 8],
+        unlock_keys: &'static [(u8, u8)],
+    ) -> Self {
+        Vial {
+            definition,
+            uid,
+            unlock_keys,
+            unlocked: false,
+            unlocking: false,
+            unlock_steps: UNLOCK_STEPS,
+            last_step: 0,
+        }
+    }
+
+    pub fn is_unlocked(&self) -> bool {
+        self.unlocked
+    }
+
+    /// Handle the Vial or VIA command in `report`, replacing it with the response
+    pub fn handle<K: Keyboard>(&mut self, keyboard: &mut K, report: &mut [u8; REPORT_LEN]) {
+        if report[0] == PREFIX {
+            self.handle_vial(keyboard, report);
+            return;
+        }
+        if self.unlocking {
+            // Nothing can be changed while the keys are being held, in case they're being typed
+            report[0] = UNHANDLED;
+            return;
+        }
+
+        if !self.unlocked {
+            match report[0] {
+                DYNAMIC_KEYMAP_MACRO_SET_BUFFER | BOOTLOADER_JUMP => return,
+                DYNAMIC_KEYMAP_SET_KEYCODE => firewall(&mut report[4..6]),
+                DYNAMIC_KEYMAP_SET_BUFFER => {
+                    let len = usize::from(report[3]).min(REPORT_LEN - 4);
+                    for code in report[4..4 + len].chunks_exact_mut(2) {
+                        firewall(code);
+                    }
+                }
+                _ => {}
+            }
+        }
+        via::handle(keyboard, report);
+        if report[0] == GET_PROTOCOL_VERSION {
+            report[1..3].copy_from_slice(&VIA_PROTOCOL_VERSION.to_be_bytes());
+        }
+    }
+
+    fn handle_vial<K: Keyboard>(&mut self, keyboard: &mut K, report: &mut [u8; REPORT_LEN]) {
+        match report[1] {
+            GET_KEYBOARD_ID => {
+                *report = [0; REPORT_LEN];
+                report[..4].copy_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+                report[4..12].copy_from_slice(&self.uid);
+            }
+            GET_SIZE => {
+                let size = self.definition.len() as u32;
+                report[..4].copy_from_slice(&size.to_le_bytes());
+            }
+            GET_DEFINITION => {
+                let page = usize::from(u16::from_le_bytes([report[2], report[3]]));
+                let chunk = self.definition.chunks(REPORT_LEN).nth(page).unwrap_or(&[]);
+                report[..chunk.len()].copy_from_slice(chunk);
+            }
+            GET_UNLOCK_STATUS => {
+                *report = [0xFF; REPORT_LEN];
+                report[0] = self.unlocked.into();
+                report[1] = self.unlocking.into();
+                for (bytes, &(row, col)) in report[2..].chunks_exact_mut(2).zip(self.unlock_keys) {
+                    bytes.copy_from_slice(&[row, col]);
+                }
+            }
+            UNLOCK_START => {
+                self.unlocking = true;
+                self.unlock_steps = UNLOCK_STEPS;
+                self.last_step = keyboard.uptime();
+            }
+            UNLOCK_POLL => {
+                if self.unlocking {
+                    self.poll_unlock(keyboard);
+                }
+                report[0] = self.unlocked.into();
+                report[1] = self.unlocking.into();
+                report[2] = self.unlock_steps;
+            }
+            LOCK => self.unlocked = false,
+            // There are no QMK settings
+            QMK_SETTINGS_QUERY => *report = [0xFF; REPORT_LEN],
+            DYNAMIC_ENTRY_OP if !self.unlocking => self.dynamic_entry(keyboard, report),
+            _ => report[0] = UNHANDLED,
+        }
+    }
+
+    fn poll_unlock<K: Keyboard>(&mut self, keyboard: &K) {
+        let matrix = keyboard.matrix();
+        let holding = self.unlock_keys.iter().all(|&(row, col)| {
+            matrix
+                .get(usize::from(row))
+                .and_then(|cols| cols.get(usize::from(col)))
+                .copied()
+                .unwrap_or(false)
+        });
+        if !holding {
+            self.unlock_steps = UNLOCK_STEPS;
+            return;
+        }
+
+        let now = keyboard.uptime();
+        if now.wrapping_sub(self.last_step) > UNLOCK_STEP {
+            self.last_step = now;
+            self.unlock_steps -= 1;
+            if self.unlock_steps == 0 {
+                self.unlocking = false;
+                self.unlocked = true;
+            }
+        }
+    }
+
+    fn dynamic_entry<K: Keyboard>(&mut self, keyboard: &mut K, report: &mut [u8; REPORT_LEN]) {
+        let index = report[3];
+        match report[2] {
+            GET_NUMBER_OF_ENTRIES => {
+                *report = [0; REPORT_LEN];
+                report[0] = TAP_DANCE_COUNT as u8;
+                report[1] = COMBO_COUNT as u8;
+                // No key overrides
+                report[2] = 0;
+            }
+            TAP_DANCE_GET => get(keyboard.tap_dances().get(index), report),
+            TAP_DANCE_SET => {
+                let mut entry = TapDance::from_bytes(&entry_bytes(report));
+                if !self.unlocked {
+                    for code in [
+                        &mut entry.on_tap,
+                        &mut entry.on_hold,
+                        &mut entry.on_double_tap,
+                        &mut entry.on_tap_hold,
+                    ] {
+                        *code = firewalled(*code);
+                    }
+                }
+                report[0] = status(keyboard.tap_dances().set(index, entry));
+            }
+            COMBO_GET => get(keyboard.combos().get(index), report),
+            COMBO_SET => {
+                let mut entry = Combo::from_bytes(&entry_bytes(report));
+                if !self.unlocked {
+                    entry.output = firewalled(entry.output);
+                }
+                report[0] = status(keyboard.combos().set(index, entry));
+            }
+            _ => report[0] = UNHANDLED,
+        }
+    }
+}
+
+/// Respond with `entry`, or an error if there's no such entry
+fn get<T: Entry>(entry: Option<T>, report: &mut [u8; REPORT_LEN]) {
+    match entry {
+        Some(entry) => {
+            report[0] = OK;
+            report[1..1 + ENTRY_LEN].copy_from_slice(&entry.to_bytes());
+        }
+        None => report[0] = NO_SUCH_ENTRY,
+    }
+}
+
+/// The entry being set by a dynamic entry operation
+fn entry_bytes(report: &[u8; REPORT_LEN]) -> [u8; ENTRY_LEN] {
+    let mut bytes = [0; ENTRY_LEN];
+    bytes.copy_from_slice(&report[4..4 + ENTRY_LEN]);
+    bytes
+}
+
+fn status(found: bool) -> u8 {
+    if found {
+        OK
+    } else {
+        NO_SUCH_ENTRY
+    }
+}
+
+/// `code`, or nothing if it would reboot into the bootloader
+fn firewalled(code: u16) -> u16 {
+    if code == QK_BOOT {
+        0
+    } else {
+        code
+    }
+}
+
+/// Replace a big endian `QK_BOOT` with nothing
+fn firewall(code: &mut [u8]) {
+    let value = firewalled(u16::from_be_bytes([code[0], code[1]]));
+    code.copy_from_slice(&value.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::via::tests::FakeKeyboard;
+
+    const DEFINITION: [u8; 40] = [7; 40];
+    const UNLOCK_KEYS: [(u8, u8); 2] = [(0, 0), (7, 5)];
+
+    impl Keyboard for FakeKeyboard {
+        fn tap_dances(&mut self) -> &mut TapDances {
+            &mut self.tap_dances
+        }
+
+        fn combos(&mut self) -> &mut Combos {
+            &mut self.combos
+        }
+    }
+
+    fn vial() -> Vial {
+        Vial::new(&DEFINITION, [1, 2, 3, 4, 5, 6, 7, 8], &UNLOCK_KEYS)
+    }
+
+    fn send(vial: &mut Vial, keyboard: &mut FakeKeyboard, request: &[u8]) -> [u8; REPORT_LEN] {
+        let mut report = [0; REPORT_LEN];
+        report[..request.len()].copy_from_slice(request);
+        vial.handle(keyboard, &mut report);
+        report
+    }
+
+    /// Hold the unlock keys and poll until unlocked
+    fn unlock(vial: &mut Vial, keyboard: &mut FakeKeyboard) {
+        send(vial, keyboard, &[PREFIX, UNLOCK_START]);
+        for &(row, col) in UNLOCK_KEYS.iter() {
+            keyboard.matrix[usize::from(row)][usize::from(col)] = true;
+        }
+        // FakeKeyboard's uptime doesn't move, so put the last step in the past
+        for _ in 0..UNLOCK_STEPS {
+            vial.last_step = vial.last_step.wrapping_sub(UNLOCK_STEP + 1);
+            send(vial, keyboard, &[PREFIX, UNLOCK_POLL]);
+        }
+        assert!(vial.is_unlocked());
+    }
+
+    #[test]
+    fn keyboard_id_and_definition() {
+        let mut vial = vial();
+        let mut keyboard = FakeKeyboard::new();
+        let response = send(&mut vial, &mut keyboard, &[PREFIX, GET_KEYBOARD_ID]);
+        assert_eq!(response[..12], [6, 0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8]);
+
+        let response = send(&mut vial, &mut keyboard, &[PREFIX, GET_SIZE]);
+        assert_eq!(response[..4], [40, 0, 0, 0]);
+        let response = send(&mut vial, &mut keyboard, &[PREFIX, GET_DEFINITION, 1, 0]);
+        assert_eq!(response[..8], [7; 8]);
+        assert_eq!(response[8], 0);
+    }
+
+    #[test]
+    fn reports_via_protocol_vial_expects() {
+        let response = send(
+            &mut vial(),
+            &mut FakeKeyboard::new(),
+            &[GET_PROTOCOL_VERSION],
+        );
+        assert_eq!(response[..3], [GET_PROTOCOL_VERSION, 0x00, 0x09]);
+    }
+
+    #[test]
+    fn unlock_status_lists_keys() {
+        let response = send(
+            &mut vial(),
+            &mut FakeKeyboard::new(),
+            &[PREFIX, GET_UNLOCK_STATUS],
+        );
+        assert_eq!(response[..7], [0, 0, 0, 0, 7, 5, 0xFF]);
+    }
+
+    #[test]
+    fn letting_go_starts_unlocking_again() {
+        let mut vial = vial();
+        let mut keyboard = FakeKeyboard::new();
+        send(&mut vial, &mut keyboard, &[PREFIX, UNLOCK_START]);
+        keyboard.matrix[0][0] = true;
+        keyboard.matrix[7][5] = true;
+        vial.last_step = vial.last_step.wrapping_sub(UNLOCK_STEP + 1);
+        let response = send(&mut vial, &mut keyboard, &[PREFIX, UNLOCK_POLL]);
+        assert_eq!(response[..3], [0, 1, UNLOCK_STEPS - 1]);
+
+        keyboard.matrix[7][5] = false;
+        let response = send(&mut vial, &mut keyboard, &[PREFIX, UNLOCK_POLL]);
+        assert_eq!(response[..3], [0, 1, UNLOCK_STEPS]);
+    }
+
+    #[test]
+    fn nothing_changes_while_unlocking() {
+        let mut vial = vial();
+        let mut keyboard = FakeKeyboard::new();
+        send(&mut vial, &mut keyboard, &[PREFIX, UNLOCK_START]);
+        let request = [DYNAMIC_KEYMAP_SET_KEYCODE, 0, 0, 0, 0x00, 0x04];
+        assert_eq!(send(&mut vial, &mut keyboard, &request)[0], UNHANDLED);
+        assert_eq!(keyboard.keymap[0][0][0], 0);
+    }
+
+    #[test]
+    fn locked_keyboard_is_protected() {
+        let mut vial = vial();
+        let mut keyboard = FakeKeyboard::new();
+        send(
+            &mut vial,
+            &mut keyboard,
+            &[DYNAMIC_KEYMAP_MACRO_SET_BUFFER, 0, 0, 1, b'x'],
+        );
+        assert_eq!(keyboard.macros.get(0), b"");
+        send(&mut vial, &mut keyboard, &[BOOTLOADER_JUMP]);
+        assert!(!keyboard.bootloader);
+        send(
+            &mut vial,
+            &mut keyboard,
+            &[DYNAMIC_KEYMAP_SET_KEYCODE, 0, 1, 1, 0x7C, 0x00],
+        );
+        assert_eq!(keyboard.keymap[0][1][1], 0);
+        send(
+            &mut vial,
+            &mut keyboard,
+            &[DYNAMIC_KEYMAP_SET_KEYCODE, 0, 1, 1, 0x00, 0x04],
+        );
+        assert_eq!(keyboard.keymap[0][1][1], 0x04);
+
+        unlock(&mut vial, &mut keyboard);
+        send(
+            &mut vial,
+            &mut keyboard,
+            &[DYNAMIC_KEYMAP_MACRO_SET_BUFFER, 0, 0, 1, b'x'],
+        );
+        assert_eq!(keyboard.macros.get(0), b"x");
+        send(
+            &mut vial,
+            &mut keyboard,
+            &[DYNAMIC_KEYMAP_SET_KEYCODE, 0, 1, 1, 0x7C, 0x00],
+        );
+        assert_eq!(keyboard.keymap[0][1][1], QK_BOOT);
+
+        send(&mut vial, &mut keyboard, &[PREFIX, LOCK]);
+        assert!(!vial.is_unlocked());
+    }
+
+    #[test]
+    fn tap_dances_and_combos() {
+        let mut vial = vial();
+        let mut keyboard = FakeKeyboard::new();
+        let response = send(&mut vial, &mut keyboard, &[PREFIX, DYNAMIC_ENTRY_OP]);
+        assert_eq!(response[..3], [8, 8, 0]);
+
+        let dance = [
+            PREFIX,
+            DYNAMIC_ENTRY_OP,
+            TAP_DANCE_SET,
+            2,
+            0x04,
+            0,
+            0x00,
+            0x7C,
+        ];
+        assert_eq!(send(&mut vial, &mut keyboard, &dance)[0], OK);
+        let response = send(
+            &mut vial,
+            &mut keyboard,
+            &[PREFIX, DYNAMIC_ENTRY_OP, TAP_DANCE_GET, 2],
+        );
+        // QK_BOOT isn't allowed while locked
+        assert_eq!(response[..5], [OK, 0x04, 0, 0, 0]);
+
+        let combo = [
+            PREFIX,
+            DYNAMIC_ENTRY_OP,
+            COMBO_SET,
+            1,
+            0x0D,
+            0,
+            0x0E,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0x29,
+        ];
+        assert_eq!(send(&mut vial, &mut keyboard, &combo)[0], OK);
+        assert_eq!(keyboard.combos.get(1).unwrap().output, 0x29);
+
+        let missing = [PREFIX, DYNAMIC_ENTRY_OP, COMBO_GET, COMBO_COUNT as u8];
+        assert_eq!(send(&mut vial, &mut keyboard, &missing)[0], NO_SUCH_ENTRY);
+    }
+}
@@ -0,0 +1,239 @@
+//! The names of keys in `{...}` escapes
+
+use crate::layouts::{Layout, Modifier};
+
+/// Names for keys in macro escapes, anything else has to be a keyberon `KeyCode` name
+const SPECIAL_KEYS: [(&str, &str); 21] = [
+    ("ENTER", "Enter"),
+    ("TAB", "Tab"),
+    ("ESC", "Escape"),
+    ("BACKSPACE", "BSpace"),
+    ("DELETE", "Delete"),
+    ("INSERT", "Insert"),
+    ("SPACE", "Space"),
+    ("HOME", "Home"),
+    ("END", "End"),
+    ("PGUP", "PgUp"),
+    ("PGDN", "PgDown"),
+    ("UP", "Up"),
+    ("DOWN", "Down"),
+    ("LEFT", "Left"),
+    ("RIGHT", "Right"),
+    // Modifiers
+    ("CTRL", "LCtrl"),
+    ("SHIFT", "LShift"),
+    ("ALT", "LAlt"),
+    ("GUI", "LGui"),
+    ("ALTGR", "RAlt"),
+    ("RSHIFT", "RShift"),
+];
+
+/// HID usage and keyberon `KeyCode` name of every key a macro can press, the ones QMK gives
+/// basic keycodes
+pub static KEY_CODES: [(u8, &str); 169] = [
+    (0x04, "A"),
+    (0x05, "B"),
+    (0x06, "C"),
+    (0x07, "D"),
+    (0x08, "E"),
+    (0x09, "F"),
+    (0x0A, "G"),
+    (0x0B, "H"),
+    (0x0C, "I"),
+    (0x0D, "J"),
+    (0x0E, "K"),
+    (0x0F, "L"),
+    (0x10, "M"),
+    (0x11, "N"),
+    (0x12, "O"),
+    (0x13, "P"),
+    (0x14, "Q"),
+    (0x15, "R"),
+    (0x16, "S"),
+    (0x17, "T"),
+    (0x18, "U"),
+    (0x19, "V"),
+    (0x1A, "W"),
+    (0x1B, "X"),
+    (0x1C, "Y"),
+    (0x1D, "Z"),
+    (0x1E, "Kb1"),
+    (0x1F, "Kb2"),
+    (0x20, "Kb3"),
+    (0x21, "Kb4"),
+    (0x22, "Kb5"),
+    (0x23, "Kb6"),
+    (0x24, "Kb7"),
+    (0x25, "Kb8"),
+    (0x26, "Kb9"),
+    (0x27, "Kb0"),
+    (0x28, "Enter"),
+    (0x29, "Escape"),
+    (0x2A, "BSpace"),
+    (0x2B, "Tab"),
+    (0x2C, "Space"),
+    (0x2D, "Minus"),
+    (0x2E, "Equal"),
+    (0x2F, "LBracket"),
+    (0x30, "RBracket"),
+    (0x31, "Bslash"),
+    (0x32, "NonUsHash"),
+    (0x33, "SColon"),
+    (0x34, "Quote"),
+    (0x35, "Grave"),
+    (0x36, "Comma"),
+    (0x37, "Dot"),
+    (0x38, "Slash"),
+    (0x39, "CapsLock"),
+    (0x3A, "F1"),
+    (0x3B, "F2"),
+    (0x3C, "F3"),
+    (0x3D, "F4"),
+    (0x3E, "F5"),
+    (0x3F, "F6"),
+    (0x40, "F7"),
+    (0x41, "F8"),
+    (0x42, "F9"),
+    (0x43, "F10"),
+    (0x44, "F11"),
+    (0x45, "F12"),
+    (0x46, "PScreen"),
+    (0x47, "ScrollLock"),
+    (0x48, "Pause"),
+    (0x49, "Insert"),
+    (0x4A, "Home"),
+    (0x4B, "PgUp"),
+    (0x4C, "Delete"),
+    (0x4D, "End"),
+    (0x4E, "PgDown"),
+    (0x4F, "Right"),
+    (0x50, "Left"),
+    (0x51, "Down"),
+    (0x52, "Up"),
+    (0x53, "NumLock"),
+    (0x54, "KpSlash"),
+    (0x55, "KpAsterisk"),
+    (0x56, "KpMinus"),
+    (0x57, "KpPlus"),
+    (0x58, "KpEnter"),
+    (0x59, "Kp1"),
+    (0x5A, "Kp2"),
+    (0x5B, "Kp3"),
+    (0x5C, "Kp4"),
+    (0x5D, "Kp5"),
+    (0x5E, "Kp6"),
+    (0x5F, "Kp7"),
+    (0x60, "Kp8"),
+    (0x61, "Kp9"),
+    (0x62, "Kp0"),
+    (0x63, "KpDot"),
+    (0x64, "NonUsBslash"),
+    (0x65, "Application"),
+    (0x66, "Power"),
+    (0x67, "KpEqual"),
+    (0x68, "F13"),
+    (0x69, "F14"),
+    (0x6A, "F15"),
+    (0x6B, "F16"),
+    (0x6C, "F17"),
+    (0x6D, "F18"),
+    (0x6E, "F19"),
+    (0x6F, "F20"),
+    (0x70, "F21"),
+    (0x71, "F22"),
+    (0x72, "F23"),
+    (0x73, "F24"),
+    (0x74, "Execute"),
+    (0x75, "Help"),
+    (0x76, "Menu"),
+    (0x77, "Select"),
+    (0x78, "Stop"),
+    (0x79, "Again"),
+    (0x7A, "Undo"),
+    (0x7B, "Cut"),
+    (0x7C, "Copy"),
+    (0x7D, "Paste"),
+    (0x7E, "Find"),
+    (0x7F, "Mute"),
+    (0x80, "VolUp"),
+    (0x81, "VolDown"),
+    (0x82, "LockingCapsLock"),
+    (0x83, "LockingNumLock"),
+    (0x84, "LockingScrollLock"),
+    (0x85, "KpComma"),
+    (0x86, "KpEqualSign"),
+    (0x87, "Intl1"),
+    (0x88, "Intl2"),
+    (0x89, "Intl3"),
+    (0x8A, "Intl4"),
+    (0x8B, "Intl5"),
+    (0x8C, "Intl6"),
+    (0x8D, "Intl7"),
+    (0x8E, "Intl8"),
+    (0x8F, "Intl9"),
+    (0x90, "Lang1"),
+    (0x91, "Lang2"),
+    (0x92, "Lang3"),
+    (0x93, "Lang4"),
+    (0x94, "Lang5"),
+    (0x95, "Lang6"),
+    (0x96, "Lang7"),
+    (0x97, "Lang8"),
+    (0x98, "Lang9"),
+    (0x99, "AltErase"),
+    (0x9A, "SysReq"),
+    (0x9B, "Cancel"),
+    (0x9C, "Clear"),
+    (0x9D, "Prior"),
+    (0x9E, "Return"),
+    (0x9F, "Separator"),
+    (0xA0, "Out"),
+    (0xA1, "Oper"),
+    (0xA2, "ClearAgain"),
+    (0xA3, "CrSel"),
+    (0xA4, "ExSel"),
+    (0xE0, "LCtrl"),
+    (0xE1, "LShift"),
+    (0xE2, "LAlt"),
+    (0xE3, "LGui"),
+    (0xE4, "RCtrl"),
+    (0xE5, "RShift"),
+    (0xE6, "RAlt"),
+    (0xE7, "RGui"),
+];
+
+/// The keyberon `KeyCode` name of a key in a `{...}` escape, such as `ENTER`, `T` or `F5`. A
+/// character is the key that types it on `layout`.
+pub fn parse_name(name: &str, layout: &Layout) -> Result<String, String> {
+    if let Some(&(_, key)) = SPECIAL_KEYS.iter().find(|(alias, _)| *alias == name) {
+        return Ok(key.to_string());
+    }
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (None, _) => Err("missing key name".to_string()),
+        (Some(ch), None) => match layout.stroke(ch.to_ascii_lowercase()) {
+            Some(stroke) if stroke.modifier == Modifier::None && !stroke.dead => {
+                Ok(stroke.key.to_string())
+            }
+            _ => Err(format!(
+                "'{}' isn't on a key of its own on the {} layout, use the key's name",
+                ch, layout.name
+            )),
+        },
+        // Anything else has to be a keyberon KeyCode, like F5 or PScreen
+        _ if KEY_CODES.iter().any(|&(_, key)| key == name) => Ok(name.to_string()),
+        _ => Err(format!("unknown key '{}'", name)),
+    }
+}
+
+/// The name `key` is written as in a `{...}` escape, the inverse of `parse_name`
+pub fn name(key: &str, layout: &Layout) -> String {
+    if let Some(&(alias, _)) = SPECIAL_KEYS.iter().find(|(_, name)| *name == key) {
+        return alias.to_string();
+    }
+    // A + would be taken as joining keys
+    match layout.char(key, Modifier::None).filter(|&ch| ch != '+') {
+        Some(ch) if !layout.is_dead(ch) => ch.to_ascii_uppercase().to_string(),
+        _ => key.to_string(),
+    }
+}
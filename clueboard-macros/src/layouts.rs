@@ -0,0 +1,277 @@
+//! The software keyboard layouts the host can be using, which decide the keys that type each
+//! character
+//!
+//! Each layout lists what its keys type as on Windows, by the keyberon `KeyCode` name of the key,
+//! which is named for what it types on a US layout. Letter keys that type their own letter are
+//! left out. Only ASCII matters as that's all a macro can type.
+
+/// A modifier held while pressing a key to type a character
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Modifier {
+    None,
+    Shift,
+    AltGr,
+}
+
+const MODIFIERS: [Modifier; 3] = [Modifier::None, Modifier::Shift, Modifier::AltGr];
+
+/// What typing a character takes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stroke {
+    /// keyberon `KeyCode` name of the key
+    pub key: &'static str,
+    pub modifier: Modifier,
+    /// Whether the key is a dead key, which needs a space after it to type the character alone
+    pub dead: bool,
+}
+
+pub struct Layout {
+    /// What the layout is called in `@layout` lines
+    pub name: &'static str,
+    /// Keys and what they type on their own, with shift, and with AltGr, NONE for anything that
+    /// isn't ASCII
+    keys: &'static [(&'static str, [char; 3])],
+    /// Characters typed by dead keys
+    dead: &'static [char],
+}
+
+/// No character, or one that isn't ASCII
+const NONE: char = '\0';
+
+const LETTERS: [&str; 26] = [
+    "A", "B", "C", "D", "E", "F", "G", "H", "I", "J", "K", "L", "M", "N", "O", "P", "Q", "R", "S",
+    "T", "U", "V", "W", "X", "Y", "Z",
+];
+
+impl Layout {
+    /// What typing `ch` takes, if it can be typed
+    pub fn stroke(&self, ch: char) -> Option<Stroke> {
+        let found = self.keys.iter().find_map(|&(key, chars)| {
+            let modifier = chars.iter().position(|&typed| typed == ch && ch != NONE)?;
+            Some((key, MODIFIERS[modifier]))
+        });
+        let (key, modifier) = match found {
+            Some(found) => found,
+            None if ch == ' ' => ("Space", Modifier::None),
+            None if ch.is_ascii_alphabetic() => {
+                let key = LETTERS[usize::from(ch.to_ascii_uppercase() as u8 - b'A')];
+                if self.keys.iter().any(|&(name, _)| name == key) {
+                    return None;
+                }
+                if ch.is_ascii_uppercase() {
+                    (key, Modifier::Shift)
+                } else {
+                    (key, Modifier::None)
+                }
+            }
+            None => return None,
+        };
+        Some(Stroke {
+            key,
+            modifier,
+            dead: self.dead.contains(&ch),
+        })
+    }
+
+    /// The character `key` types with `modifier` held, the inverse of `stroke`
+    pub fn char(&self, key: &str, modifier: Modifier) -> Option<char> {
+        let chars = match self.keys.iter().find(|&&(name, _)| name == key) {
+            Some(&(_, chars)) => chars,
+            None if key == "Space" => [' ', NONE, NONE],
+            None if LETTERS.contains(&key) => {
+                let letter = key.chars().next()?;
+                [letter.to_ascii_lowercase(), letter, NONE]
+            }
+            None => return None,
+        };
+        let index = MODIFIERS.iter().position(|&other| other == modifier)?;
+        Some(chars[index]).filter(|&ch| ch != NONE)
+    }
+
+    /// Whether `ch` is typed by a dead key
+    pub fn is_dead(&self, ch: char) -> bool {
+        self.dead.contains(&ch)
+    }
+}
+
+/// US QWERTY, what macros are typed for unless there's an `@layout` line
+pub static US: Layout = Layout {
+    name: "us",
+    keys: &[
+        ("Kb1", ['1', '!', NONE]),
+        ("Kb2", ['2', '@', NONE]),
+        ("Kb3", ['3', '#', NONE]),
+        ("Kb4", ['4', '$', NONE]),
+        ("Kb5", ['5', '%', NONE]),
+        ("Kb6", ['6', '^', NONE]),
+        ("Kb7", ['7', '&', NONE]),
+        ("Kb8", ['8', '*', NONE]),
+        ("Kb9", ['9', '(', NONE]),
+        ("Kb0", ['0', ')', NONE]),
+        ("Minus", ['-', '_', NONE]),
+        ("Equal", ['=', '+', NONE]),
+        ("LBracket", ['[', '{', NONE]),
+        ("RBracket", [']', '}', NONE]),
+        ("Bslash", ['\\', '|', NONE]),
+        ("SColon", [';', ':', NONE]),
+        ("Quote", ['\'', '"', NONE]),
+        ("Grave", ['`', '~', NONE]),
+        ("Comma", [',', '<', NONE]),
+        ("Dot", ['.', '>', NONE]),
+        ("Slash", ['/', '?', NONE]),
+    ],
+    dead: &[],
+};
+
+/// UK QWERTY
+pub static UK: Layout = Layout {
+    name: "uk",
+    keys: &[
+        ("Kb1", ['1', '!', NONE]),
+        ("Kb2", ['2', '"', NONE]),
+        ("Kb3", ['3', NONE, NONE]),
+        ("Kb4", ['4', '$', NONE]),
+        ("Kb5", ['5', '%', NONE]),
+        ("Kb6", ['6', '^', NONE]),
+        ("Kb7", ['7', '&', NONE]),
+        ("Kb8", ['8', '*', NONE]),
+        ("Kb9", ['9', '(', NONE]),
+        ("Kb0", ['0', ')', NONE]),
+        ("Minus", ['-', '_', NONE]),
+        ("Equal", ['=', '+', NONE]),
+        ("LBracket", ['[', '{', NONE]),
+        ("RBracket", [']', '}', NONE]),
+        ("SColon", [';', ':', NONE]),
+        ("Quote", ['\'', '@', NONE]),
+        ("NonUsHash", ['#', '~', NONE]),
+        ("Grave", ['`', NONE, NONE]),
+        ("NonUsBslash", ['\\', '|', NONE]),
+        ("Comma", [',', '<', NONE]),
+        ("Dot", ['.', '>', NONE]),
+        ("Slash", ['/', '?', NONE]),
+    ],
+    dead: &[],
+};
+
+/// German QWERTZ
+pub static DE: Layout = Layout {
+    name: "de",
+    keys: &[
+        ("Kb1", ['1', '!', NONE]),
+        ("Kb2", ['2', '"', NONE]),
+        ("Kb3", ['3', NONE, NONE]),
+        ("Kb4", ['4', '$', NONE]),
+        ("Kb5", ['5', '%', NONE]),
+        ("Kb6", ['6', '&', NONE]),
+        ("Kb7", ['7', '/', '{']),
+        ("Kb8", ['8', '(', '[']),
+        ("Kb9", ['9', ')', ']']),
+        ("Kb0", ['0', '=', '}']),
+        ("Minus", [NONE, '?', '\\']),
+        ("Equal", [NONE, '`', NONE]),
+        ("Q", ['q', 'Q', '@']),
+        ("Y", ['z', 'Z', NONE]),
+        ("Z", ['y', 'Y', NONE]),
+        ("RBracket", ['+', '*', '~']),
+        ("NonUsHash", ['#', '\'', NONE]),
+        ("Grave", ['^', NONE, NONE]),
+        ("NonUsBslash", ['<', '>', '|']),
+        ("Comma", [',', ';', NONE]),
+        ("Dot", ['.', ':', NONE]),
+        ("Slash", ['-', '_', NONE]),
+    ],
+    dead: &['^', '`'],
+};
+
+/// French AZERTY
+pub static FR: Layout = Layout {
+    name: "fr",
+    keys: &[
+        ("Kb1", ['&', '1', NONE]),
+        ("Kb2", [NONE, '2', '~']),
+        ("Kb3", ['"', '3', '#']),
+        ("Kb4", ['\'', '4', '{']),
+        ("Kb5", ['(', '5', '[']),
+        ("Kb6", ['-', '6', '|']),
+        ("Kb7", [NONE, '7', '`']),
+        ("Kb8", ['_', '8', '\\']),
+        ("Kb9", [NONE, '9', '^']),
+        ("Kb0", [NONE, '0', '@']),
+        ("Minus", [')', NONE, ']']),
+        ("Equal", ['=', '+', '}']),
+        ("A", ['q', 'Q', NONE]),
+        ("Q", ['a', 'A', NONE]),
+        ("W", ['z', 'Z', NONE]),
+        ("Z", ['w', 'W', NONE]),
+        ("RBracket", ['$', NONE, NONE]),
+        ("SColon", ['m', 'M', NONE]),
+        ("Quote", [NONE, '%', NONE]),
+        ("NonUsHash", ['*', NONE, NONE]),
+        ("NonUsBslash", ['<', '>', NONE]),
+        ("M", [',', '?', NONE]),
+        ("Comma", [';', '.', NONE]),
+        ("Dot", [':', '/', NONE]),
+        ("Slash", ['!', NONE, NONE]),
+    ],
+    dead: &['~', '`'],
+};
+
+/// US Dvorak
+pub static DVORAK: Layout = Layout {
+    name: "dvorak",
+    keys: &[
+        ("Kb1", ['1', '!', NONE]),
+        ("Kb2", ['2', '@', NONE]),
+        ("Kb3", ['3', '#', NONE]),
+        ("Kb4", ['4', '$', NONE]),
+        ("Kb5", ['5', '%', NONE]),
+        ("Kb6", ['6', '^', NONE]),
+        ("Kb7", ['7', '&', NONE]),
+        ("Kb8", ['8', '*', NONE]),
+        ("Kb9", ['9', '(', NONE]),
+        ("Kb0", ['0', ')', NONE]),
+        ("Minus", ['[', '{', NONE]),
+        ("Equal", [']', '}', NONE]),
+        ("Q", ['\'', '"', NONE]),
+        ("W", [',', '<', NONE]),
+        ("E", ['.', '>', NONE]),
+        ("R", ['p', 'P', NONE]),
+        ("T", ['y', 'Y', NONE]),
+        ("Y", ['f', 'F', NONE]),
+        ("U", ['g', 'G', NONE]),
+        ("I", ['c', 'C', NONE]),
+        ("O", ['r', 'R', NONE]),
+        ("P", ['l', 'L', NONE]),
+        ("LBracket", ['/', '?', NONE]),
+        ("RBracket", ['=', '+', NONE]),
+        ("Bslash", ['\\', '|', NONE]),
+        ("S", ['o', 'O', NONE]),
+        ("D", ['e', 'E', NONE]),
+        ("F", ['u', 'U', NONE]),
+        ("G", ['i', 'I', NONE]),
+        ("H", ['d', 'D', NONE]),
+        ("J", ['h', 'H', NONE]),
+        ("K", ['t', 'T', NONE]),
+        ("L", ['n', 'N', NONE]),
+        ("SColon", ['s', 'S', NONE]),
+        ("Quote", ['-', '_', NONE]),
+        ("Grave", ['`', '~', NONE]),
+        ("Z", [';', ':', NONE]),
+        ("X", ['q', 'Q', NONE]),
+        ("C", ['j', 'J', NONE]),
+        ("V", ['k', 'K', NONE]),
+        ("B", ['x', 'X', NONE]),
+        ("N", ['b', 'B', NONE]),
+        ("Comma", ['w', 'W', NONE]),
+        ("Dot", ['v', 'V', NONE]),
+        ("Slash", ['z', 'Z', NONE]),
+    ],
+    dead: &[],
+};
+
+pub static LAYOUTS: [&Layout; 5] = [&US, &UK, &DE, &FR, &DVORAK];
+
+/// The layout called `name` in an `@layout` line
+pub fn by_name(name: &str) -> Option<&'static Layout> {
+    LAYOUTS.iter().copied().find(|layout| layout.name == name)
+}
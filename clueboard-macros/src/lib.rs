@@ -0,0 +1,606 @@
+//! Compiles `src/macros.txt` into keyberon key sequences
+//!
+//! Each line of the file is `CONST_NAME: key presses`, with the escapes described at the top of
+//! `src/macros.txt`. The firmware's build script calls `parse` and writes the macros out as Rust.
+//! Being a host crate its tests need the host target:
+//!
+//! ```text
+//! cargo test -p clueboard-macros --target x86_64-unknown-linux-gnu
+//! ```
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::layouts::{Layout, Modifier};
+
+mod keys;
+pub mod layouts;
+
+pub use keys::KEY_CODES;
+
+/// One step of a macro, a keyberon `SequenceEvent`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// Press the key with this keyberon `KeyCode` name
+    Press(String),
+    Release(String),
+    /// Wait this many milliseconds, the layout is ticked once a millisecond
+    Delay(u32),
+}
+
+/// Written as the Rust for the `SequenceEvent`
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::Press(key) => write!(f, "SequenceEvent::Press({})", key),
+            Event::Release(key) => write!(f, "SequenceEvent::Release({})", key),
+            Event::Delay(ms) => write!(f, "SequenceEvent::Delay {{ duration: {} }}", ms),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Macro {
+    pub name: String,
+    pub events: Vec<Event>,
+}
+
+/// Written as the Rust for a constant holding the macro's `Action`
+impl fmt::Display for Macro {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "const {}: Action = Action::Sequence {{ events: &[",
+            self.name
+        )?;
+        for event in &self.events {
+            write!(f, "{}, ", event)?;
+        }
+        write!(f, "] }};")
+    }
+}
+
+/// A problem with a line of the macros file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    /// Line number, from 1
+    pub line: usize,
+    /// Column in characters, from 1
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Parse the macros in `src`, or every problem with them
+pub fn parse(src: &str) -> Result<Vec<Macro>, Vec<Error>> {
+    let mut macros = Vec::new();
+    let mut errors = Vec::new();
+    // Line each macro was defined on
+    let mut names = HashMap::new();
+    // Milliseconds between keys, set by @delay lines for the macros after them
+    let mut key_delay = 0;
+    // Host layout, set by @layout lines for the macros after them
+    let mut layout = &layouts::US;
+    for (i, line) in src.lines().enumerate() {
+        let mut error = |column: usize, message: String| {
+            errors.push(Error {
+                line: i + 1,
+                column,
+                message,
+            })
+        };
+        if line.starts_with('#') {
+            continue;
+        }
+        if line.trim().is_empty() {
+            error(
+                1,
+                "blank line, start it with # to separate macros".to_string(),
+            );
+            continue;
+        }
+        if let Some(delay) = line.strip_prefix("@delay ") {
+            match delay.trim().parse() {
+                Ok(delay) => key_delay = delay,
+                Err(_) => error(8, format!("invalid delay '{}'", delay.trim())),
+            }
+            continue;
+        }
+        if let Some(name) = line.strip_prefix("@layout ") {
+            match layouts::by_name(name.trim()) {
+                Some(found) => layout = found,
+                None => {
+                    let names = layouts::LAYOUTS.iter().map(|layout| layout.name);
+                    error(
+                        9,
+                        format!(
+                            "unknown layout '{}', expected one of {}",
+                            name.trim(),
+                            names.collect::<Vec<_>>().join(", ")
+                        ),
+                    );
+                }
+            }
+            continue;
+        }
+
+        let (name, rest) = match line.split_once(':') {
+            Some(parts) => parts,
+            None => {
+                error(
+                    line.chars().count() + 1,
+                    "missing ':', expected CONST_NAME: key presses".to_string(),
+                );
+                continue;
+            }
+        };
+        if !is_identifier(name) {
+            error(1, format!("'{}' is not a valid constant name", name));
+        } else if let Some(first) = names.get(name) {
+            error(1, format!("{} is already defined on line {}", name, first));
+        } else {
+            names.insert(name, i + 1);
+        }
+        // Skip space in between : and definition
+        let keys = rest.strip_prefix(' ').unwrap_or(rest);
+        let keys_column = line.chars().count() - keys.chars().count() + 1;
+
+        match parse_keys(keys, layout, key_delay) {
+            Ok(events) => macros.push(Macro {
+                name: name.to_string(),
+                events,
+            }),
+            Err(key_errors) => {
+                for (column, message) in key_errors {
+                    error(keys_column + column, message);
+                }
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(macros)
+    } else {
+        Err(errors)
+    }
+}
+
+/// The events that type `keys` on `layout`, with `key_delay` milliseconds between each key.
+/// Errors are given with the column they're at, counting from 0.
+pub fn parse_keys(
+    keys: &str,
+    layout: &Layout,
+    key_delay: u32,
+) -> Result<Vec<Event>, Vec<(usize, String)>> {
+    // The events of each key typed, or delay
+    let mut steps = Vec::new();
+    let mut errors = Vec::new();
+    let mut chars = keys.chars().enumerate().peekable();
+    while let Some((column, ch)) = chars.next() {
+        match ch {
+            // {{ and }} type a brace
+            '{' | '}' if chars.next_if(|&(_, next)| next == ch).is_some() => {
+                steps.extend(char_events(ch, layout))
+            }
+            '{' => {
+                let escape = chars
+                    .by_ref()
+                    .map(|(_, ch)| ch)
+                    .take_while(|&ch| ch != '}')
+                    .collect::<String>();
+                if !keys.chars().skip(column).any(|ch| ch == '}') {
+                    errors.push((column, "unclosed {, use {{ to type {".to_string()));
+                } else if let Some(delay) = escape.strip_prefix("DELAY ") {
+                    match delay.trim().parse() {
+                        Ok(delay) => steps.push(vec![Event::Delay(delay)]),
+                        Err(_) => errors.push((column, format!("invalid delay {{{}}}", escape))),
+                    }
+                } else {
+                    match escape
+                        .split('+')
+                        .map(|name| keys::parse_name(name, layout))
+                        .collect()
+                    {
+                        Ok(keys) => steps.push(chord(keys)),
+                        Err(err) => {
+                            errors.push((column, format!("invalid key {{{}}}: {}", escape, err)))
+                        }
+                    }
+                }
+            }
+            '}' => errors.push((column, "unmatched }, use }} to type }".to_string())),
+            _ => match char_events(ch, layout) {
+                Some(events) => steps.push(events),
+                None => errors.push((
+                    column,
+                    format!("{:?} can't be typed on the {} layout", ch, layout.name),
+                )),
+            },
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let mut events = Vec::new();
+    for (i, step) in steps.into_iter().enumerate() {
+        if i > 0 && key_delay != 0 {
+            events.push(Event::Delay(key_delay));
+        }
+        events.extend(step);
+    }
+    Ok(events)
+}
+
+/// The macro text that types `events` on `layout`, the inverse of `parse_keys` for keys it could
+/// produce
+pub fn decode(events: &[Event], layout: &Layout) -> String {
+    let mut text = String::new();
+    let mut held = Vec::new();
+    // Whether the keys held have been typed, they are once the first of them is released
+    let mut typed = false;
+    // Whether the last key was a dead key, so the space after it is part of the same character
+    let mut dead = false;
+    for event in events {
+        match event {
+            Event::Press(key) => {
+                held.push(key.as_str());
+                typed = false;
+            }
+            Event::Release(key) => {
+                if !typed {
+                    if dead && held == ["Space"] {
+                        dead = false;
+                    } else {
+                        let (keys, is_dead) = describe(&held, layout);
+                        text.push_str(&keys);
+                        dead = is_dead;
+                    }
+                    typed = true;
+                }
+                held.retain(|held| held != key);
+            }
+            Event::Delay(ms) => text.push_str(&format!("{{DELAY {}}}", ms)),
+        }
+    }
+    text
+}
+
+/// The events that type `ch` on `layout`, if there's a key for it
+fn char_events(ch: char, layout: &Layout) -> Option<Vec<Event>> {
+    let stroke = layout.stroke(ch)?;
+    let key = stroke.key.to_string();
+    let mut events = match stroke.modifier {
+        Modifier::None => chord(vec![key]),
+        Modifier::Shift => chord(vec!["LShift".to_string(), key]),
+        Modifier::AltGr => chord(vec!["RAlt".to_string(), key]),
+    };
+    if stroke.dead {
+        events.extend(chord(vec!["Space".to_string()]));
+    }
+    Some(events)
+}
+
+/// Events that press `keys` in order, then release them the other way round
+fn chord(keys: Vec<String>) -> Vec<Event> {
+    let releases = keys
+        .iter()
+        .rev()
+        .cloned()
+        .map(Event::Release)
+        .collect::<Vec<_>>();
+    keys.into_iter().map(Event::Press).chain(releases).collect()
+}
+
+/// The macro text for pressing the keys in `held` together on `layout`, and whether it's a dead
+/// key
+fn describe(held: &[&str], layout: &Layout) -> (String, bool) {
+    let ch = match *held {
+        [key] => layout.char(key, Modifier::None),
+        ["LShift", key] => layout.char(key, Modifier::Shift),
+        ["RAlt", key] => layout.char(key, Modifier::AltGr),
+        _ => None,
+    };
+    match ch {
+        Some(ch @ '{') | Some(ch @ '}') => (format!("{}{}", ch, ch), layout.is_dead(ch)),
+        Some(ch) => (ch.to_string(), layout.is_dead(ch)),
+        None => {
+            let names = held
+                .iter()
+                .map(|key| keys::name(key, layout))
+                .collect::<Vec<_>>();
+            (format!("{{{}}}", names.join("+")), false)
+        }
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(ch) if ch.is_ascii_alphabetic() || ch == '_')
+        && chars.all(|ch| ch.is_ascii_alphanumeric() || ch == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layouts::{DE, DVORAK, FR, LAYOUTS, UK, US};
+
+    fn press(key: &str) -> Event {
+        Event::Press(key.to_string())
+    }
+
+    fn release(key: &str) -> Event {
+        Event::Release(key.to_string())
+    }
+
+    /// `ch` as it's written in a macro
+    fn escape(ch: char) -> String {
+        match ch {
+            '{' | '}' => format!("{}{}", ch, ch),
+            _ => ch.to_string(),
+        }
+    }
+
+    /// Deterministic pseudo random numbers, xorshift
+    struct Random(u32);
+
+    impl Random {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0 as usize % n
+        }
+    }
+
+    #[test]
+    fn every_printable_character_is_typed() {
+        for ch in ' '..='~' {
+            let events = parse_keys(&escape(ch), &US, 0).unwrap();
+            let (key, modifier) = match events.as_slice() {
+                [Event::Press(key), Event::Release(released)] if key == released => {
+                    (key, Modifier::None)
+                }
+                [Event::Press(shift), Event::Press(key), Event::Release(released), Event::Release(unshift)]
+                    if shift == "LShift" && unshift == "LShift" && key == released =>
+                {
+                    (key, Modifier::Shift)
+                }
+                events => panic!("{:?} typed as {:?}", ch, events),
+            };
+            let shifted = ch.is_ascii_uppercase() || "~!@#$%^&*()_+{}|:\"<>?".contains(ch);
+            assert_eq!(modifier == Modifier::Shift, shifted, "{:?}", ch);
+            assert_eq!(US.char(key, modifier), Some(ch));
+        }
+    }
+
+    #[test]
+    fn every_printable_character_round_trips() {
+        for layout in LAYOUTS.iter() {
+            for ch in ' '..='~' {
+                let text = escape(ch);
+                let events = parse_keys(&text, layout, 0)
+                    .unwrap_or_else(|err| panic!("{} layout: {:?}", layout.name, err));
+                assert_eq!(decode(&events, layout), text, "{} layout", layout.name);
+            }
+        }
+    }
+
+    #[test]
+    fn characters_use_the_us_keys() {
+        let cases = [
+            ('a', "A", Modifier::None),
+            ('A', "A", Modifier::Shift),
+            ('0', "Kb0", Modifier::None),
+            ('!', "Kb1", Modifier::Shift),
+            (' ', "Space", Modifier::None),
+            ('\'', "Quote", Modifier::None),
+            ('"', "Quote", Modifier::Shift),
+            ('{', "LBracket", Modifier::Shift),
+            ('\\', "Bslash", Modifier::None),
+        ];
+        for &(ch, key, modifier) in cases.iter() {
+            let stroke = US.stroke(ch).unwrap();
+            assert_eq!((stroke.key, stroke.modifier), (key, modifier), "{:?}", ch);
+        }
+    }
+
+    #[test]
+    fn other_layouts_use_their_own_keys() {
+        let cases = [
+            (&UK, '"', "Kb2", Modifier::Shift),
+            (&UK, '#', "NonUsHash", Modifier::None),
+            (&DE, 'z', "Y", Modifier::None),
+            (&DE, '@', "Q", Modifier::AltGr),
+            (&DE, '/', "Kb7", Modifier::Shift),
+            (&FR, 'a', "Q", Modifier::None),
+            (&FR, '1', "Kb1", Modifier::Shift),
+            (&FR, 'm', "SColon", Modifier::None),
+            (&DVORAK, 's', "SColon", Modifier::None),
+            (&DVORAK, '[', "Minus", Modifier::None),
+        ];
+        for &(layout, ch, key, modifier) in cases.iter() {
+            let stroke = layout.stroke(ch).unwrap();
+            assert_eq!(
+                (stroke.key, stroke.modifier),
+                (key, modifier),
+                "{:?} on {}",
+                ch,
+                layout.name
+            );
+        }
+    }
+
+    #[test]
+    fn dead_keys_are_followed_by_a_space() {
+        assert_eq!(
+            parse_keys("^a", &DE, 0).unwrap(),
+            [
+                press("Grave"),
+                release("Grave"),
+                press("Space"),
+                release("Space"),
+                press("A"),
+                release("A"),
+            ]
+        );
+        assert_eq!(
+            parse_keys("~", &FR, 0).unwrap(),
+            [
+                press("RAlt"),
+                press("Kb2"),
+                release("Kb2"),
+                release("RAlt"),
+                press("Space"),
+                release("Space"),
+            ]
+        );
+    }
+
+    #[test]
+    fn random_text_round_trips() {
+        const ESCAPES: [&str; 7] = [
+            "{ENTER}",
+            "{TAB}",
+            "{F5}",
+            "{CTRL+ALT+T}",
+            "{SHIFT+TAB}",
+            "{GUI+UP}",
+            "{DELAY 20}",
+        ];
+        let mut random = Random(0x1234_5678);
+        for layout in LAYOUTS.iter() {
+            for _ in 0..1000 {
+                let mut text = String::new();
+                for _ in 0..random.below(20) {
+                    if random.below(10) == 0 {
+                        text.push_str(ESCAPES[random.below(ESCAPES.len())]);
+                    } else {
+                        let ch = (b' ' + random.below(95) as u8) as char;
+                        text.push_str(&escape(ch));
+                    }
+                }
+                let events = parse_keys(&text, layout, 0).unwrap();
+                assert_eq!(decode(&events, layout), text, "{} layout", layout.name);
+            }
+        }
+    }
+
+    #[test]
+    fn layout_lines_apply_to_later_macros() {
+        let macros = parse("US: z\n@layout de\nDE: z{CTRL+z}").unwrap();
+        assert_eq!(macros[0].events, [press("Z"), release("Z")]);
+        assert_eq!(
+            macros[1].events,
+            [
+                press("Y"),
+                release("Y"),
+                press("LCtrl"),
+                press("Y"),
+                release("Y"),
+                release("LCtrl"),
+            ]
+        );
+    }
+
+    #[test]
+    fn chords_are_released_in_reverse() {
+        assert_eq!(
+            parse_keys("{CTRL+ALT+t}", &US, 0).unwrap(),
+            [
+                press("LCtrl"),
+                press("LAlt"),
+                press("T"),
+                release("T"),
+                release("LAlt"),
+                release("LCtrl"),
+            ]
+        );
+    }
+
+    #[test]
+    fn key_delay_goes_between_keys() {
+        assert_eq!(
+            parse_keys("a{ENTER}", &US, 15).unwrap(),
+            [
+                press("A"),
+                release("A"),
+                Event::Delay(15),
+                press("Enter"),
+                release("Enter"),
+            ]
+        );
+
+        let macros = parse("A: a\n@delay 5\nB: bc\n@delay 0\nC: de").unwrap();
+        assert!(!macros[0].events.contains(&Event::Delay(5)));
+        assert!(macros[1].events.contains(&Event::Delay(5)));
+        assert!(!macros[2].events.contains(&Event::Delay(5)));
+    }
+
+    #[test]
+    fn macros_are_written_as_rust() {
+        let macros = parse("# comment\nHI: h{DELAY 10}").unwrap();
+        assert_eq!(
+            macros[0].to_string(),
+            "const HI: Action = Action::Sequence { events: &[SequenceEvent::Press(H), \
+             SequenceEvent::Release(H), SequenceEvent::Delay { duration: 10 }, ] };"
+        );
+    }
+
+    #[test]
+    fn every_error_is_reported() {
+        let src = "\
+A: ok
+no colon
+9BAD: x
+A: again
+
+B: caf\u{e9} {nope+x} {DELAY soon} a}b {PRINTSCREEN}
+@delay soon
+C: {ENTER
+@layout xx";
+        let errors = parse(src)
+            .unwrap_err()
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            [
+                "2:9: missing ':', expected CONST_NAME: key presses",
+                "3:1: '9BAD' is not a valid constant name",
+                "4:1: A is already defined on line 1",
+                "5:1: blank line, start it with # to separate macros",
+                "6:7: '\u{e9}' can't be typed on the us layout",
+                "6:9: invalid key {nope+x}: unknown key 'nope'",
+                "6:18: invalid delay {DELAY soon}",
+                "6:32: unmatched }, use }} to type }",
+                "6:35: invalid key {PRINTSCREEN}: unknown key 'PRINTSCREEN'",
+                "7:8: invalid delay 'soon'",
+                "8:4: unclosed {, use {{ to type {",
+                "9:9: unknown layout 'xx', expected one of us, uk, de, fr, dvorak",
+            ]
+        );
+    }
+
+    #[test]
+    fn shifted_characters_cant_be_chorded() {
+        let errors = parse_keys("{CTRL+!}", &US, 0).unwrap_err();
+        assert_eq!(
+            errors,
+            [(
+                0,
+                "invalid key {CTRL+!}: '!' isn't on a key of its own on the us layout, use the \
+                 key's name"
+                    .to_string()
+            )]
+        );
+    }
+}
@@ -0,0 +1,8 @@
+/* STM32F303CC: 256K of flash and 40K of RAM, plus 8K of CCM RAM that isn't used */
+MEMORY
+{
+  /* The last two 2K pages are the settings store (src/flash.rs), leaving them out makes linking
+     fail if the firmware ever grows into them instead of the store erasing code */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 256K - 4K
+  RAM : ORIGIN = 0x20000000, LENGTH = 40K
+}
@@ -0,0 +1,182 @@
+//! Per-key backlight
+//!
+//! Keys are addressed by their matrix position, the same row and column used by the `layer!`
+//! macro in `layout.rs`. `LED_MAP` translates that into the IS31FL3731 LED index.
+
+use clueboard_core::{KeyFrame, COLS, ROWS};
+
+use crate::is31fl3731::LED_COUNT;
+
+/// Number of brightness steps above off that `BL_INC`, `BL_DEC` and `BL_STEP` move through
+pub const LEVELS: u8 = 8;
+/// Brightness level after power on
+pub const DEFAULT_LEVEL: u8 = LEVELS / 2;
+/// How far the brightness moves towards its target each tick, a full fade takes 255 ticks
+const FADE_STEP: u8 = 1;
+
+/* LED index of each matrix position
+ *
+ * The LEDs are wired in keyboard order, left to right, top to bottom:
+ * ,-----------------------------------------------------------.  ,---.
+ * |  0|  1|  2|  3|  4|  5|  6|  7|  8|  9| 10| 11| 12|     13|  | 14|
+ * |-----------------------------------------------------------|  |---|
+ * |   15| 16| 17| 18| 19| 20| 21| 22| 23| 24| 25| 26| 27|   28|  | 29|
+ * |-----------------------------------------------------------|  `---'
+ * |    30| 31| 32| 33| 34| 35| 36| 37| 38| 39| 40| 41|      42|
+ * |--------------------------------------------------------------.
+ * |  43    | 44| 45| 46| 47| 48| 49| 50| 51| 52| 53|      54  |55|
+ * |------------------------------------------------------------------.
+ * |  56| 57|  58|        59|       60|   61|  62|  63|  64| 65|66| 67|
+ * `------------------------------------------------------------------'
+ */
+#[rustfmt::skip]
+const LED_MAP: [[Option<u8>; COLS]; ROWS] = [
+    [Some(0),  Some(1),  Some(2),  Some(3),  Some(4),  Some(5),  Some(6),  Some(7)],
+    [Some(15), Some(16), Some(17), Some(18), Some(19), Some(20), Some(21), Some(22)],
+    [Some(30), Some(31), Some(32), Some(33), Some(34), Some(35), Some(36), Some(37)],
+    [Some(43), None,     Some(44), Some(45), Some(46), Some(47), Some(48), Some(49)],
+    [Some(56), Some(57), Some(58), None,     None,     Some(59), Some(60), None],
+    [Some(8),  Some(9),  Some(10), Some(11), Some(12), Some(13), None,     Some(14)],
+    [Some(23), Some(24), Some(25), Some(26), Some(27), Some(28), None,     Some(29)],
+    [Some(38), Some(39), Some(40), Some(41), None,     Some(42), None,     None],
+    [Some(50), Some(51), Some(52), Some(53), None,     Some(54), Some(55), None],
+    [Some(61), None,     Some(62), Some(63), Some(64), Some(65), Some(66), Some(67)],
+];
+
+/// The IS31FL3731 LED index of the switch at `row`, `col` in the matrix
+///
+/// Returns `None` for matrix positions that don't have a switch.
+pub fn led_index(row: usize, col: usize) -> Option<usize> {
+    LED_MAP
+        .get(row)
+        .and_then(|leds| leds.get(col))
+        .and_then(|&led| led)
+        .map(usize::from)
+}
+
+/// Brightness of every LED, ready to be written to the controller
+#[derive(Clone, PartialEq)]
+pub struct Frame {
+    pwm: [u8; LED_COUNT],
+}
+
+impl Frame {
+    pub const fn new() -> Self {
+        Frame {
+            pwm: [0; LED_COUNT],
+        }
+    }
+
+    /// Set the brightness of the switch at `row`, `col` in the matrix
+    pub fn set_key(&mut self, row: usize, col: usize, brightness: u8) {
+        if let Some(led) = led_index(row, col) {
+            self.pwm[led] = brightness;
+        }
+    }
+
+    /// Draw the brightness of each switch from `keys`, scaled by the overall `brightness`
+    pub fn draw(&mut self, keys: &KeyFrame, brightness: u8) {
+        for (row, keys) in keys.iter().enumerate() {
+            for (col, &key) in keys.iter().enumerate() {
+                let scaled = u16::from(key) * u16::from(brightness) / 255;
+                self.set_key(row, col, scaled as u8);
+            }
+        }
+    }
+
+    pub fn pwm(&self) -> &[u8; LED_COUNT] {
+        &self.pwm
+    }
+}
+
+impl Default for Frame {
+    fn default() -> Self {
+        Frame::new()
+    }
+}
+
+/// Backlight adjustments that can be bound in the layout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BacklightAction {
+    /// Turn the backlight up one level
+    Increase,
+    /// Turn the backlight down one level
+    Decrease,
+    /// Turn the backlight on or off, keeping the level
+    Toggle,
+    /// Cycle through the levels, wrapping around to off after the brightest
+    Step,
+}
+
+/// Overall backlight brightness, fading between levels
+pub struct Backlight {
+    level: u8,
+    enabled: bool,
+    brightness: u8,
+}
+
+impl Backlight {
+    /// Start dark and fade in to `level`, if `enabled`
+    pub const fn new(level: u8, enabled: bool) -> Self {
+        Backlight {
+            level,
+            enabled,
+            brightness: 0,
+        }
+    }
+
+    pub fn apply(&mut self, action: BacklightAction) {
+        match action {
+            BacklightAction::Increase => {
+                self.level = (self.level + 1).min(LEVELS);
+                self.enabled = true;
+            }
+            BacklightAction::Decrease => {
+                self.level = self.level.saturating_sub(1);
+                self.enabled = self.level != 0;
+            }
+            BacklightAction::Toggle => {
+                self.enabled = !self.enabled;
+                if self.enabled && self.level == 0 {
+                    self.level = LEVELS;
+                }
+            }
+            BacklightAction::Step => {
+                self.level = (self.level + 1) % (LEVELS + 1);
+                self.enabled = self.level != 0;
+            }
+        }
+    }
+
+    pub fn level(&self) -> u8 {
+        self.level
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// The brightness currently being faded towards
+    pub fn target(&self) -> u8 {
+        if self.enabled {
+            (u16::from(self.level) * 255 / u16::from(LEVELS)) as u8
+        } else {
+            0
+        }
+    }
+
+    /// Move the brightness one step towards the target
+    pub fn tick(&mut self) {
+        let target = self.target();
+        if self.brightness < target {
+            self.brightness = self.brightness.saturating_add(FADE_STEP).min(target);
+        } else if self.brightness > target {
+            self.brightness = self.brightness.saturating_sub(FADE_STEP).max(target);
+        }
+    }
+
+    /// The current, possibly mid-fade, brightness
+    pub fn brightness(&self) -> u8 {
+        self.brightness
+    }
+}
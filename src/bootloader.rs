@@ -0,0 +1,66 @@
+//! Entering the STM32F303 system memory DFU bootloader from the firmware
+//!
+//! Jumping straight into the bootloader from a running firmware leaves clocks and peripherals
+//! configured in ways it doesn't expect. Instead a magic value is left in an RTC backup register,
+//! which survives a reset, and the chip is reset. `jump_if_requested` is called first thing in
+//! `init` and jumps to the bootloader while everything is still in its reset state.
+
+use stm32f3xx_hal::pac;
+
+/// Start of system memory, where the bootloader's vector table is
+const SYSTEM_MEMORY: u32 = 0x1FFF_D800;
+/// Written to the backup register to request the bootloader, "BOOT"
+const MAGIC: u32 = 0x424F_4F54;
+/// How long D+ is held low so the host sees the keyboard disconnect, 10ms at 48MHz
+const DISCONNECT_CYCLES: u32 = 48_000_000 / 100;
+
+/// Disconnect from USB and reset into the bootloader
+pub fn reboot_to_bootloader() -> ! {
+    cortex_m::interrupt::disable();
+
+    // NOTE(unsafe) interrupts are disabled and nothing returns from here, so no driver will see
+    // the changes to the registers below
+    unsafe {
+        // Power down the USB peripheral and hold D+ (PA12) low so the host notices the keyboard
+        // has gone away before the bootloader connects
+        let usb = &*pac::USB::ptr();
+        usb.cntr.write(|w| w.fres().set_bit().pdwn().set_bit());
+        let gpioa = &*pac::GPIOA::ptr();
+        gpioa.bsrr.write(|w| w.br12().set_bit());
+        gpioa.moder.modify(|_, w| w.moder12().output());
+        cortex_m::asm::delay(DISCONNECT_CYCLES);
+
+        write_backup_register(MAGIC);
+    }
+
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+/// Jump to the bootloader if `reboot_to_bootloader` asked for it
+///
+/// This has to be called before any clocks or peripherals are set up.
+pub fn jump_if_requested() {
+    // NOTE(unsafe) called before anything else has been initialised
+    unsafe {
+        if (*pac::RTC::ptr()).bkp0r.read().bits() != MAGIC {
+            return;
+        }
+
+        // Clear the request so the next reset starts the firmware again
+        write_backup_register(0);
+        cortex_m::asm::bootload(SYSTEM_MEMORY as *const u32)
+    }
+}
+
+unsafe fn write_backup_register(value: u32) {
+    let rcc = &*pac::RCC::ptr();
+    let pwr = &*pac::PWR::ptr();
+    let rtc = &*pac::RTC::ptr();
+
+    // The backup domain is write protected until DBP is set
+    rcc.apb1enr.modify(|_, w| w.pwren().set_bit());
+    pwr.cr.modify(|_, w| w.dbp().set_bit());
+    rtc.bkp0r.write(|w| w.bits(value));
+    pwr.cr.modify(|_, w| w.dbp().clear_bit());
+    rcc.apb1enr.modify(|_, w| w.pwren().clear_bit());
+}
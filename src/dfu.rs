@@ -0,0 +1,118 @@
+//! USB DFU runtime interface
+//!
+//! Advertises that the keyboard can be switched into DFU mode from the host. When `dfu-util -e`
+//! sends a DFU_DETACH request the keyboard reboots into the STM32 bootloader, which then
+//! enumerates as the DFU device that does the actual flashing. Detaching can be refused, as it
+//! is while Vial is locked.
+//!
+//! https://www.usb.org/sites/default/files/DFU_1.1.pdf
+
+use usb_device::class_prelude::*;
+use usb_device::control::{Recipient, RequestType};
+
+use clueboard_core::dfu::Detach;
+
+const CLASS_APPLICATION_SPECIFIC: u8 = 0xFE;
+const SUBCLASS_DFU: u8 = 0x01;
+const PROTOCOL_RUNTIME: u8 = 0x01;
+const DESCRIPTOR_DFU_FUNCTIONAL: u8 = 0x21;
+
+const REQUEST_DETACH: u8 = 0x00;
+const REQUEST_GET_STATUS: u8 = 0x03;
+const REQUEST_GET_STATE: u8 = 0x05;
+
+/// The device detaches itself rather than waiting for a USB reset, and can download
+const ATTRIBUTES: u8 = 0x08 | 0x01;
+/// Longest the host should wait for the detach, in milliseconds
+const DETACH_TIMEOUT: u16 = 1000;
+/// Largest download block, as used by the STM32 bootloader
+const TRANSFER_SIZE: u16 = 2048;
+const DFU_VERSION: u16 = 0x011A;
+/// The appIDLE state, the only one a runtime interface is ever in
+const STATE_APP_IDLE: u8 = 0;
+
+pub struct DfuRuntime {
+    interface: InterfaceNumber,
+    detach: Detach,
+}
+
+impl DfuRuntime {
+    pub fn new<B: UsbBus>(alloc: &UsbBusAllocator<B>) -> Self {
+        DfuRuntime {
+            interface: alloc.interface(),
+            detach: Detach::new(),
+        }
+    }
+
+    /// Accept or refuse detach requests from now on, refusing drops one already accepted
+    pub fn set_allowed(&mut self, allowed: bool) {
+        self.detach.set_allowed(allowed)
+    }
+
+    /// Advance time by one tick, returns true when it's time to reboot into the bootloader
+    pub fn tick(&mut self) -> bool {
+        self.detach.tick()
+    }
+
+    fn is_for_us(&self, request: &control::Request) -> bool {
+        request.request_type == RequestType::Class
+            && request.recipient == Recipient::Interface
+            && request.index == u16::from(u8::from(self.interface))
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for DfuRuntime {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface(
+            self.interface,
+            CLASS_APPLICATION_SPECIFIC,
+            SUBCLASS_DFU,
+            PROTOCOL_RUNTIME,
+        )?;
+        let [timeout_lo, timeout_hi] = DETACH_TIMEOUT.to_le_bytes();
+        let [size_lo, size_hi] = TRANSFER_SIZE.to_le_bytes();
+        let [version_lo, version_hi] = DFU_VERSION.to_le_bytes();
+        writer.write(
+            DESCRIPTOR_DFU_FUNCTIONAL,
+            &[
+                ATTRIBUTES, timeout_lo, timeout_hi, size_lo, size_hi, version_lo, version_hi,
+            ],
+        )
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        if !self.is_for_us(xfer.request()) {
+            return;
+        }
+
+        match xfer.request().request {
+            REQUEST_DETACH if self.detach.is_allowed() => {
+                if xfer.accept().is_ok() {
+                    self.detach.start();
+                }
+            }
+            _ => {
+                xfer.reject().ok();
+            }
+        }
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        if !self.is_for_us(xfer.request()) {
+            return;
+        }
+
+        match xfer.request().request {
+            REQUEST_GET_STATUS => {
+                // bStatus OK, bwPollTimeout 0, bState, iString
+                xfer.accept_with(&[0, 0, 0, 0, STATE_APP_IDLE, 0]).ok();
+            }
+            REQUEST_GET_STATE => {
+                xfer.accept_with(&[STATE_APP_IDLE]).ok();
+            }
+            _ => {
+                xfer.reject().ok();
+            }
+        }
+    }
+}
@@ -0,0 +1,122 @@
+//! The STM32F303's internal flash pages set aside for settings
+//!
+//! The last two 2K pages of the 256K flash hold the settings store. memory.x leaves them out of
+//! the flash the firmware is linked into, so it can't grow into them.
+//!
+//! The HAL only exposes the flash access control register, so erasing and programming go straight
+//! to the peripheral. Code runs from flash, so the CPU stalls while a page is erased or written.
+
+use clueboard_core::store::Flash;
+use stm32f3xx_hal::pac;
+
+/// Address of the first of the two pages
+const START: usize = 0x0803_F000;
+const PAGE_SIZE: usize = 2048;
+
+/// Written to the key register in turn to unlock the flash control register
+const KEY1: u32 = 0x4567_0123;
+const KEY2: u32 = 0xCDEF_89AB;
+
+// Status register bits
+const BSY: u32 = 1 << 0;
+const PGERR: u32 = 1 << 2;
+const WRPRTERR: u32 = 1 << 4;
+const EOP: u32 = 1 << 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The flash being written wasn't erased
+    Programming,
+    WriteProtected,
+}
+
+pub struct InternalFlash {
+    _private: (),
+}
+
+impl InternalFlash {
+    /// The settings pages
+    ///
+    /// # Safety
+    ///
+    /// Only one of these can exist, and nothing else can be erasing or programming flash.
+    pub unsafe fn new() -> Self {
+        InternalFlash { _private: () }
+    }
+
+    /// Unlock the control register and run `f`, locking it again afterwards
+    fn unlocked<T>(&mut self, f: impl FnOnce(&pac::flash::RegisterBlock) -> T) -> T {
+        // NOTE(unsafe) InternalFlash is the only thing using the control and status registers,
+        // the HAL only uses ACR
+        let flash = unsafe { &*pac::FLASH::ptr() };
+        if flash.cr.read().lock().bit_is_set() {
+            flash.keyr.write(|w| unsafe { w.bits(KEY1) });
+            flash.keyr.write(|w| unsafe { w.bits(KEY2) });
+        }
+        let result = f(flash);
+        flash.cr.modify(|_, w| w.lock().set_bit());
+        result
+    }
+}
+
+impl Flash for InternalFlash {
+    type Error = Error;
+
+    const PAGE_SIZE: usize = PAGE_SIZE;
+
+    fn read(&self, page: usize, offset: usize, buf: &mut [u8]) {
+        let address = START + page * PAGE_SIZE + offset;
+        // NOTE(unsafe) the pages are always mapped and readable
+        let data = unsafe { core::slice::from_raw_parts(address as *const u8, buf.len()) };
+        buf.copy_from_slice(data);
+    }
+
+    fn erase(&mut self, page: usize) -> Result<(), Error> {
+        let address = START + page * PAGE_SIZE;
+        self.unlocked(|flash| {
+            flash.cr.modify(|_, w| w.per().set_bit());
+            flash.ar.write(|w| unsafe { w.bits(address as u32) });
+            flash.cr.modify(|_, w| w.strt().set_bit());
+            let result = wait(flash);
+            flash.cr.modify(|_, w| w.per().clear_bit());
+            result
+        })
+    }
+
+    fn write(&mut self, page: usize, offset: usize, data: &[u8]) -> Result<(), Error> {
+        let address = START + page * PAGE_SIZE + offset;
+        self.unlocked(|flash| {
+            // Flash is programmed a half-word at a time
+            flash.cr.modify(|_, w| w.pg().set_bit());
+            let mut result = Ok(());
+            for (i, half_word) in data.chunks_exact(2).enumerate() {
+                let value = u16::from_le_bytes([half_word[0], half_word[1]]);
+                // NOTE(unsafe) the address is within the settings pages and half-word aligned
+                unsafe { core::ptr::write_volatile((address + i * 2) as *mut u16, value) };
+                result = wait(flash);
+                if result.is_err() {
+                    break;
+                }
+            }
+            flash.cr.modify(|_, w| w.pg().clear_bit());
+            result
+        })
+    }
+}
+
+/// Wait for the current operation to finish and check how it went
+fn wait(flash: &pac::flash::RegisterBlock) -> Result<(), Error> {
+    while flash.sr.read().bits() & BSY != 0 {}
+    let status = flash.sr.read().bits();
+    // The flags are cleared by writing 1 to them
+    flash
+        .sr
+        .write(|w| unsafe { w.bits(status & (PGERR | WRPRTERR | EOP)) });
+    if status & WRPRTERR != 0 {
+        Err(Error::WriteProtected)
+    } else if status & PGERR != 0 {
+        Err(Error::Programming)
+    } else {
+        Ok(())
+    }
+}
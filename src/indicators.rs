@@ -0,0 +1,176 @@
+//! Caps Lock, Num Lock and friends
+//!
+//! The host tells the keyboard the state of its lock keys through HID output reports. `HostLeds`
+//! records that state as keyberon receives it and the matrix scan hands it on to whatever
+//! `LockIndicators` says should show it.
+
+use clueboard_core::audio::{Note, Player};
+
+use crate::backlight::Frame;
+
+/// Brightness of a lit indicator key, the same whatever the backlight is doing so a lock can be
+/// seen with the backlight off
+const BRIGHTNESS: u8 = 255;
+
+/// State of the host's lock keys
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LockState {
+    pub num_lock: bool,
+    pub caps_lock: bool,
+    pub scroll_lock: bool,
+    pub compose: bool,
+    pub kana: bool,
+}
+
+/// Receives the lock state from the host
+#[derive(Default)]
+pub struct HostLeds {
+    state: LockState,
+}
+
+impl HostLeds {
+    pub fn state(&self) -> LockState {
+        self.state
+    }
+}
+
+impl keyberon::keyboard::Leds for HostLeds {
+    fn num_lock(&mut self, status: bool) {
+        self.state.num_lock = status;
+    }
+
+    fn caps_lock(&mut self, status: bool) {
+        self.state.caps_lock = status;
+    }
+
+    fn scroll_lock(&mut self, status: bool) {
+        self.state.scroll_lock = status;
+    }
+
+    fn compose(&mut self, status: bool) {
+        self.state.compose = status;
+    }
+
+    fn kana(&mut self, status: bool) {
+        self.state.kana = status;
+    }
+}
+
+/// Where the state of a lock is shown
+#[derive(Clone, Copy)]
+pub enum LockIndicator {
+    /// Light the switch at this matrix row and column while the lock is on
+    Key(usize, usize),
+    /// Play `on` when the lock turns on and `off` when it turns off
+    Song {
+        on: &'static [Note],
+        off: &'static [Note],
+    },
+    /// Called with the new state each time the lock changes
+    Hook(fn(bool)),
+}
+
+/// The indicators for each lock, a lock can be shown in several ways or not at all
+pub struct LockIndicators {
+    pub num_lock: &'static [LockIndicator],
+    pub caps_lock: &'static [LockIndicator],
+    pub scroll_lock: &'static [LockIndicator],
+    pub compose: &'static [LockIndicator],
+    pub kana: &'static [LockIndicator],
+}
+
+impl LockIndicators {
+    /// Play the songs and run the hooks of any locks that differ between `previous` and
+    /// `current`
+    pub fn changed(&self, previous: LockState, current: LockState, player: &mut Player) {
+        let previous = self.with_state(previous);
+        let current = self.with_state(current);
+        for (&(indicators, was), &(_, now)) in previous.iter().zip(current.iter()) {
+            if was == now {
+                continue;
+            }
+            for indicator in indicators {
+                match *indicator {
+                    LockIndicator::Key(..) => {}
+                    LockIndicator::Song { on, off } => player.play_song(if now { on } else { off }),
+                    LockIndicator::Hook(hook) => hook(now),
+                }
+            }
+        }
+    }
+
+    /// Light the keys of the locks that are on, over the top of the backlight already in `frame`
+    pub fn draw(&self, state: LockState, frame: &mut Frame) {
+        for &(indicators, on) in self.with_state(state).iter() {
+            for indicator in indicators {
+                if let (&LockIndicator::Key(row, col), true) = (indicator, on) {
+                    frame.set_key(row, col, BRIGHTNESS);
+                }
+            }
+        }
+    }
+
+    fn with_state(&self, state: LockState) -> [(&'static [LockIndicator], bool); 5] {
+        [
+            (self.num_lock, state.num_lock),
+            (self.caps_lock, state.caps_lock),
+            (self.scroll_lock, state.scroll_lock),
+            (self.compose, state.compose),
+            (self.kana, state.kana),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    use clueboard_core::{COLS, ROWS};
+
+    static HOOKED: AtomicBool = AtomicBool::new(false);
+
+    fn hook(on: bool) {
+        HOOKED.store(on, Ordering::SeqCst);
+    }
+
+    static INDICATORS: LockIndicators = LockIndicators {
+        num_lock: &[LockIndicator::Hook(hook)],
+        caps_lock: &[LockIndicator::Key(2, 0)],
+        scroll_lock: &[],
+        compose: &[],
+        kana: &[],
+    };
+
+    #[test]
+    fn indicator_keys_show_with_the_backlight_off() {
+        let caps = LockState {
+            caps_lock: true,
+            ..LockState::default()
+        };
+        let mut lit = Frame::new();
+        lit.draw(&[[0; COLS]; ROWS], 0);
+        INDICATORS.draw(caps, &mut lit);
+
+        let mut expected = Frame::new();
+        expected.set_key(2, 0, BRIGHTNESS);
+        assert!(lit == expected);
+
+        let mut unlit = Frame::new();
+        INDICATORS.draw(LockState::default(), &mut unlit);
+        assert!(unlit == Frame::new());
+    }
+
+    #[test]
+    fn hooks_are_called_when_the_lock_changes() {
+        let num = LockState {
+            num_lock: true,
+            ..LockState::default()
+        };
+        let mut player = Player::new();
+        INDICATORS.changed(LockState::default(), num, &mut player);
+        assert!(HOOKED.load(Ordering::SeqCst));
+        INDICATORS.changed(num, LockState::default(), &mut player);
+        assert!(!HOOKED.load(Ordering::SeqCst));
+    }
+}
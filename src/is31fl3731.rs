@@ -0,0 +1,84 @@
+//! Driver for the IS31FL3731 LED matrix controller
+//!
+//! The Clueboard uses one IS31FL3731 to drive the white LED under each switch.
+//! Only picture mode on frame 0 is used, the firmware keeps its own copy of the
+//! frame and writes the PWM registers whenever it changes.
+
+use embedded_hal::blocking::i2c::Write;
+
+/// Number of LEDs the controller can drive
+pub const LED_COUNT: usize = 144;
+
+/// I2C address with the AD pin tied to GND
+pub const DEFAULT_ADDRESS: u8 = 0x74;
+
+// Command register, selects which page the other registers refer to
+const COMMAND_REGISTER: u8 = 0xFD;
+const FUNCTION_PAGE: u8 = 0x0B;
+const FRAME_0: u8 = 0x00;
+
+// Function page registers
+const CONFIG_REGISTER: u8 = 0x00;
+const PICTURE_DISPLAY_REGISTER: u8 = 0x01;
+const AUDIO_SYNC_REGISTER: u8 = 0x06;
+const SHUTDOWN_REGISTER: u8 = 0x0A;
+
+// Frame page registers
+const LED_CONTROL_OFFSET: u8 = 0x00;
+const BLINK_CONTROL_OFFSET: u8 = 0x12;
+const PWM_OFFSET: u8 = 0x24;
+
+const PICTURE_MODE: u8 = 0x00;
+const CONTROL_BYTES: usize = LED_COUNT / 8;
+
+pub struct Is31fl3731<I2C> {
+    i2c: I2C,
+    address: u8,
+}
+
+impl<I2C, E> Is31fl3731<I2C>
+where
+    I2C: Write<Error = E>,
+{
+    pub fn new(i2c: I2C, address: u8) -> Self {
+        Is31fl3731 { i2c, address }
+    }
+
+    /// Reset the controller into picture mode showing frame 0 with every LED enabled and dark
+    pub fn init(&mut self) -> Result<(), E> {
+        self.select_page(FUNCTION_PAGE)?;
+        self.write_register(SHUTDOWN_REGISTER, 0x00)?;
+        self.write_register(CONFIG_REGISTER, PICTURE_MODE)?;
+        self.write_register(PICTURE_DISPLAY_REGISTER, FRAME_0)?;
+        self.write_register(AUDIO_SYNC_REGISTER, 0x00)?;
+
+        self.select_page(FRAME_0)?;
+        self.write_block(BLINK_CONTROL_OFFSET, &[0; CONTROL_BYTES])?;
+        self.write_block(PWM_OFFSET, &[0; LED_COUNT])?;
+        self.write_block(LED_CONTROL_OFFSET, &[0xFF; CONTROL_BYTES])?;
+
+        self.select_page(FUNCTION_PAGE)?;
+        self.write_register(SHUTDOWN_REGISTER, 0x01)?;
+        self.select_page(FRAME_0)
+    }
+
+    /// Write the PWM duty cycle of every LED
+    pub fn write_pwm(&mut self, pwm: &[u8; LED_COUNT]) -> Result<(), E> {
+        self.write_block(PWM_OFFSET, pwm)
+    }
+
+    fn select_page(&mut self, page: u8) -> Result<(), E> {
+        self.write_register(COMMAND_REGISTER, page)
+    }
+
+    fn write_register(&mut self, register: u8, value: u8) -> Result<(), E> {
+        self.i2c.write(self.address, &[register, value])
+    }
+
+    fn write_block(&mut self, register: u8, data: &[u8]) -> Result<(), E> {
+        let mut buf = [0; LED_COUNT + 1];
+        buf[0] = register;
+        buf[1..=data.len()].copy_from_slice(data);
+        self.i2c.write(self.address, &buf[..=data.len()])
+    }
+}
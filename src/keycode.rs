@@ -0,0 +1,103 @@
+//! Numbering of the actions that can be stored as keymap overrides
+//!
+//! These match QMK's keycodes so tools made for QMK keyboards show the right thing.
+//! https://docs.qmk.fm/#/keycodes
+
+use keyberon::action::Action as KeyberonAction;
+use keyberon::key_code::KeyCode;
+
+use clueboard_core::dynamic_macros::MACRO_COUNT;
+use clueboard_core::tap_dance::TAP_DANCE_COUNT;
+
+use crate::layout::CustomAction;
+
+type Action = KeyberonAction<CustomAction>;
+
+const KC_NO: u16 = 0x0000;
+const KC_TRNS: u16 = 0x0001;
+/// `MO(layer)`, the layer is in the low 5 bits
+const QK_MOMENTARY: u16 = 0x5220;
+const QK_MOMENTARY_MAX: u16 = 0x523F;
+/// `DF(layer)`, the layer is in the low 5 bits
+const QK_DEF_LAYER: u16 = 0x5240;
+const QK_DEF_LAYER_MAX: u16 = 0x525F;
+/// `TD(index)`, the rest of the tap dances follow on from it
+const QK_TAP_DANCE: u16 = 0x5700;
+const QK_TAP_DANCE_MAX: u16 = QK_TAP_DANCE + TAP_DANCE_COUNT as u16 - 1;
+/// `QK_MACRO_0`, the rest of the dynamic macros follow on from it
+const QK_MACRO: u16 = 0x7700;
+const QK_MACRO_MAX: u16 = QK_MACRO + MACRO_COUNT as u16 - 1;
+const QK_BOOT: u16 = 0x7C00;
+/// `QK_KB_0`, the first keycode left for keyboards to define. Setting a key to it puts back the
+/// compiled in action, and it's what keys whose action has no keycode read as.
+pub const DEFAULT: u16 = 0x7E00;
+
+/// The action for `code`, if it's one the keyboard supports
+pub fn action(code: u16) -> Option<Action> {
+    match code {
+        KC_NO => Some(Action::NoOp),
+        KC_TRNS => Some(Action::Trans),
+        // The basic keycodes are the HID usages, the same values keyberon uses
+        0x0004..=0x00FF => key_code(code as u8).map(Action::KeyCode),
+        QK_MOMENTARY..=QK_MOMENTARY_MAX => {
+            let layer = usize::from(code - QK_MOMENTARY);
+            if layer < crate::LAYERS.len() {
+                Some(Action::Layer(layer))
+            } else {
+                None
+            }
+        }
+        QK_DEF_LAYER..=QK_DEF_LAYER_MAX => {
+            let layer = code - QK_DEF_LAYER;
+            if usize::from(layer) < crate::LAYERS.len() {
+                Some(Action::Custom(CustomAction::DefaultLayer(layer as u8)))
+            } else {
+                None
+            }
+        }
+        QK_TAP_DANCE..=QK_TAP_DANCE_MAX => Some(Action::Custom(CustomAction::TapDance(
+            (code - QK_TAP_DANCE) as u8,
+        ))),
+        QK_MACRO..=QK_MACRO_MAX => Some(Action::Custom(CustomAction::DynamicMacro(
+            (code - QK_MACRO) as u8,
+        ))),
+        QK_BOOT => Some(Action::Custom(CustomAction::Bootloader)),
+        _ => None,
+    }
+}
+
+/// The keycode for `action`, if it has one
+pub fn code(action: &Action) -> Option<u16> {
+    match *action {
+        Action::NoOp => Some(KC_NO),
+        Action::Trans => Some(KC_TRNS),
+        // keyberon has keys past the ones QMK gives basic keycodes
+        Action::KeyCode(key) => Some(key as u8)
+            .filter(|&usage| key_code(usage).is_some())
+            .map(u16::from),
+        Action::Layer(layer) if layer <= usize::from(QK_MOMENTARY_MAX - QK_MOMENTARY) => {
+            Some(QK_MOMENTARY + layer as u16)
+        }
+        Action::Custom(CustomAction::DefaultLayer(layer))
+            if u16::from(layer) <= QK_DEF_LAYER_MAX - QK_DEF_LAYER =>
+        {
+            Some(QK_DEF_LAYER + u16::from(layer))
+        }
+        Action::Custom(CustomAction::TapDance(index)) => Some(QK_TAP_DANCE + u16::from(index)),
+        Action::Custom(CustomAction::DynamicMacro(index)) => Some(QK_MACRO + u16::from(index)),
+        Action::Custom(CustomAction::Bootloader) => Some(QK_BOOT),
+        _ => None,
+    }
+}
+
+/// The key with HID usage `usage`, if it's one QMK gives a basic keycode
+pub fn key_code(usage: u8) -> Option<KeyCode> {
+    match usage {
+        0x04..=0xA4 | 0xE0..=0xE7 => {
+            // NOTE(unsafe) KeyCode is a repr(u8) enum with a variant for every value in these
+            // ranges
+            Some(unsafe { core::mem::transmute::<u8, KeyCode>(usage) })
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,255 @@
+//! The keymap in use, the compiled in layers with any stored overrides applied
+//!
+//! keyberon's `Layout` only takes `'static` layers, so the compiled in layers are copied into
+//! statics where the overrides are applied. `Keymap` is the only thing with access to them and it
+//! owns the `Layout` made from them. There are two copies of the statics: keys changed while
+//! running are applied to the copy the `Layout` isn't using, and a new `Layout` is made from it.
+
+use core::ptr::{addr_of, addr_of_mut};
+
+use keyberon::action::Action as KeyberonAction;
+use keyberon::key_code::KeyCode;
+use keyberon::layout::{CustomEvent, Event, Layers, Layout};
+
+use clueboard_core::keymap;
+use clueboard_core::{COLS, ROWS};
+
+use crate::keycode;
+use crate::layout::CustomAction;
+
+type Action = KeyberonAction<CustomAction>;
+
+/// Number of layers in the keymap
+pub const LAYER_COUNT: usize = 3;
+
+pub type Overrides = keymap::Overrides<LAYER_COUNT>;
+
+const NO_OP: Action = Action::NoOp;
+const EMPTY_ROW: [Action; COLS] = [NO_OP; COLS];
+const EMPTY_LAYER: [[Action; COLS]; ROWS] = [EMPTY_ROW; ROWS];
+
+/// Number of copies of the statics below, one in use and one to build the next keymap in
+const BUFFERS: usize = 2;
+
+/// The action of every key
+static mut ACTIONS: [[[[Action; COLS]; ROWS]; LAYER_COUNT]; BUFFERS] =
+    [[EMPTY_LAYER; LAYER_COUNT]; BUFFERS];
+/// Each row of `ACTIONS` as a slice, as `Layers` needs
+static mut ROW_SLICES: [[[&[Action]; ROWS]; LAYER_COUNT]; BUFFERS] =
+    [[[&[]; ROWS]; LAYER_COUNT]; BUFFERS];
+/// Each layer of `ROW_SLICES` as a slice
+static mut LAYER_SLICES: [[&[&[Action]]; LAYER_COUNT]; BUFFERS] = [[&[]; LAYER_COUNT]; BUFFERS];
+
+/// Modifier swaps applied to the keys the keymap presses, for hosts that expect them elsewhere
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Swaps {
+    /// Swap Left Ctrl and Caps Lock
+    pub ctrl_caps: bool,
+    /// Swap Alt and GUI on both sides
+    pub alt_gui: bool,
+}
+
+/// Swap changes that can be bound in the layout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SwapAction {
+    ToggleCtrlCaps,
+    ToggleAltGui,
+}
+
+impl Swaps {
+    pub fn apply(&mut self, action: SwapAction) {
+        match action {
+            SwapAction::ToggleCtrlCaps => self.ctrl_caps = !self.ctrl_caps,
+            SwapAction::ToggleAltGui => self.alt_gui = !self.alt_gui,
+        }
+    }
+
+    /// The key sent in place of `key`
+    pub fn key(&self, key: KeyCode) -> KeyCode {
+        match key {
+            KeyCode::LCtrl if self.ctrl_caps => KeyCode::CapsLock,
+            KeyCode::CapsLock if self.ctrl_caps => KeyCode::LCtrl,
+            KeyCode::LAlt if self.alt_gui => KeyCode::LGui,
+            KeyCode::LGui if self.alt_gui => KeyCode::LAlt,
+            KeyCode::RAlt if self.alt_gui => KeyCode::RGui,
+            KeyCode::RGui if self.alt_gui => KeyCode::RAlt,
+            key => key,
+        }
+    }
+}
+
+pub struct Keymap {
+    layout: Layout<CustomAction>,
+    layers: Layers<CustomAction>,
+    /// Which copy of the statics `layers` is in
+    buffer: usize,
+    defaults: Layers<CustomAction>,
+    overrides: Overrides,
+    default_layer: usize,
+    swaps: Swaps,
+    /// Whether the overrides have changed since they were last taken
+    changed: bool,
+}
+
+impl Keymap {
+    /// `defaults` with each of `overrides` that's a supported keycode applied
+    ///
+    /// # Safety
+    ///
+    /// Only one of these can exist.
+    pub unsafe fn new(defaults: Layers<CustomAction>, overrides: Overrides) -> Self {
+        let layers = apply(0, defaults, &overrides);
+        Keymap {
+            layout: Layout::new(layers),
+            layers,
+            buffer: 0,
+            defaults,
+            overrides,
+            default_layer: 0,
+            swaps: Swaps::default(),
+            changed: false,
+        }
+    }
+
+    /// Keycode of the key at `row`, `col` on `layer`, or `keycode::DEFAULT` if its action doesn't
+    /// have one
+    pub fn keycode(&self, layer: usize, row: usize, col: usize) -> u16 {
+        self.layers[layer]
+            .get(row)
+            .and_then(|actions| actions.get(col))
+            .and_then(keycode::code)
+            .unwrap_or(keycode::DEFAULT)
+    }
+
+    /// Change the key at `row`, `col` on `layer` to `code`, unsupported keycodes are ignored
+    ///
+    /// Any keys held down are released, since the layout is made again from the new actions.
+    pub fn set_keycode(&mut self, layer: usize, row: usize, col: usize, code: u16) {
+        let code = match code {
+            keycode::DEFAULT => None,
+            code if keycode::action(code).is_some() => Some(code),
+            _ => return,
+        };
+        if self.overrides.get(layer, row, col) != code {
+            self.overrides.set(layer, row, col, code);
+            self.rebuild();
+        }
+    }
+
+    /// Go back to the compiled in keymap
+    pub fn reset(&mut self) {
+        if self.overrides != Overrides::new() {
+            self.overrides.clear();
+            self.rebuild();
+        }
+    }
+
+    /// The overrides, if they've changed since this was last called
+    pub fn take_changed(&mut self) -> Option<Overrides> {
+        if core::mem::replace(&mut self.changed, false) {
+            Some(self.overrides.clone())
+        } else {
+            None
+        }
+    }
+
+    /// The actions on `layer`, by matrix row then column
+    pub fn layer(&self, layer: usize) -> &[&[Action]] {
+        self.layers[layer]
+    }
+
+    pub fn event(&mut self, event: Event) {
+        self.layout.event(event)
+    }
+
+    pub fn tick(&mut self) -> CustomEvent<'_, CustomAction> {
+        self.layout.tick()
+    }
+
+    /// The keys pressed, with the swaps applied
+    pub fn keycodes(&self) -> impl Iterator<Item = KeyCode> + '_ {
+        let swaps = self.swaps;
+        self.layout.keycodes().map(move |key| swaps.key(key))
+    }
+
+    pub fn current_layer(&self) -> usize {
+        self.layout.current_layer()
+    }
+
+    pub fn default_layer(&self) -> usize {
+        self.default_layer
+    }
+
+    pub fn set_default_layer(&mut self, layer: usize) {
+        self.default_layer = layer;
+        self.layout.set_default_layer(layer)
+    }
+
+    pub fn swaps(&self) -> Swaps {
+        self.swaps
+    }
+
+    pub fn set_swaps(&mut self, swaps: Swaps) {
+        self.swaps = swaps;
+    }
+
+    fn rebuild(&mut self) {
+        // NOTE(unsafe) the layout and layers only use the other buffer, and there's only one Keymap
+        let buffer = (self.buffer + 1) % BUFFERS;
+        let layers = unsafe { apply(buffer, self.defaults, &self.overrides) };
+        self.layout = Layout::new(layers);
+        self.layout.set_default_layer(self.default_layer);
+        self.layers = layers;
+        self.buffer = buffer;
+        self.changed = true;
+    }
+}
+
+/// Copy `defaults` into `buffer` of `ACTIONS`, apply the supported `overrides`, and point that
+/// buffer's slices at them
+///
+/// # Safety
+///
+/// Nothing else can be using `buffer` of the statics.
+unsafe fn apply(
+    buffer: usize,
+    defaults: Layers<CustomAction>,
+    overrides: &Overrides,
+) -> Layers<CustomAction> {
+    let actions = &mut *addr_of_mut!(ACTIONS[buffer]);
+    for (actions, default) in actions.iter_mut().zip(defaults) {
+        for (actions, default) in actions.iter_mut().zip(default.iter()) {
+            for (action, default) in actions.iter_mut().zip(default.iter()) {
+                *action = *default;
+            }
+        }
+    }
+    for (layer, row, col, code) in overrides.iter() {
+        if let Some(action) = keycode::action(code) {
+            actions[layer][row][col] = action;
+        }
+    }
+    layers(buffer)
+}
+
+/// Point `buffer` of the slices at `buffer` of `ACTIONS`
+///
+/// # Safety
+///
+/// Nothing else can be using `buffer` of the statics.
+unsafe fn layers(buffer: usize) -> Layers<CustomAction> {
+    let actions = &*addr_of!(ACTIONS[buffer]);
+    let row_slices = &mut *addr_of_mut!(ROW_SLICES[buffer]);
+    for (slices, actions) in row_slices.iter_mut().zip(actions) {
+        for (slice, actions) in slices.iter_mut().zip(actions) {
+            *slice = actions;
+        }
+    }
+
+    let row_slices = &*addr_of!(ROW_SLICES[buffer]);
+    let layer_slices = &mut *addr_of_mut!(LAYER_SLICES[buffer]);
+    for (slice, rows) in layer_slices.iter_mut().zip(row_slices) {
+        *slice = rows;
+    }
+    &*addr_of!(LAYER_SLICES[buffer])
+}
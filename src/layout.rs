@@ -1,9 +1,39 @@
 use keyberon::action::Action::NoOp;
-use keyberon::action::{Action, SequenceEvent};
+use keyberon::action::SequenceEvent;
 use keyberon::key_code::KeyCode::*;
 
+use clueboard_core::audio::{AudioAction, Note};
+use clueboard_core::effects::{Effect, EffectAction};
+
+use crate::backlight::{BacklightAction, Frame};
+use crate::indicators::{LockIndicator, LockIndicators};
+use crate::keymap::SwapAction;
+use crate::songs;
+
+type Action = keyberon::action::Action<CustomAction>;
 type ClueboardLayer = &'static [&'static [Action]];
 
+/// Actions handled by the firmware rather than keyberon
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CustomAction {
+    Backlight(BacklightAction),
+    Effect(EffectAction),
+    Song(&'static [Note]),
+    Audio(AudioAction),
+    /// Play notes from the keys instead of typing
+    MusicMode,
+    /// Reboot into the DFU bootloader for flashing
+    Bootloader,
+    /// Type the dynamic macro with this index, as set with VIA
+    DynamicMacro(u8),
+    /// The tap dance with this index, as set with Vial
+    TapDance(u8),
+    /// Make this layer the one active when no layer keys are held, it's kept in the settings
+    DefaultLayer(u8),
+    /// Swap modifier keys, kept in the settings
+    Swap(SwapAction),
+}
+
 #[allow(unused)]
 enum Layer {
     BaseLayer = 0,
@@ -61,7 +91,7 @@ macro_rules! layer {
 }
 
 #[rustfmt::skip]
-pub(crate) static BASE_LAYER: ClueboardLayer = layer!(
+pub static BASE_LAYER: ClueboardLayer = layer!(
 KC_ESC,  KC_1,   KC_2,   KC_3,   KC_4,   KC_5,   KC_6,   KC_7,   KC_8,   KC_9,    KC_0,    KC_MINS, KC_EQL,  KC_BSPC,                 KC_PGUP,
 KC_TAB,  KC_Q,   KC_W,   KC_E,   KC_R,   KC_T,   KC_Y,   KC_U,   KC_I,   KC_O,    KC_P,    KC_LBRC, KC_RBRC, KC_BSLS,                 KC_PGDN,
 KC_LCTL, KC_A,   KC_S,   KC_D,   KC_F,   KC_G,   KC_H,   KC_J,   KC_K,   KC_L,    KC_SCLN, KC_QUOT,          KC_ENT,
@@ -69,21 +99,60 @@ KC_LSFT,         KC_Z,   KC_X,   KC_C,   KC_V,   KC_B,   KC_N,   KC_M,   KC_COMM
 MO_FL,   KC_LALT,KC_LGUI,                KC_SPC, KC_SPC,                          KC_NO  , KC_RGUI, MO_ML,   KC_APP , KC_LEFT,KC_DOWN,KC_RGHT);
 
 #[rustfmt::skip]
-pub(crate) static FUNCTION_LAYER: ClueboardLayer = layer!(
+pub static FUNCTION_LAYER: ClueboardLayer = layer!(
 KC_GRV,  KC_F1,  KC_F2,  KC_F3,  KC_F4,  KC_F5,  KC_F6,  KC_F7,  KC_F8,  KC_F9,   KC_F10,  KC_F11,  KC_F12,  KC_DEL,                 KC_VOLU,
-______,  ______, ______, ______, ______, ______, ______, ______, ______, KC_MPRV, KC_MPLY, KC_MNXT, KC_MUTE, KC_INS,                 KC_VOLD,
-______,  ______, ______, ______, ______, ______, KC_LEFT,KC_DOWN,KC_UP  ,KC_RGHT, ______,  ______,           ______,
-______,          ______, ______, ______, ______, ______, ______, ______, ______,  ______,  ______,           ______,         KC_PGUP,
-______,  ______, ______,                 ______, ______,                          ______,  ______,  ______,  ______, KC_HOME,KC_PGDN,KC_END);
+______,  LM_REAC,LM_HEAT,______, ______, AU_TOGG,CK_TOGG,CK_VOLD,CK_VOLU,KC_MPRV, KC_MPLY, KC_MNXT, KC_MUTE, KC_INS,                 KC_VOLD,
+CL_TOGG, LM_SOLD,LM_BRTH,LM_WAVE,LM_RIPL,LM_GRAD,KC_LEFT,KC_DOWN,KC_UP  ,KC_RGHT, ______,  ______,           ______,
+______,          BL_DEC, BL_TOGG,BL_INC, BL_STEP,LM_NEXT,______, MU_ON,  ______,  ______,  ______,           ______,         KC_PGUP,
+______,  ______, AG_TOGG,                 ______, ______,                          ______,  ______,  ______,  ______, KC_HOME,KC_PGDN,KC_END);
 
 #[rustfmt::skip]
-pub(crate) static MACRO_LAYER: ClueboardLayer = layer!(
-______,  ______, EMAIL,  ______, ______, ______, ______, ______, ______, ______,  ______,  ______,  ______,  KC_PRN,                 ______,
-______,  ______, FNAME,  ______, ______, ______, ______, UNAME,  ______, ______,  PHONE,   ______,  ______,  ______,                 ______,
+pub static MACRO_LAYER: ClueboardLayer = layer!(
+QK_BOOT, ______, EMAIL,  ______, ______, ______, ______, ______, ______, ______,  ______,  ______,  ______,  KC_PRN,                 ______,
+______,  ______, FNAME,  ______, ______, ______, ______, UNAME,  ______, SN_ODE,  PHONE,   ______,  ______,  ______,                 ______,
 ______,  ADDR,   ______, ______, ______, ______, ______, ______, ______, ______,  ______,  ______,           ______,
 ______,          ______, ______, ______, ______, TOWN,   ______, LNAME,  ______,  ______,  ______,           ______,         ______,
 ______,  ______, ______,                 ______, ______,                          ______,  ______,  ______,  ______, ______, ______, ______);
 
+/// How the host's lock keys are shown
+pub static LOCK_INDICATORS: LockIndicators = LockIndicators {
+    num_lock: &[],
+    caps_lock: &[
+        // The Caps Lock position, even though it's mapped to Ctrl
+        LockIndicator::Key(2, 0),
+        LockIndicator::Song {
+            on: songs::CAPS_LOCK_ON,
+            off: songs::CAPS_LOCK_OFF,
+        },
+    ],
+    scroll_lock: &[],
+    compose: &[],
+    kana: &[],
+};
+
+/// Played once the keyboard has started
+pub static STARTUP_SONG: &[Note] = songs::STARTUP;
+
+/// Played when each layer becomes active, indexed by layer
+pub static LAYER_SONGS: [&[Note]; 3] = [&[], songs::FUNCTION_LAYER, songs::MACRO_LAYER];
+
+/// Brightness of the keys lit while a layer is held, the same whatever the backlight is doing
+const HIGHLIGHT_BRIGHTNESS: u8 = 255;
+
+/// Light only the keys that do something on `layer`, so it's clear what's available while the
+/// layer is held. Drawn over the backlight so it shows with the backlight off.
+pub fn highlight_bound_keys(layer: &[&[Action]], frame: &mut Frame) {
+    for (row, actions) in layer.iter().enumerate() {
+        for (col, action) in actions.iter().enumerate() {
+            let brightness = match action {
+                Action::Trans | Action::NoOp => 0,
+                _ => HIGHLIGHT_BRIGHTNESS,
+            };
+            frame.set_key(row, col, brightness);
+        }
+    }
+}
+
 // Map keyberon Actions to QMK key codes
 // https://docs.qmk.fm/#/keycodes_basic
 const ______: Action = Action::Trans;
@@ -186,8 +255,244 @@ const KC_LEFT: Action = Action::KeyCode(Left);
 const KC_DOWN: Action = Action::KeyCode(Down);
 const KC_RGHT: Action = Action::KeyCode(Right);
 
+// https://docs.qmk.fm/#/feature_backlight
+const BL_INC: Action = Action::Custom(CustomAction::Backlight(BacklightAction::Increase));
+const BL_DEC: Action = Action::Custom(CustomAction::Backlight(BacklightAction::Decrease));
+const BL_TOGG: Action = Action::Custom(CustomAction::Backlight(BacklightAction::Toggle));
+const BL_STEP: Action = Action::Custom(CustomAction::Backlight(BacklightAction::Step));
+
+// https://docs.qmk.fm/#/feature_led_matrix
+const LM_NEXT: Action = Action::Custom(CustomAction::Effect(EffectAction::Next));
+const LM_SOLD: Action = select_effect(Effect::Solid);
+const LM_BRTH: Action = select_effect(Effect::Breathing);
+const LM_WAVE: Action = select_effect(Effect::Wave);
+const LM_RIPL: Action = select_effect(Effect::Ripple);
+const LM_GRAD: Action = select_effect(Effect::Gradient);
+const LM_REAC: Action = select_effect(Effect::Reactive);
+const LM_HEAT: Action = select_effect(Effect::Heatmap);
+
+const fn select_effect(effect: Effect) -> Action {
+    Action::Custom(CustomAction::Effect(EffectAction::Select(effect)))
+}
+
+// https://docs.qmk.fm/#/feature_audio?id=keycodes
+const AU_TOGG: Action = Action::Custom(CustomAction::Audio(AudioAction::Toggle));
+
+// https://docs.qmk.fm/#/feature_audio?id=clicky
+const CK_TOGG: Action = Action::Custom(CustomAction::Audio(AudioAction::ToggleClicky));
+const CK_VOLU: Action = Action::Custom(CustomAction::Audio(AudioAction::VolumeUp));
+const CK_VOLD: Action = Action::Custom(CustomAction::Audio(AudioAction::VolumeDown));
+
+// https://docs.qmk.fm/#/keycodes_magic
+const CL_TOGG: Action = Action::Custom(CustomAction::Swap(SwapAction::ToggleCtrlCaps));
+const AG_TOGG: Action = Action::Custom(CustomAction::Swap(SwapAction::ToggleAltGui));
+
+// https://docs.qmk.fm/#/quantum_keycodes
+const QK_BOOT: Action = Action::Custom(CustomAction::Bootloader);
+
+// https://docs.qmk.fm/#/feature_audio?id=music-mode
+const MU_ON: Action = Action::Custom(CustomAction::MusicMode);
+
+const SN_ODE: Action = Action::Custom(CustomAction::Song(songs::ODE_TO_JOY));
+
 const MO_FL: Action = Action::Layer(Layer::FunctionLayer as usize);
 const MO_ML: Action = Action::Layer(Layer::MacroLayer as usize);
 
 // Contains macro definitions generated by build.rs
 include!(concat!(env!("OUT_DIR"), "/macros.rs"));
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fmt::Write;
+    use std::string::String;
+    use std::vec::Vec;
+
+    use keyberon::key_code::KeyCode;
+
+    use clueboard_core::{COLS, ROWS};
+
+    /// Every position of every layer and the action there, regenerate it with
+    /// `UPDATE_GOLDEN=1 cargo test --lib --target x86_64-unknown-linux-gnu`
+    const GOLDEN_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/layout.golden");
+
+    const LAYERS: [(&str, ClueboardLayer); 3] = [
+        ("BASE_LAYER", BASE_LAYER),
+        ("FUNCTION_LAYER", FUNCTION_LAYER),
+        ("MACRO_LAYER", MACRO_LAYER),
+    ];
+
+    const SONGS: [(&str, &[Note]); 6] = [
+        ("STARTUP", songs::STARTUP),
+        ("FUNCTION_LAYER", songs::FUNCTION_LAYER),
+        ("MACRO_LAYER", songs::MACRO_LAYER),
+        ("CAPS_LOCK_ON", songs::CAPS_LOCK_ON),
+        ("CAPS_LOCK_OFF", songs::CAPS_LOCK_OFF),
+        ("ODE_TO_JOY", songs::ODE_TO_JOY),
+    ];
+
+    /// `action` as it appears in the golden file
+    fn describe(action: &Action) -> String {
+        match action {
+            Action::Custom(CustomAction::Song(notes)) => {
+                let name = SONGS
+                    .iter()
+                    .find(|(_, song)| song == notes)
+                    .map_or("?", |(name, _)| name);
+                format!("Custom(Song({}))", name)
+            }
+            // The keys pressed, prefixed with the modifiers held, and the delays
+            Action::Sequence { events } => {
+                // Modifiers held, and whether a key has been pressed with each
+                let mut held: Vec<(KeyCode, bool)> = Vec::new();
+                let mut keys = Vec::new();
+                for event in events.iter() {
+                    match *event {
+                        SequenceEvent::Press(key) if modifier_prefix(key).is_some() => {
+                            held.push((key, false))
+                        }
+                        SequenceEvent::Release(key) if modifier_prefix(key).is_some() => {
+                            // A modifier tapped on its own is shown by name
+                            if let Some(i) = held.iter().position(|&(held, _)| held == key) {
+                                if !held.remove(i).1 {
+                                    keys.push(format!("{:?}", key));
+                                }
+                            }
+                        }
+                        SequenceEvent::Press(key) => {
+                            let mut name = String::new();
+                            for (modifier, used) in held.iter_mut() {
+                                name.push_str(modifier_prefix(*modifier).unwrap_or_default());
+                                *used = true;
+                            }
+                            write!(name, "{:?}", key).unwrap();
+                            keys.push(name);
+                        }
+                        SequenceEvent::Delay { duration } => {
+                            keys.push(format!("Delay({})", duration))
+                        }
+                        _ => {}
+                    }
+                }
+                format!("Sequence({})", keys.join(" "))
+            }
+            action => format!("{:?}", action),
+        }
+    }
+
+    /// Prefix for keys pressed while `key` is held, if it's a modifier
+    fn modifier_prefix(key: KeyCode) -> Option<&'static str> {
+        match key {
+            LCtrl => Some("C-"),
+            LShift => Some("S-"),
+            LAlt => Some("A-"),
+            LGui => Some("G-"),
+            RCtrl => Some("RC-"),
+            RShift => Some("RS-"),
+            RAlt => Some("AltGr-"),
+            RGui => Some("RG-"),
+            _ => None,
+        }
+    }
+
+    fn render() -> String {
+        let mut out = String::new();
+        for (i, (name, layer)) in LAYERS.iter().enumerate() {
+            if i > 0 {
+                out.push('\n');
+            }
+            writeln!(out, "{}", name).unwrap();
+            for (row, actions) in layer.iter().enumerate() {
+                for (col, action) in actions.iter().enumerate() {
+                    writeln!(out, "{},{} {}", row, col, describe(action)).unwrap();
+                }
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn layers_match_golden_file() {
+        let actual = render();
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            std::fs::write(GOLDEN_PATH, &actual).unwrap();
+            return;
+        }
+
+        let expected = std::fs::read_to_string(GOLDEN_PATH).unwrap();
+        let mut diff = String::new();
+        let mut layer = "";
+        for (expected, actual) in expected.lines().zip(actual.lines()) {
+            if !expected.contains(' ') && expected == actual {
+                layer = expected;
+            }
+            if expected != actual {
+                writeln!(diff, "{}\n  - {}\n  + {}", layer, expected, actual).unwrap();
+            }
+        }
+        assert!(
+            diff.is_empty() && expected.lines().count() == actual.lines().count(),
+            "layers don't match {}, run with UPDATE_GOLDEN=1 if the change is intended:\n{}",
+            GOLDEN_PATH,
+            diff
+        );
+    }
+
+    #[test]
+    fn sequences_show_modifiers_and_delays() {
+        static EVENTS: [SequenceEvent; 14] = [
+            SequenceEvent::Press(LCtrl),
+            SequenceEvent::Press(LAlt),
+            SequenceEvent::Press(T),
+            SequenceEvent::Release(T),
+            SequenceEvent::Release(LAlt),
+            SequenceEvent::Release(LCtrl),
+            SequenceEvent::Delay { duration: 200 },
+            SequenceEvent::Press(RAlt),
+            SequenceEvent::Press(Q),
+            SequenceEvent::Release(Q),
+            SequenceEvent::Release(RAlt),
+            SequenceEvent::Press(LGui),
+            SequenceEvent::Release(LGui),
+            SequenceEvent::Complete,
+        ];
+        assert_eq!(
+            describe(&Action::Sequence { events: &EVENTS }),
+            "Sequence(C-A-T Delay(200) AltGr-Q LGui)"
+        );
+    }
+
+    /// Names in macros.txt are checked against clueboard-macros' table of `KeyCode`s
+    #[test]
+    fn macro_key_names_match_keyberon() {
+        for &(usage, name) in clueboard_macros::KEY_CODES.iter() {
+            let key = crate::keycode::key_code(usage).unwrap();
+            assert_eq!(format!("{:?}", key), name, "{:#04x}", usage);
+        }
+    }
+
+    #[test]
+    fn bound_keys_show_with_the_backlight_off() {
+        let mut frame = Frame::new();
+        frame.draw(&[[255; COLS]; ROWS], 0);
+        highlight_bound_keys(FUNCTION_LAYER, &mut frame);
+
+        // F1 is lit and Tab, with nothing on the function layer, isn't
+        let mut expected = frame.clone();
+        expected.set_key(0, 1, HIGHLIGHT_BRIGHTNESS);
+        expected.set_key(1, 0, 0);
+        assert!(frame == expected);
+        expected.set_key(0, 1, 0);
+        assert!(frame != expected);
+    }
+
+    #[test]
+    fn layers_are_complete() {
+        for (name, layer) in LAYERS.iter() {
+            assert_eq!(layer.len(), ROWS, "{}", name);
+            for actions in layer.iter() {
+                assert_eq!(actions.len(), COLS, "{}", name);
+            }
+        }
+    }
+}
@@ -0,0 +1,44 @@
+//! The parts of the firmware that don't touch the hardware
+//!
+//! Everything from the debounced switches to the keyboard report is in `pipeline`, along with the
+//! keymap, layout and backlight it uses. These build for the host as well as the keyboard, so the
+//! firmware's behaviour can be tested with:
+//!
+//! ```text
+//! cargo test --lib --target x86_64-unknown-linux-gnu
+//! ```
+
+#![cfg_attr(not(test), no_std)]
+
+pub mod backlight;
+pub mod indicators;
+pub mod is31fl3731;
+pub mod keycode;
+pub mod keymap;
+pub mod layout;
+pub mod pipeline;
+pub mod songs;
+
+use crate::layout::{CustomAction, BASE_LAYER, FUNCTION_LAYER, MACRO_LAYER};
+
+pub static LAYERS: keyberon::layout::Layers<CustomAction> =
+    &[BASE_LAYER, FUNCTION_LAYER, MACRO_LAYER];
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    /// Vial reads the compressed copy from the keyboard, so it has to be compressed again after
+    /// each change to the JSON
+    #[test]
+    fn vial_definition_matches_the_json() {
+        let json = include_bytes!("../via/clueboard66_lp.json");
+        let mut compressed: &[u8] = include_bytes!("../via/clueboard66_lp.json.xz");
+        let mut decompressed = Vec::new();
+        lzma_rs::xz_decompress(&mut compressed, &mut decompressed).unwrap();
+        assert!(
+            decompressed[..] == json[..],
+            "via/clueboard66_lp.json.xz is out of date, see the README"
+        );
+    }
+}
@@ -1,73 +1,107 @@
 #![no_main]
 #![no_std]
 
-mod layout;
+mod bootloader;
+mod dfu;
+mod flash;
+mod raw_hid;
+mod settings;
+mod speaker;
+mod via;
 
 use panic_halt as _;
 
 use embedded_hal::digital::v2::OutputPin;
 use rtic::app;
-use stm32f3xx_hal::gpio::{Input, Output, PXx, PushPull};
+use stm32f3xx_hal::gpio::gpiob::{PB8, PB9};
+use stm32f3xx_hal::gpio::{Input, OpenDrain, Output, PXx, PushPull, AF4};
+use stm32f3xx_hal::i2c::I2c;
 use stm32f3xx_hal::prelude::*;
 use stm32f3xx_hal::usb::{Peripheral, UsbBus, UsbBusType};
 use stm32f3xx_hal::{pac, timer};
 use usb_device::bus::UsbBusAllocator;
 use usb_device::class::UsbClass as _;
-use usb_device::device::UsbVidPid;
+use usb_device::device::{UsbDeviceBuilder, UsbVidPid};
 
-use keyberon::debounce::Debouncer;
-use keyberon::key_code::{KbHidReport, KeyCode};
-use keyberon::layout::Layout;
-use keyberon::matrix::{Matrix, PressedKeys};
+use keyberon::key_code::KbHidReport;
+use keyberon::matrix::Matrix;
 
-use crate::layout::{BASE_LAYER, FUNCTION_LAYER, MACRO_LAYER};
+use clueboard_core::audio::{AudioAction, Clicky, Player};
+use clueboard_core::bootmagic::{self, Bootmagic};
+use clueboard_core::combos::Combos;
+use clueboard_core::dynamic_macros::MacroBuffer;
+use clueboard_core::effects::Animator;
+use clueboard_core::music::MusicEvent;
+use clueboard_core::store::Store;
+use clueboard_core::tap_dance::TapDances;
+#[cfg(feature = "vial")]
+use clueboard_core::vial::Vial;
+use clueboard_core::{KeyFrame, COLS, PID, ROWS, VID};
 
-// Same values that Clueboard QMK firmware uses
-const VID: u16 = 0xC1ED;
-const PID: u16 = 0x2391;
+use clueboard_rust_firmware::backlight::{Backlight, Frame};
+use clueboard_rust_firmware::indicators::{HostLeds, LockState};
+use clueboard_rust_firmware::is31fl3731::{self, Is31fl3731};
+use clueboard_rust_firmware::keymap::{Keymap, Overrides};
+use clueboard_rust_firmware::layout::{
+    highlight_bound_keys, CustomAction, LAYER_SONGS, LOCK_INDICATORS, STARTUP_SONG,
+};
+use clueboard_rust_firmware::pipeline::{self, Pipeline};
+use clueboard_rust_firmware::LAYERS;
 
-type UsbClass = keyberon::Class<'static, UsbBusType, ()>;
-type UsbDevice = usb_device::device::UsbDevice<'static, UsbBusType>;
+use crate::dfu::DfuRuntime;
+use crate::flash::InternalFlash;
+use crate::raw_hid::RawHid;
+use crate::settings::{Settings, SettingsStore};
+use crate::speaker::Speaker;
+use crate::via::ViaKeyboard;
 
-// pub struct Leds {
-//     caps_lock: gpio::gpioc::PC13<gpio::Output<gpio::PushPull>>,
-// }
-// impl keyberon::keyboard::Leds for Leds {
-//     fn caps_lock(&mut self, status: bool) {
-//         if status {
-//             self.caps_lock.set_low().unwrap()
-//         } else {
-//             self.caps_lock.set_high().unwrap()
-//         }
-//     }
-// }
-
-pub static LAYERS: keyberon::layout::Layers = &[BASE_LAYER, FUNCTION_LAYER, MACRO_LAYER];
+type UsbClass = keyberon::Class<'static, UsbBusType, HostLeds>;
+type RawHidClass = RawHid<'static, UsbBusType>;
+type UsbDevice = usb_device::device::UsbDevice<'static, UsbBusType>;
+type LedDriver = Is31fl3731<I2c<pac::I2C1, (PB8<AF4<OpenDrain>>, PB9<AF4<OpenDrain>>)>>;
 
 #[app(device = stm32f3xx_hal::pac, peripherals = true)]
 const APP: () = {
     struct Resources {
         usb_dev: UsbDevice,
         usb_class: UsbClass,
+        dfu: DfuRuntime,
+        raw_hid: RawHidClass,
         matrix: Matrix<PXx<Output<PushPull>>, PXx<Input>, 8, 10>,
-        debouncer: Debouncer<PressedKeys<8, 10>>,
-        layout: Layout,
+        pipeline: Pipeline,
         timer: timer::Timer<pac::TIM3>,
+        led_driver: LedDriver,
+        led_frame: Frame,
+        backlight: Backlight,
+        animator: Animator,
+        lock_state: LockState,
+        speaker: Speaker,
+        player: Player,
+        clicky: Clicky,
+        layer: usize,
+        settings: Settings,
+        store: SettingsStore,
+        /// Started with the compiled in keymap, nothing that would replace the stored keymap, tap
+        /// dances or combos is saved
+        safe_keymap: bool,
+        #[cfg(feature = "vial")]
+        vial: Vial,
+        /// Milliseconds since power on
+        uptime: u32,
+        /// Keys pressed since power on
+        presses: u32,
     }
 
     #[init]
     fn init(c: init::Context) -> init::LateResources {
         static mut USB_BUS: Option<UsbBusAllocator<UsbBusType>> = None;
 
+        // Has to happen before anything else is set up
+        bootloader::jump_if_requested();
+
         let mut flash = c.device.FLASH.constrain();
         let mut rcc = c.device.RCC.constrain();
 
-        // set 0x424C in DR10 for dfu on reset
-        // let bkp = rcc
-        //     .bkp
-        //     .constrain(c.device.BKP, &mut rcc.apb1, &mut c.device.PWR);
-        // bkp.write_data_register_low(9, 0x424C);
-
         let clocks = rcc
             .cfgr
             .use_hse(8.MHz())
@@ -90,10 +124,6 @@ const APP: () = {
         usb_dp.set_low().unwrap();
         cortex_m::asm::delay(clocks.sysclk().0 / 100);
 
-        // let mut led = gpioc.pc13.into_push_pull_output(&mut gpioc.crh);
-        // led.set_high().unwrap();
-        let leds = (); // Leds { caps_lock: led };
-
         let usb_dm =
             gpioa
                 .pa11
@@ -110,13 +140,16 @@ const APP: () = {
         *USB_BUS = Some(UsbBus::new(usb));
         let usb_bus = USB_BUS.as_ref().unwrap();
 
-        let usb_class = keyberon::new_class(usb_bus, leds);
-        let usb_dev = keyberon::new_device(
-            usb_bus,
-            UsbVidPid(VID, PID),
-            "Clueboard",
-            "66% HotSwap Keyboard",
-        );
+        let usb_class = keyberon::new_class(usb_bus, HostLeds::default());
+        // Lets `dfu-util -e` reboot the keyboard into the bootloader
+        let dfu = DfuRuntime::new(usb_bus);
+        // Lets VIA change the keymap and macros
+        let raw_hid = RawHid::new(usb_bus);
+        let usb_dev = UsbDeviceBuilder::new(usb_bus, UsbVidPid(VID, PID))
+            .manufacturer("Clueboard")
+            .product("66% HotSwap Keyboard")
+            .serial_number(via::SERIAL_NUMBER)
+            .build();
 
         // Set up the matrix scan timer, polls at 1kHz (1000 times a second/every 1ms)
         let mut timer = timer::Timer::new(c.device.TIM3, clocks, &mut rcc.apb1);
@@ -219,53 +252,352 @@ const APP: () = {
                     .downgrade(),
             ],
         );
+        let mut matrix = matrix.unwrap();
+
+        // NOTE(unsafe) this is the only InternalFlash
+        let mut store = Store::open(unsafe { InternalFlash::new() });
+
+        // Keys held while plugging in, checked before the layout is set up
+        let magic = matrix.get().ok().and_then(|keys| bootmagic::check(&keys.0));
+        match magic {
+            Some(Bootmagic::Bootloader) => bootloader::reboot_to_bootloader(),
+            Some(Bootmagic::ClearSettings) => {
+                store.clear().ok();
+            }
+            Some(Bootmagic::SafeKeymap) | None => {}
+        }
+        let safe_keymap = magic == Some(Bootmagic::SafeKeymap);
+        let mut settings = Settings::load(&store);
+        let overrides = if safe_keymap {
+            // The stored changes are only ignored, idle doesn't save over them so they're still
+            // there next time
+            settings.default_layer = 0;
+            settings.swaps = Default::default();
+            Overrides::new()
+        } else {
+            Overrides::load(&store)
+        };
+        // NOTE(unsafe) this is the only Keymap
+        let mut keymap = unsafe { Keymap::new(LAYERS, overrides) };
+        keymap.set_default_layer(settings.default_layer.into());
+        keymap.set_swaps(settings.swaps);
+        let macros = MacroBuffer::load(&store);
+        // Tap dances and combos can make the keymap as unusable as overrides can
+        let (tap_dances, combos) = if safe_keymap {
+            (TapDances::new(), Combos::new())
+        } else {
+            (TapDances::load(&store), Combos::load(&store))
+        };
+
+        // The IS31FL3731 LED controller is on I2C1, PB8 (SCL) and PB9 (SDA)
+        let scl =
+            gpiob
+                .pb8
+                .into_af4_open_drain(&mut gpiob.moder, &mut gpiob.otyper, &mut gpiob.afrh);
+        let sda =
+            gpiob
+                .pb9
+                .into_af4_open_drain(&mut gpiob.moder, &mut gpiob.otyper, &mut gpiob.afrh);
+        let i2c = I2c::new(
+            c.device.I2C1,
+            (scl, sda),
+            400_000.Hz(),
+            clocks,
+            &mut rcc.apb1,
+        );
+        let mut led_driver = Is31fl3731::new(i2c, is31fl3731::DEFAULT_ADDRESS);
+        // The keyboard is still usable without the LEDs so errors are ignored
+        led_driver.init().ok();
+
+        // The speakers are on the DAC outputs, PA4 and PA5. TIM2 generates the tone.
+        let speaker_pins = (
+            gpioa.pa4.into_analog(&mut gpioa.moder, &mut gpioa.pupdr),
+            gpioa.pa5.into_analog(&mut gpioa.moder, &mut gpioa.pupdr),
+        );
+        let tone_timer = timer::Timer::new(c.device.TIM2, clocks, &mut rcc.apb1);
+        let mut speaker = Speaker::new(c.device.DAC1, tone_timer, speaker_pins);
+        speaker.set_volume(settings.volume);
+        let mut clicky = Clicky::new();
+        clicky.set_enabled(settings.clicky);
+        let mut player = Player::new();
+        player.set_enabled(settings.audio);
+        player.play_song(STARTUP_SONG);
 
         init::LateResources {
             usb_dev,
             usb_class,
+            dfu,
+            raw_hid,
             timer,
-            debouncer: Debouncer::new(PressedKeys::default(), PressedKeys::default(), 5),
-            matrix: matrix.unwrap(),
-            layout: Layout::new(LAYERS),
+            matrix,
+            pipeline: Pipeline::new(keymap, macros, tap_dances, combos),
+            led_driver,
+            led_frame: Frame::new(),
+            backlight: Backlight::new(settings.backlight_level, settings.backlight_enabled),
+            animator: Animator::new(settings.effect),
+            lock_state: LockState::default(),
+            speaker,
+            player,
+            clicky,
+            layer: 0,
+            settings,
+            store,
+            safe_keymap,
+            #[cfg(feature = "vial")]
+            vial: via::new_vial(),
+            uptime: 0,
+            presses: 0,
+        }
+    }
+
+    // Writing a whole frame over I2C takes several milliseconds so it's done here instead of in
+    // the matrix scan. The frame is copied out so the lock is only held briefly. Settings and
+    // everything changed with VIA are saved here too since writing to flash is slow.
+    #[idle(resources = [led_driver, led_frame, settings, store, safe_keymap, pipeline])]
+    fn idle(mut c: idle::Context) -> ! {
+        use rtic::Mutex;
+        let mut shown = Frame::new();
+        let mut saved = c.resources.settings.lock(|settings| *settings);
+        // Settings include the default layer and swaps, which a safe keymap start resets
+        let save_keymap = !*c.resources.safe_keymap;
+        loop {
+            let settings = c.resources.settings.lock(|settings| *settings);
+            if settings != saved && save_keymap {
+                // If saving fails the change is only lost at the next power cycle
+                settings.save(&mut c.resources.store).ok();
+                saved = settings;
+            }
+            let overrides = c
+                .resources
+                .pipeline
+                .lock(|pipeline| pipeline.keymap.take_changed());
+            if let Some(overrides) = overrides.filter(|_| save_keymap) {
+                overrides.save(&mut c.resources.store).ok();
+            }
+            let macros = c.resources.pipeline.lock(|pipeline| {
+                let macros = &mut pipeline.macros;
+                macros.take_changed().then(|| macros.clone())
+            });
+            if let Some(macros) = macros {
+                macros.save(&mut c.resources.store).ok();
+            }
+            let tap_dances = c.resources.pipeline.lock(|pipeline| {
+                let tap_dances = &mut pipeline.tap_dances;
+                tap_dances.take_changed().then(|| tap_dances.clone())
+            });
+            if let Some(tap_dances) = tap_dances.filter(|_| save_keymap) {
+                tap_dances.save(&mut c.resources.store).ok();
+            }
+            let combos = c.resources.pipeline.lock(|pipeline| {
+                let combos = &mut pipeline.combos;
+                combos.take_changed().then(|| combos.clone())
+            });
+            if let Some(combos) = combos.filter(|_| save_keymap) {
+                combos.save(&mut c.resources.store).ok();
+            }
+
+            let frame = c.resources.led_frame.lock(|frame| frame.clone());
+            if frame != shown && c.resources.led_driver.write_pwm(frame.pwm()).is_ok() {
+                shown = frame;
+            } else {
+                cortex_m::asm::wfi();
+            }
         }
     }
 
-    #[task(binds = USB_HP_CAN_TX, priority = 2, resources = [usb_dev, usb_class])]
+    #[task(binds = USB_HP_CAN_TX, priority = 2, resources = [usb_dev, usb_class, dfu, raw_hid])]
     fn usb_tx(mut c: usb_tx::Context) {
-        usb_poll(&mut c.resources.usb_dev, &mut c.resources.usb_class);
+        usb_poll(
+            &mut c.resources.usb_dev,
+            &mut c.resources.usb_class,
+            &mut c.resources.dfu,
+            &mut c.resources.raw_hid,
+        );
     }
 
-    #[task(binds = USB_LP_CAN_RX0, priority = 2, resources = [usb_dev, usb_class])]
+    #[task(binds = USB_LP_CAN_RX0, priority = 2, resources = [usb_dev, usb_class, dfu, raw_hid])]
     fn usb_rx(mut c: usb_rx::Context) {
-        usb_poll(&mut c.resources.usb_dev, &mut c.resources.usb_class);
+        usb_poll(
+            &mut c.resources.usb_dev,
+            &mut c.resources.usb_class,
+            &mut c.resources.dfu,
+            &mut c.resources.raw_hid,
+        );
+    }
+
+    #[task(binds = TIM2, priority = 3, resources = [speaker])]
+    fn tone(c: tone::Context) {
+        c.resources.speaker.toggle();
     }
 
-    #[task(binds = TIM3, priority = 1, resources = [usb_class, matrix, debouncer, layout, timer])]
+    #[task(
+        binds = TIM3,
+        priority = 1,
+        resources = [
+            usb_class, dfu, raw_hid, matrix, pipeline, timer, led_frame, backlight, animator,
+            lock_state, speaker, player, clicky, layer, settings, vial, uptime, presses,
+        ],
+    )]
     fn tick(mut c: tick::Context) {
         c.resources.timer.clear_event(timer::Event::Update);
+        *c.resources.uptime = c.resources.uptime.wrapping_add(1);
 
-        for event in c
+        // Vial's lock covers dfu-util too
+        #[cfg(feature = "vial")]
+        let dfu_allowed = c.resources.vial.is_unlocked();
+        #[cfg(not(feature = "vial"))]
+        let dfu_allowed = true;
+        let detach = c.resources.dfu.lock(|dfu| {
+            dfu.set_allowed(dfu_allowed);
+            dfu.tick()
+        });
+        if detach {
+            bootloader::reboot_to_bootloader();
+        }
+
+        let keys = c.resources.matrix.get().unwrap();
+        // Kept for VIA's switch tester
+        let switches = keys.0;
+        let mut output = TickOutput {
+            usb_class: &mut c.resources.usb_class,
+            animator: &mut *c.resources.animator,
+            presses: &mut *c.resources.presses,
+        };
+        let tick = c.resources.pipeline.tick(keys, &mut output);
+        match tick.action {
+            Some(CustomAction::Backlight(action)) => c.resources.backlight.apply(action),
+            Some(CustomAction::Effect(action)) => c.resources.animator.apply(action),
+            Some(CustomAction::Song(song)) => c.resources.player.play_song(song),
+            Some(CustomAction::Bootloader) => bootloader::reboot_to_bootloader(),
+            Some(CustomAction::Audio(action)) => match action {
+                AudioAction::Toggle => c.resources.player.toggle(),
+                AudioAction::ToggleClicky => c.resources.clicky.toggle(),
+                AudioAction::VolumeUp => c.resources.speaker.lock(|speaker| speaker.volume_up()),
+                AudioAction::VolumeDown => {
+                    c.resources.speaker.lock(|speaker| speaker.volume_down())
+                }
+            },
+            _ => {}
+        }
+        match tick.music {
+            MusicEvent::Play(frequency) => c.resources.player.play(frequency, u16::MAX),
+            MusicEvent::Stop => c.resources.player.stop(),
+            MusicEvent::Nothing => {}
+        }
+        if tick.action.is_some() {
+            // Custom actions are what change the settings, idle saves them
+            let settings = &mut *c.resources.settings;
+            settings.backlight_level = c.resources.backlight.level();
+            settings.backlight_enabled = c.resources.backlight.is_enabled();
+            settings.effect = c.resources.animator.effect();
+            settings.clicky = c.resources.clicky.is_enabled();
+            settings.volume = c.resources.speaker.lock(|speaker| speaker.volume());
+            settings.audio = c.resources.player.is_enabled();
+            // Changed by the pipeline itself
+            let keymap = &c.resources.pipeline.keymap;
+            settings.default_layer = keymap.default_layer() as u8;
+            settings.swaps = keymap.swaps();
+        }
+
+        if let Some(mut report) = c.resources.raw_hid.lock(|raw_hid| raw_hid.take_request()) {
+            let pipeline = &mut *c.resources.pipeline;
+            let mut keyboard = ViaKeyboard {
+                keymap: &mut pipeline.keymap,
+                macros: &mut pipeline.macros,
+                tap_dances: &mut pipeline.tap_dances,
+                combos: &mut pipeline.combos,
+                uptime: *c.resources.uptime,
+                matrix: &switches,
+                settings: &*c.resources.settings,
+                presses: *c.resources.presses,
+            };
+            #[cfg(feature = "vial")]
+            via::handle(c.resources.vial, &mut keyboard, &mut report);
+            #[cfg(not(feature = "vial"))]
+            via::handle(&mut keyboard, &mut report);
+            // VIA waits for each response before sending another request, so the endpoint is free
+            c.resources
+                .raw_hid
+                .lock(|raw_hid| raw_hid.write(&report))
+                .ok();
+        }
+
+        // The click is started after the report is sent so it doesn't delay it
+        let layer = c.resources.pipeline.keymap.current_layer();
+        if tick.pressed && !c.resources.pipeline.is_playing_music() {
+            if let Some(click) = c.resources.clicky.click(layer) {
+                c.resources.player.click(click);
+            }
+        }
+
+        let lock_state = c
             .resources
-            .debouncer
-            .events(c.resources.matrix.get().unwrap())
-        {
-            c.resources.layout.event(event);
+            .usb_class
+            .lock(|k| k.device_mut().leds_mut().state());
+        if lock_state != *c.resources.lock_state {
+            LOCK_INDICATORS.changed(*c.resources.lock_state, lock_state, c.resources.player);
+            *c.resources.lock_state = lock_state;
+        }
+
+        if layer != *c.resources.layer {
+            match LAYER_SONGS.get(layer) {
+                Some(song) if !song.is_empty() => c.resources.player.play_song(song),
+                _ => {}
+            }
+            *c.resources.layer = layer;
+        }
+
+        if let Some(frequency) = c.resources.player.tick() {
+            c.resources
+                .speaker
+                .lock(|speaker| speaker.set_frequency(frequency));
+        }
+
+        c.resources.backlight.tick();
+        if c.resources.animator.tick() {
+            let mut keys: KeyFrame = [[0; COLS]; ROWS];
+            c.resources.animator.render(&mut keys);
+            let frame = &mut *c.resources.led_frame;
+            frame.draw(&keys, c.resources.backlight.brightness());
+            if layer != 0 {
+                highlight_bound_keys(c.resources.pipeline.keymap.layer(layer), frame);
+            }
+            LOCK_INDICATORS.draw(lock_state, frame);
         }
-        c.resources.layout.tick();
-        send_report(c.resources.layout.keycodes(), &mut c.resources.usb_class);
     }
 };
 
-fn send_report(iter: impl Iterator<Item = KeyCode>, usb_class: &mut resources::usb_class<'_>) {
-    use rtic::Mutex;
-    let report: KbHidReport = iter.collect();
-    if usb_class.lock(|k| k.device_mut().set_keyboard_report(report.clone())) {
-        while let Ok(0) = usb_class.lock(|k| k.write(report.as_bytes())) {}
+/// Where the pipeline's results go, the keyboard report is sent over USB
+struct TickOutput<'a, 'b> {
+    usb_class: &'a mut resources::usb_class<'b>,
+    animator: &'a mut Animator,
+    presses: &'a mut u32,
+}
+
+impl pipeline::Output for TickOutput<'_, '_> {
+    fn send_report(&mut self, report: &KbHidReport) {
+        use rtic::Mutex;
+        if self
+            .usb_class
+            .lock(|k| k.device_mut().set_keyboard_report(report.clone()))
+        {
+            while let Ok(0) = self.usb_class.lock(|k| k.write(report.as_bytes())) {}
+        }
+    }
+
+    fn key_pressed(&mut self, row: u8, col: u8) {
+        self.animator.key_pressed(row.into(), col.into());
+        *self.presses = self.presses.wrapping_add(1);
     }
 }
 
-fn usb_poll(usb_dev: &mut UsbDevice, keyboard: &mut UsbClass) {
-    if usb_dev.poll(&mut [keyboard]) {
+fn usb_poll(
+    usb_dev: &mut UsbDevice,
+    keyboard: &mut UsbClass,
+    dfu: &mut DfuRuntime,
+    raw_hid: &mut RawHidClass,
+) {
+    if usb_dev.poll(&mut [keyboard, dfu, raw_hid]) {
         keyboard.poll();
     }
 }
@@ -0,0 +1,420 @@
+//! Everything between the key matrix and the keyboard report
+//!
+//! The firmware scans the matrix once a millisecond and hands the result to `Pipeline::tick`,
+//! which debounces it, runs it through the keymap, tap dances, combos and macros, and sends the
+//! report through an `Output`. Nothing in here touches the hardware, so the same pipeline runs on
+//! the host with scripted switch states and an `Output` that records the reports.
+
+use core::convert::TryFrom;
+
+use keyberon::debounce::Debouncer;
+use keyberon::key_code::KbHidReport;
+use keyberon::layout::{CustomEvent, Event};
+use keyberon::matrix::PressedKeys;
+
+use clueboard_core::combos::{Combiner, Combos};
+use clueboard_core::dynamic_macros::{MacroBuffer, MacroPlayer};
+use clueboard_core::music::{MusicEvent, MusicMode};
+use clueboard_core::tap_dance::{TapDancer, TapDances};
+use clueboard_core::{COLS, ROWS};
+
+use crate::keycode;
+use crate::keymap::Keymap;
+use crate::layout::CustomAction;
+
+/// Which switches are down, by matrix row then column
+pub type Switches = PressedKeys<COLS, ROWS>;
+
+/// Ticks a switch has to stay the same before it counts as changed
+const DEBOUNCE_TICKS: u16 = 5;
+
+/// Where the results of each tick go, the USB keyboard interface on the keyboard
+pub trait Output {
+    /// Called every tick with the keys that are down
+    fn send_report(&mut self, report: &KbHidReport);
+
+    /// A switch was pressed, after debouncing
+    fn key_pressed(&mut self, _row: u8, _col: u8) {}
+}
+
+/// What happened in a tick that the rest of the keyboard reacts to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tick {
+    /// Whether any switch was pressed
+    pub pressed: bool,
+    /// A custom action that was pressed, other than the ones the pipeline handles itself.
+    /// Default layer and swap changes are handled here but still passed on so they're saved.
+    pub action: Option<CustomAction>,
+    /// What music mode wants played
+    pub music: MusicEvent,
+}
+
+pub struct Pipeline {
+    debouncer: Debouncer<Switches>,
+    pub keymap: Keymap,
+    pub macros: MacroBuffer,
+    macro_player: MacroPlayer,
+    pub tap_dances: TapDances,
+    tap_dancer: TapDancer,
+    pub combos: Combos,
+    combiner: Combiner,
+    music: MusicMode,
+}
+
+impl Pipeline {
+    pub fn new(keymap: Keymap, macros: MacroBuffer, tap_dances: TapDances, combos: Combos) -> Self {
+        Pipeline {
+            debouncer: Debouncer::new(Switches::default(), Switches::default(), DEBOUNCE_TICKS),
+            keymap,
+            macros,
+            macro_player: MacroPlayer::new(),
+            tap_dances,
+            tap_dancer: TapDancer::new(),
+            combos,
+            combiner: Combiner::new(),
+            music: MusicMode::new(),
+        }
+    }
+
+    /// Whether the keys are playing notes instead of typing
+    pub fn is_playing_music(&self) -> bool {
+        self.music.is_active()
+    }
+
+    /// Advance by one tick with the switches as they were just scanned
+    pub fn tick(&mut self, switches: Switches, output: &mut impl Output) -> Tick {
+        // Before this tick's presses so a dance decided by them is sent this tick
+        self.tap_dancer.tick();
+
+        let mut pressed = false;
+        let mut music = MusicEvent::Nothing;
+        for event in self.debouncer.events(switches) {
+            if let Event::Press(row, col) = event {
+                output.key_pressed(row, col);
+                pressed = true;
+            }
+
+            if self.music.is_active() {
+                let music_event = match event {
+                    Event::Press(row, col) => self.music.press(row.into(), col.into()),
+                    Event::Release(row, col) => {
+                        // Releases still go to the keymap so nothing is left held down when
+                        // music mode ends
+                        self.keymap.event(event);
+                        self.music.release(row.into(), col.into())
+                    }
+                };
+                if music_event != MusicEvent::Nothing {
+                    music = music_event;
+                }
+            } else {
+                self.keymap.event(event);
+            }
+        }
+
+        let mut action = None;
+        let mut dance_pressed = false;
+        match self.keymap.tick() {
+            CustomEvent::Press(&CustomAction::MusicMode) => self.music.enter(),
+            CustomEvent::Press(&CustomAction::DynamicMacro(index)) => {
+                self.macro_player.play(self.macros.get(index))
+            }
+            CustomEvent::Press(&CustomAction::TapDance(index)) => {
+                let entry = self.tap_dances.get(index).unwrap_or_default();
+                self.tap_dancer.press(index, entry);
+                dance_pressed = true;
+            }
+            CustomEvent::Release(&CustomAction::TapDance(index)) => self.tap_dancer.release(index),
+            CustomEvent::Press(&other) => {
+                match other {
+                    CustomAction::DefaultLayer(layer) => {
+                        self.keymap.set_default_layer(layer.into())
+                    }
+                    CustomAction::Swap(swap) => {
+                        let mut swaps = self.keymap.swaps();
+                        swaps.apply(swap);
+                        self.keymap.set_swaps(swaps);
+                    }
+                    _ => {}
+                }
+                action = Some(other);
+            }
+            _ => {}
+        }
+        if pressed && !dance_pressed {
+            self.tap_dancer.interrupt();
+        }
+
+        self.macro_player.tick();
+        let report = if self.music.is_active() {
+            // Nothing is typed while playing music
+            core::iter::empty().collect()
+        } else {
+            let combos = self.combos.as_slice();
+            self.combiner
+                .update(combos, self.keymap.keycodes().map(|key| key as u8));
+            let dance_keys = self
+                .tap_dancer
+                .keycodes()
+                .filter_map(|code| u8::try_from(code).ok());
+            self.combiner
+                .keys(combos)
+                .chain(dance_keys)
+                .chain(self.macro_player.keys())
+                .filter_map(keycode::key_code)
+                .collect()
+        };
+        output.send_report(&report);
+
+        Tick {
+            pressed,
+            action,
+            music,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Mutex, MutexGuard};
+    use std::vec::Vec;
+
+    use keyberon::key_code::KeyCode;
+
+    use crate::keymap::Overrides;
+
+    /// Only one `Keymap` can exist at a time, tests take this while they have one
+    static KEYMAP: Mutex<()> = Mutex::new(());
+
+    // Matrix positions, see the layout comment in layout.rs
+    const ESC: (usize, usize) = (0, 0);
+    const KB1: (usize, usize) = (0, 1);
+    const K: (usize, usize) = (7, 0);
+    const MO_FL: (usize, usize) = (4, 0);
+    const MO_ML: (usize, usize) = (9, 3);
+    const MU_ON: (usize, usize) = (8, 0);
+    const LSHIFT: (usize, usize) = (3, 0);
+    const CAPS: (usize, usize) = (2, 0);
+
+    /// Records every report sent
+    #[derive(Default)]
+    struct Reports(Vec<KbHidReport>);
+
+    impl Output for Reports {
+        fn send_report(&mut self, report: &KbHidReport) {
+            self.0.push(report.clone());
+        }
+    }
+
+    /// The pipeline with the compiled in keymap, fed switch states a tick at a time
+    struct Simulator {
+        pipeline: Pipeline,
+        switches: [[bool; COLS]; ROWS],
+        reports: Reports,
+        _keymap: MutexGuard<'static, ()>,
+    }
+
+    impl Simulator {
+        fn new() -> Self {
+            let guard = KEYMAP.lock().unwrap_or_else(|err| err.into_inner());
+            // NOTE(unsafe) the guard is held for as long as the keymap exists
+            let keymap = unsafe { Keymap::new(crate::LAYERS, Overrides::new()) };
+            Simulator {
+                pipeline: Pipeline::new(
+                    keymap,
+                    MacroBuffer::new(),
+                    TapDances::new(),
+                    Combos::new(),
+                ),
+                switches: [[false; COLS]; ROWS],
+                reports: Reports::default(),
+                _keymap: guard,
+            }
+        }
+
+        /// Hold `key` down from the next tick
+        fn press(&mut self, (row, col): (usize, usize)) {
+            self.switches[row][col] = true;
+        }
+
+        fn release(&mut self, (row, col): (usize, usize)) {
+            self.switches[row][col] = false;
+        }
+
+        /// Run for `ticks` milliseconds, returning the keys in the last report
+        fn run(&mut self, ticks: u16) -> Vec<u8> {
+            for _ in 0..ticks {
+                self.pipeline
+                    .tick(PressedKeys(self.switches), &mut self.reports);
+            }
+            keys(self.reports.0.last().unwrap())
+        }
+
+        /// Long enough for any change to get through the debouncer
+        fn settle(&mut self) -> Vec<u8> {
+            self.run(DEBOUNCE_TICKS * 2)
+        }
+    }
+
+    /// The modifier bits then the other keys of `report`
+    fn keys(report: &KbHidReport) -> Vec<u8> {
+        let bytes = report.as_bytes();
+        let keys = bytes[2..].iter().copied().filter(|&key| key != 0);
+        core::iter::once(bytes[0]).chain(keys).collect()
+    }
+
+    #[test]
+    fn one_report_per_tick() {
+        let mut simulator = Simulator::new();
+        assert_eq!(simulator.run(3), [0]);
+        assert_eq!(simulator.reports.0.len(), 3);
+    }
+
+    #[test]
+    fn presses_are_debounced() {
+        let mut simulator = Simulator::new();
+        simulator.press(KB1);
+        assert_eq!(simulator.run(1), [0]);
+        assert_eq!(simulator.settle(), [0, KeyCode::Kb1 as u8]);
+
+        simulator.release(KB1);
+        assert_eq!(simulator.settle(), [0]);
+    }
+
+    #[test]
+    fn bouncing_switches_are_ignored() {
+        let mut simulator = Simulator::new();
+        for _ in 0..DEBOUNCE_TICKS * 2 {
+            simulator.press(KB1);
+            simulator.run(1);
+            simulator.release(KB1);
+            simulator.run(1);
+        }
+        assert!(simulator.reports.0.iter().all(|report| keys(report) == [0]));
+    }
+
+    #[test]
+    fn holding_mo_fl_and_pressing_1_is_f1() {
+        let mut simulator = Simulator::new();
+        simulator.press(MO_FL);
+        assert_eq!(simulator.settle(), [0]);
+        simulator.press(KB1);
+        assert_eq!(simulator.settle(), [0, KeyCode::F1 as u8]);
+
+        // Letting go of the layer key first doesn't change the key that's down
+        simulator.release(MO_FL);
+        assert_eq!(simulator.settle(), [0, KeyCode::F1 as u8]);
+        simulator.release(KB1);
+        assert_eq!(simulator.settle(), [0]);
+        simulator.press(KB1);
+        assert_eq!(simulator.settle(), [0, KeyCode::Kb1 as u8]);
+    }
+
+    #[test]
+    fn modifiers_go_in_the_first_byte() {
+        let mut simulator = Simulator::new();
+        simulator.press(LSHIFT);
+        simulator.press(K);
+        assert_eq!(simulator.settle(), [0x02, KeyCode::K as u8]);
+    }
+
+    #[test]
+    fn firmware_actions_are_passed_on() {
+        let mut simulator = Simulator::new();
+        simulator.press(MO_ML);
+        simulator.settle();
+        simulator.press(ESC);
+        let actions = (0..DEBOUNCE_TICKS * 2)
+            .filter_map(|_| {
+                let switches = PressedKeys(simulator.switches);
+                simulator
+                    .pipeline
+                    .tick(switches, &mut simulator.reports)
+                    .action
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(actions, [CustomAction::Bootloader]);
+    }
+
+    #[test]
+    fn music_mode_types_nothing() {
+        let mut simulator = Simulator::new();
+        simulator.press(MO_FL);
+        simulator.settle();
+        simulator.press(MU_ON);
+        simulator.settle();
+        simulator.release(MU_ON);
+        simulator.release(MO_FL);
+        simulator.settle();
+        assert!(simulator.pipeline.is_playing_music());
+
+        simulator.press(K);
+        assert_eq!(simulator.settle(), [0]);
+    }
+
+    #[test]
+    fn default_layer_keys_change_the_base_layer() {
+        let mut simulator = Simulator::new();
+        // DF(1) on the 1 key
+        simulator
+            .pipeline
+            .keymap
+            .set_keycode(0, KB1.0, KB1.1, 0x5241);
+        simulator.press(KB1);
+        simulator.settle();
+        simulator.release(KB1);
+        simulator.settle();
+        assert_eq!(simulator.pipeline.keymap.default_layer(), 1);
+
+        simulator.press(KB1);
+        assert_eq!(simulator.settle(), [0, KeyCode::F1 as u8]);
+    }
+
+    #[test]
+    fn swaps_change_the_keys_sent() {
+        let mut simulator = Simulator::new();
+        simulator.press(CAPS);
+        assert_eq!(simulator.settle(), [0x01]);
+        simulator.release(CAPS);
+        simulator.settle();
+
+        // CL_TOGG is Fn + Caps
+        simulator.press(MO_FL);
+        simulator.press(CAPS);
+        simulator.settle();
+        simulator.release(CAPS);
+        simulator.release(MO_FL);
+        simulator.settle();
+        assert!(simulator.pipeline.keymap.swaps().ctrl_caps);
+
+        simulator.press(CAPS);
+        assert_eq!(simulator.settle(), [0, KeyCode::CapsLock as u8]);
+    }
+
+    #[test]
+    fn dynamic_macros_are_typed() {
+        let mut simulator = Simulator::new();
+        simulator.pipeline.macros.write(0, b"hi\0");
+        // QK_MACRO_0 on the 1 key
+        simulator
+            .pipeline
+            .keymap
+            .set_keycode(0, KB1.0, KB1.1, 0x7700);
+        simulator.press(KB1);
+        simulator.settle();
+        simulator.release(KB1);
+        simulator.run(100);
+
+        let mut typed = simulator
+            .reports
+            .0
+            .iter()
+            .map(keys)
+            .filter(|keys| keys.len() > 1)
+            .map(|keys| keys[1])
+            .collect::<Vec<_>>();
+        typed.dedup();
+        assert_eq!(typed, [KeyCode::H as u8, KeyCode::I as u8]);
+    }
+}
@@ -0,0 +1,149 @@
+//! Raw HID interface used by VIA
+//!
+//! A vendor defined HID interface with 32 byte reports in each direction, the same one QMK
+//! keyboards have. Requests are read from the OUT endpoint and kept until the matrix scan takes
+//! them, responses are written to the IN endpoint.
+
+use usb_device::class_prelude::*;
+use usb_device::control::{Recipient, Request, RequestType};
+
+use clueboard_core::via::REPORT_LEN;
+
+const INTERFACE_CLASS_HID: u8 = 0x03;
+const DESCRIPTOR_HID: u8 = 0x21;
+const DESCRIPTOR_REPORT: u8 = 0x22;
+const HID_VERSION: u16 = 0x0111;
+
+const REQUEST_SET_IDLE: u8 = 0x0A;
+
+/// Polling interval of the endpoints in milliseconds
+const POLL_INTERVAL: u8 = 1;
+
+/// Usage page 0xFF60 and usage 0x61, which is how VIA finds the interface
+#[rustfmt::skip]
+const REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0x60, 0xFF, // Usage Page (Vendor Defined 0xFF60)
+    0x09, 0x61,       // Usage (0x61)
+    0xA1, 0x01,       // Collection (Application)
+    0x09, 0x62,       //   Usage (0x62)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x95, 0x20,       //   Report Count (32)
+    0x75, 0x08,       //   Report Size (8)
+    0x81, 0x02,       //   Input (Data, Variable, Absolute)
+    0x09, 0x63,       //   Usage (0x63)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x95, 0x20,       //   Report Count (32)
+    0x75, 0x08,       //   Report Size (8)
+    0x91, 0x02,       //   Output (Data, Variable, Absolute)
+    0xC0,             // End Collection
+];
+
+pub struct RawHid<'a, B: UsbBus> {
+    interface: InterfaceNumber,
+    ep_in: EndpointIn<'a, B>,
+    ep_out: EndpointOut<'a, B>,
+    /// Request received and not yet taken
+    request: Option<[u8; REPORT_LEN]>,
+}
+
+impl<'a, B: UsbBus> RawHid<'a, B> {
+    pub fn new(alloc: &'a UsbBusAllocator<B>) -> Self {
+        RawHid {
+            interface: alloc.interface(),
+            ep_in: alloc.interrupt(REPORT_LEN as u16, POLL_INTERVAL),
+            ep_out: alloc.interrupt(REPORT_LEN as u16, POLL_INTERVAL),
+            request: None,
+        }
+    }
+
+    /// The last request received, if it hasn't been taken already
+    pub fn take_request(&mut self) -> Option<[u8; REPORT_LEN]> {
+        self.request.take()
+    }
+
+    /// Send `report` to the host
+    pub fn write(&mut self, report: &[u8; REPORT_LEN]) -> Result<usize> {
+        self.ep_in.write(report)
+    }
+
+    fn is_for_us(&self, request: &Request) -> bool {
+        request.recipient == Recipient::Interface
+            && request.index == u16::from(u8::from(self.interface))
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for RawHid<'_, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        // No subclass or protocol, it's not a boot device
+        writer.interface(self.interface, INTERFACE_CLASS_HID, 0, 0)?;
+        let [version_lo, version_hi] = HID_VERSION.to_le_bytes();
+        let [len_lo, len_hi] = (REPORT_DESCRIPTOR.len() as u16).to_le_bytes();
+        writer.write(
+            DESCRIPTOR_HID,
+            &[
+                version_lo,
+                version_hi,
+                0, // Country code, not localised
+                1, // Number of class descriptors
+                DESCRIPTOR_REPORT,
+                len_lo,
+                len_hi,
+            ],
+        )?;
+        writer.endpoint(&self.ep_in)?;
+        writer.endpoint(&self.ep_out)
+    }
+
+    fn endpoint_out(&mut self, addr: EndpointAddress) {
+        if addr != self.ep_out.address() {
+            return;
+        }
+
+        // Always read so the endpoint is ready for the next one. VIA waits for each response
+        // before sending another request, so nothing is lost by replacing an untaken one.
+        let mut report = [0; REPORT_LEN];
+        if let Ok(len) = self.ep_out.read(&mut report) {
+            if len > 0 {
+                self.request = Some(report);
+            }
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let request = xfer.request();
+        if !self.is_for_us(request) || request.request_type != RequestType::Class {
+            return;
+        }
+
+        match request.request {
+            REQUEST_SET_IDLE => {
+                xfer.accept().ok();
+            }
+            _ => {
+                xfer.reject().ok();
+            }
+        }
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let request = xfer.request();
+        if !self.is_for_us(request) {
+            return;
+        }
+
+        match (request.request_type, request.request) {
+            (RequestType::Standard, Request::GET_DESCRIPTOR) => {
+                if (request.value >> 8) as u8 == DESCRIPTOR_REPORT {
+                    xfer.accept_with_static(REPORT_DESCRIPTOR).ok();
+                } else {
+                    xfer.reject().ok();
+                }
+            }
+            _ => {
+                xfer.reject().ok();
+            }
+        }
+    }
+}
@@ -0,0 +1,109 @@
+//! Settings kept across power cycles
+//!
+//! Each setting is stored under its own key so settings can be added later without invalidating
+//! the ones already stored. Anything missing or out of range falls back to its default.
+
+use clueboard_core::effects::Effect;
+use clueboard_core::store::{self, Store};
+
+use clueboard_rust_firmware::backlight;
+use clueboard_rust_firmware::keymap::Swaps;
+
+use crate::flash::{self, InternalFlash};
+use crate::speaker;
+
+pub type SettingsStore = Store<InternalFlash>;
+
+// Keys in the store, a key must never be reused for something else. Keymap overrides, macros, tap
+// dances and combos use the keys from 0x40 up, each from its own `FIRST_STORE_KEY`.
+const BACKLIGHT_LEVEL: u8 = 0;
+const BACKLIGHT_ENABLED: u8 = 1;
+const EFFECT: u8 = 2;
+const CLICKY: u8 = 3;
+const VOLUME: u8 = 4;
+const DEFAULT_LAYER: u8 = 5;
+const AUDIO: u8 = 6;
+const SWAP_CTRL_CAPS: u8 = 7;
+const SWAP_ALT_GUI: u8 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+    pub backlight_level: u8,
+    pub backlight_enabled: bool,
+    pub effect: Effect,
+    pub clicky: bool,
+    pub volume: u16,
+    /// Layer active when no layer keys are held
+    pub default_layer: u8,
+    /// Whether the speakers play anything at all
+    pub audio: bool,
+    pub swaps: Swaps,
+}
+
+impl Settings {
+    /// Read the stored settings
+    pub fn load(store: &SettingsStore) -> Self {
+        let defaults = Settings::default();
+        let byte = |key| {
+            let mut buf = [0];
+            store.get(key, &mut buf).filter(|&len| len == 1)?;
+            Some(buf[0])
+        };
+        let flag = |key, default| byte(key).map(|enabled| enabled != 0).unwrap_or(default);
+        let mut volume = [0; 2];
+        let volume = store
+            .get(VOLUME, &mut volume)
+            .filter(|&len| len == 2)
+            .map(|_| u16::from_le_bytes(volume));
+
+        Settings {
+            backlight_level: byte(BACKLIGHT_LEVEL)
+                .filter(|&level| level <= backlight::LEVELS)
+                .unwrap_or(defaults.backlight_level),
+            backlight_enabled: flag(BACKLIGHT_ENABLED, defaults.backlight_enabled),
+            effect: byte(EFFECT)
+                .and_then(Effect::from_index)
+                .unwrap_or(defaults.effect),
+            clicky: flag(CLICKY, defaults.clicky),
+            volume: volume
+                .filter(|&volume| volume <= speaker::MAX_VOLUME)
+                .unwrap_or(defaults.volume),
+            default_layer: byte(DEFAULT_LAYER)
+                .filter(|&layer| usize::from(layer) < clueboard_rust_firmware::LAYERS.len())
+                .unwrap_or(defaults.default_layer),
+            audio: flag(AUDIO, defaults.audio),
+            swaps: Swaps {
+                ctrl_caps: flag(SWAP_CTRL_CAPS, defaults.swaps.ctrl_caps),
+                alt_gui: flag(SWAP_ALT_GUI, defaults.swaps.alt_gui),
+            },
+        }
+    }
+
+    /// Store any settings that have changed
+    pub fn save(&self, store: &mut SettingsStore) -> Result<(), store::Error<flash::Error>> {
+        store.set(BACKLIGHT_LEVEL, &[self.backlight_level])?;
+        store.set(BACKLIGHT_ENABLED, &[self.backlight_enabled as u8])?;
+        store.set(EFFECT, &[self.effect as u8])?;
+        store.set(CLICKY, &[self.clicky as u8])?;
+        store.set(VOLUME, &self.volume.to_le_bytes())?;
+        store.set(DEFAULT_LAYER, &[self.default_layer])?;
+        store.set(AUDIO, &[self.audio as u8])?;
+        store.set(SWAP_CTRL_CAPS, &[self.swaps.ctrl_caps as u8])?;
+        store.set(SWAP_ALT_GUI, &[self.swaps.alt_gui as u8])
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            backlight_level: backlight::DEFAULT_LEVEL,
+            backlight_enabled: true,
+            effect: Effect::Solid,
+            clicky: false,
+            volume: speaker::DEFAULT_VOLUME,
+            default_layer: 0,
+            audio: true,
+            swaps: Swaps::default(),
+        }
+    }
+}
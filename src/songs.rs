@@ -0,0 +1,8 @@
+//! Songs compiled from `songs.txt` by build.rs
+
+// Not every song has to be bound to something
+#![allow(unused)]
+
+use clueboard_core::audio::Note;
+
+include!(concat!(env!("OUT_DIR"), "/songs.rs"));
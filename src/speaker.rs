@@ -0,0 +1,96 @@
+//! Driver for the two speakers
+//!
+//! The speakers are on PA4 and PA5, the two DAC outputs. They're driven in opposite phases with a
+//! square wave, TIM2 interrupts at twice the frequency of the note and each interrupt flips the
+//! outputs.
+
+use stm32f3xx_hal::gpio::gpioa::{PA4, PA5};
+use stm32f3xx_hal::gpio::Analog;
+use stm32f3xx_hal::prelude::*;
+use stm32f3xx_hal::{pac, timer};
+
+/// Highest value the 12-bit DAC accepts
+pub const MAX_VOLUME: u16 = 0xFFF;
+/// Volume after power on
+pub const DEFAULT_VOLUME: u16 = MAX_VOLUME / 2;
+/// How much each volume up or down changes the volume
+const VOLUME_STEP: u16 = MAX_VOLUME / 8;
+
+pub struct Speaker {
+    dac: pac::DAC1,
+    timer: timer::Timer<pac::TIM2>,
+    _pins: (PA4<Analog>, PA5<Analog>),
+    volume: u16,
+    high: bool,
+}
+
+impl Speaker {
+    pub fn new(
+        dac: pac::DAC1,
+        mut timer: timer::Timer<pac::TIM2>,
+        pins: (PA4<Analog>, PA5<Analog>),
+    ) -> Self {
+        // The HAL doesn't have a DAC driver so the clock is enabled directly
+        // NOTE(unsafe) only called from init, nothing else is using RCC at the same time
+        unsafe { &*pac::RCC::ptr() }
+            .apb1enr
+            .modify(|_, w| w.dac1en().set_bit());
+        dac.cr.write(|w| w.en1().set_bit().en2().set_bit());
+
+        timer.enable_interrupt(timer::Event::Update);
+        let mut speaker = Speaker {
+            dac,
+            timer,
+            _pins: pins,
+            volume: DEFAULT_VOLUME,
+            high: false,
+        };
+        speaker.set_frequency(0);
+        speaker
+    }
+
+    /// Start playing `frequency` Hz, 0 silences the speakers
+    pub fn set_frequency(&mut self, frequency: u16) {
+        if frequency == 0 {
+            self.timer.stop();
+            self.high = false;
+            self.output(0, 0);
+        } else {
+            let half_period = 500_000 / u32::from(frequency);
+            self.timer.start(half_period.microseconds());
+        }
+    }
+
+    pub fn volume(&self) -> u16 {
+        self.volume
+    }
+
+    pub fn set_volume(&mut self, volume: u16) {
+        self.volume = volume.min(MAX_VOLUME);
+    }
+
+    pub fn volume_up(&mut self) {
+        self.volume = self.volume.saturating_add(VOLUME_STEP).min(MAX_VOLUME);
+    }
+
+    pub fn volume_down(&mut self) {
+        self.volume = self.volume.saturating_sub(VOLUME_STEP);
+    }
+
+    /// Flip the outputs, called from the TIM2 interrupt
+    pub fn toggle(&mut self) {
+        self.timer.clear_event(timer::Event::Update);
+        self.high = !self.high;
+        if self.high {
+            self.output(self.volume, 0);
+        } else {
+            self.output(0, self.volume);
+        }
+    }
+
+    fn output(&mut self, pa4: u16, pa5: u16) {
+        self.dac
+            .dhr12rd
+            .write(|w| unsafe { w.dacc1dhr().bits(pa4).dacc2dhr().bits(pa5) });
+    }
+}
@@ -0,0 +1,141 @@
+//! The keyboard as VIA, or Vial when built with the `vial` feature, sees it
+
+use clueboard_core::combos::Combos;
+use clueboard_core::dynamic_macros::MacroBuffer;
+use clueboard_core::tap_dance::TapDances;
+#[cfg(not(feature = "vial"))]
+use clueboard_core::via;
+use clueboard_core::via::{Keyboard, Value, REPORT_LEN};
+#[cfg(feature = "vial")]
+use clueboard_core::vial::{self, Vial};
+use clueboard_core::{COLS, ROWS};
+
+use clueboard_rust_firmware::keymap::{Keymap, LAYER_COUNT};
+
+use crate::bootloader;
+use crate::settings::Settings;
+
+/// The crate version, major in the third byte down to patch in the lowest
+const FIRMWARE_VERSION: u32 = parse(env!("CARGO_PKG_VERSION_MAJOR")) << 16
+    | parse(env!("CARGO_PKG_VERSION_MINOR")) << 8
+    | parse(env!("CARGO_PKG_VERSION_PATCH"));
+
+#[cfg(feature = "vial")]
+pub const SERIAL_NUMBER: &str = vial::SERIAL_NUMBER;
+#[cfg(not(feature = "vial"))]
+pub const SERIAL_NUMBER: &str = env!("CARGO_PKG_VERSION");
+
+/// `via/clueboard66_lp.json` compressed, Vial reads it from the keyboard
+#[cfg(feature = "vial")]
+static DEFINITION: &[u8] = include_bytes!("../via/clueboard66_lp.json.xz");
+/// Made up for this keyboard, Vial uses it to remember the keyboard
+#[cfg(feature = "vial")]
+const UID: [u8; 8] = [0xE3, 0xFC, 0xFF, 0x80, 0xAC, 0x03, 0xFB, 0xC2];
+/// Esc and Enter, held down to unlock
+#[cfg(feature = "vial")]
+static UNLOCK_KEYS: [(u8, u8); 2] = [(0, 0), (7, 5)];
+
+#[cfg(feature = "vial")]
+pub const fn new_vial() -> Vial {
+    Vial::new(DEFINITION, UID, &UNLOCK_KEYS)
+}
+
+/// Handle the request in `report`, replacing it with the response
+#[cfg(feature = "vial")]
+pub fn handle(vial: &mut Vial, keyboard: &mut ViaKeyboard, report: &mut [u8; REPORT_LEN]) {
+    vial.handle(keyboard, report)
+}
+
+/// Handle the request in `report`, replacing it with the response
+#[cfg(not(feature = "vial"))]
+pub fn handle(keyboard: &mut ViaKeyboard, report: &mut [u8; REPORT_LEN]) {
+    via::handle(keyboard, report)
+}
+
+pub struct ViaKeyboard<'a> {
+    pub keymap: &'a mut Keymap,
+    pub macros: &'a mut MacroBuffer,
+    pub tap_dances: &'a mut TapDances,
+    pub combos: &'a mut Combos,
+    /// Milliseconds since power on
+    pub uptime: u32,
+    pub matrix: &'a [[bool; COLS]; ROWS],
+    pub settings: &'a Settings,
+    /// Keys pressed since power on
+    pub presses: u32,
+}
+
+impl Keyboard for ViaKeyboard<'_> {
+    fn layer_count(&self) -> u8 {
+        LAYER_COUNT as u8
+    }
+
+    fn keycode(&self, layer: u8, row: u8, col: u8) -> u16 {
+        self.keymap.keycode(layer.into(), row.into(), col.into())
+    }
+
+    fn set_keycode(&mut self, layer: u8, row: u8, col: u8, code: u16) {
+        self.keymap
+            .set_keycode(layer.into(), row.into(), col.into(), code)
+    }
+
+    fn reset_keymap(&mut self) {
+        self.keymap.reset()
+    }
+
+    fn macros(&mut self) -> &mut MacroBuffer {
+        self.macros
+    }
+
+    fn uptime(&self) -> u32 {
+        self.uptime
+    }
+
+    fn matrix(&self) -> &[[bool; COLS]; ROWS] {
+        self.matrix
+    }
+
+    fn firmware_version(&self) -> u32 {
+        FIRMWARE_VERSION
+    }
+
+    fn value(&self, value: Value) -> u32 {
+        let settings = self.settings;
+        match value {
+            Value::BacklightLevel => settings.backlight_level.into(),
+            Value::BacklightEnabled => settings.backlight_enabled.into(),
+            Value::Effect => settings.effect as u32,
+            Value::Clicky => settings.clicky.into(),
+            Value::Volume => settings.volume.into(),
+            Value::DefaultLayer => settings.default_layer.into(),
+            Value::KeyPresses => self.presses,
+        }
+    }
+
+    fn bootloader(&mut self) {
+        bootloader::reboot_to_bootloader()
+    }
+}
+
+#[cfg(feature = "vial")]
+impl vial::Keyboard for ViaKeyboard<'_> {
+    fn tap_dances(&mut self) -> &mut TapDances {
+        self.tap_dances
+    }
+
+    fn combos(&mut self) -> &mut Combos {
+        self.combos
+    }
+}
+
+/// Parse a decimal number at compile time
+const fn parse(digits: &str) -> u32 {
+    let digits = digits.as_bytes();
+    let mut value = 0;
+    let mut i = 0;
+    while i < digits.len() {
+        value = value * 10 + (digits[i] - b'0') as u32;
+        i += 1;
+    }
+    value
+}